@@ -0,0 +1,171 @@
+//! The first fit free list behind both the kernel heap and the userspace heap. It is its own crate
+//! so that the kernel and userspace share the code without it being part of the syscall ABI
+#![no_std]
+
+use core::{alloc::Layout, ptr};
+
+/// A free part of the heap, stored at its own start
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+/// Blocks start and end on multiples of this, so that whatever is left over when a block is split
+/// is large enough to hold a [`FreeBlock`]
+pub const BLOCK_ALIGN: usize = 16;
+
+const _: () = assert!(core::mem::size_of::<FreeBlock>() <= BLOCK_ALIGN);
+
+/// Align `value` upwards to `align`, which must be a power of two
+pub fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+/// The size and alignment of the block that holds an allocation of `layout`
+pub fn block_layout(layout: Layout) -> (usize, usize) {
+    let align = layout.align().max(BLOCK_ALIGN);
+    let size = align_up(layout.size().max(1), BLOCK_ALIGN);
+    (size, align)
+}
+
+/// The free parts of a heap, in a list sorted by address so that freed blocks are merged with
+/// their free neighbors. Blocks are taken first fit.
+///
+/// It only keeps track of free memory. Where the heap is and how it grows is up to its owner
+pub struct FreeList {
+    /// The free block with the lowest address
+    head: *mut FreeBlock,
+}
+
+impl FreeList {
+    /// Creates a list without any free memory
+    pub const fn new() -> Self {
+        FreeList {
+            head: ptr::null_mut(),
+        }
+    }
+
+    /// Adds `size` bytes at `addr` to the list, merging them with the free blocks right before
+    /// and after them. Panics if they overlap a free block, which means they were freed twice
+    ///
+    /// # Safety
+    /// The memory must be mapped, unused, and aligned to [`BLOCK_ALIGN`] on both ends. It is
+    /// written to until it is taken out of the list again
+    pub unsafe fn free(&mut self, addr: usize, mut size: usize) {
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut next = self.head;
+        // SAFETY: Every block in the list is free heap memory that holds a `FreeBlock`
+        unsafe {
+            while !next.is_null() && (next as usize) < addr {
+                prev = next;
+                next = (*next).next;
+            }
+            assert!(
+                (prev.is_null() || prev as usize + (*prev).size <= addr)
+                    && (next.is_null() || addr + size <= next as usize),
+                "heap block at {:#x} freed twice",
+                addr
+            );
+
+            if !next.is_null() && addr + size == next as usize {
+                size += (*next).size;
+                next = (*next).next;
+            }
+            if !prev.is_null() && prev as usize + (*prev).size == addr {
+                (*prev).size += size;
+                (*prev).next = next;
+                return;
+            }
+            let block = addr as *mut FreeBlock;
+            block.write(FreeBlock { size, next });
+            if prev.is_null() {
+                self.head = block;
+            } else {
+                (*prev).next = block;
+            }
+        }
+    }
+
+    /// Takes `size` bytes aligned to `align` out of the first free block that has room for them.
+    /// Whatever is left of the block on either side stays free
+    pub fn allocate(&mut self, size: usize, align: usize) -> Option<usize> {
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut current = self.head;
+        // SAFETY: Every block in the list is free heap memory that holds a `FreeBlock`, and the
+        // parts that are freed again are aligned because every size is
+        unsafe {
+            while !current.is_null() {
+                let block_start = current as usize;
+                let block_end = block_start + (*current).size;
+                let start = align_up(block_start, align);
+                match start.checked_add(size) {
+                    Some(end) if end <= block_end => {
+                        if prev.is_null() {
+                            self.head = (*current).next;
+                        } else {
+                            (*prev).next = (*current).next;
+                        }
+                        if end < block_end {
+                            self.free(end, block_end - end);
+                        }
+                        if start > block_start {
+                            self.free(block_start, start - block_start);
+                        }
+                        return Some(start);
+                    }
+                    _ => {}
+                }
+                prev = current;
+                current = (*current).next;
+            }
+        }
+        None
+    }
+
+    /// Returns where the last free block starts and how large it is, if it ends at `end`
+    pub fn free_end(&self, end: usize) -> Option<(usize, usize)> {
+        let mut last = self.head;
+        // SAFETY: Every block in the list is free heap memory that holds a `FreeBlock`
+        unsafe {
+            while !last.is_null() && !(*last).next.is_null() {
+                last = (*last).next;
+            }
+            if last.is_null() || last as usize + (*last).size != end {
+                return None;
+            }
+            Some((last as usize, (*last).size))
+        }
+    }
+
+    /// Drops everything from `new_end` onwards out of the list, so that the memory can be given
+    /// back. Everything after `new_end` must be free, as [`Self::free_end`] reports. The memory
+    /// from `new_end` onwards isn't touched, so it may already be gone
+    pub fn truncate(&mut self, new_end: usize) {
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut current = self.head;
+        // SAFETY: Every block that starts before `new_end` is free heap memory that holds a
+        // `FreeBlock`
+        unsafe {
+            while !current.is_null() && (current as usize) < new_end {
+                prev = current;
+                current = (*current).next;
+            }
+            if !current.is_null() {
+                // The last block starts at `new_end`, and is dropped completely
+                if prev.is_null() {
+                    self.head = ptr::null_mut();
+                } else {
+                    (*prev).next = ptr::null_mut();
+                }
+            } else if !prev.is_null() && new_end < prev as usize + (*prev).size {
+                (*prev).size = new_end - prev as usize;
+            }
+        }
+    }
+}
+
+impl Default for FreeList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -8,6 +8,8 @@ else
     FILE="target/x86_64/debug/zulu_os"
 fi
 echo 'got file $FILE'
+# The user program is only loaded at the address below when the kernel runs with
+# `cargo run --features no_aslr`
 rust-gdb $FILE -ex "target remote :1234" \
     "--eval-command=b enter_user_mode" \
     "--eval-command=b syscall_handler" \
@@ -15,5 +17,5 @@ rust-gdb $FILE -ex "target remote :1234" \
     "--eval-command=b _start" \
     "--eval-command=b gdt_init" \
     "--eval-command=c" \
-    "--eval-command=add-symbol-file processes/userspace_test 0x660000"
+    "--eval-command=add-symbol-file processes/userspace_test 0x100000660000"
 
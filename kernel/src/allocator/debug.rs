@@ -0,0 +1,277 @@
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{
+    mem::{align_of, size_of},
+    ptr, slice,
+};
+
+use super::LinkedListAllocator;
+use crate::serial_println;
+
+/// Freed memory is filled with this, so that use after free shows up as obviously bad data
+pub const POISON_BYTE: u8 = 0xdd;
+/// The bytes around every allocation are filled with this, and checked when it is freed
+pub const REDZONE_BYTE: u8 = 0xfd;
+/// Bytes of redzone after every allocation. The one before it is at least as large
+pub const REDZONE_SIZE: usize = 16;
+
+/// Allocations are counted in power of two size classes from 16 bytes up, and the last class
+/// holds everything larger than 16 << (SIZE_CLASSES - 2) bytes
+pub const SIZE_CLASSES: usize = 12;
+
+/// Sits in front of every allocation, and links all of them into a list so that the ones that are
+/// still allocated can be listed
+struct Header {
+    prev: *mut Header,
+    next: *mut Header,
+    /// The layout that the allocation was made with
+    size: usize,
+    align: usize,
+}
+
+/// Live allocations of a size class
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SizeClassStats {
+    /// The largest allocation that falls into the class, or `usize::MAX` for the last one
+    pub max_size: usize,
+    pub allocations: usize,
+    /// Bytes that were asked for, without headers and redzones
+    pub bytes: usize,
+}
+
+struct Allocations {
+    /// The most recent allocation
+    head: *mut Header,
+    classes: [SizeClassStats; SIZE_CLASSES],
+}
+
+// SAFETY: The headers are only accessed with the lock held, or by the owner of their allocation
+unsafe impl Send for Allocations {}
+
+/// Wraps the heap allocator to check for heap corruption and leaks, when the kernel is built with
+/// the `debug_alloc` feature.
+///
+/// Every allocation gets a header and redzones on both sides, which are checked when it is freed,
+/// and freed memory is poisoned
+pub struct DebugAllocator {
+    heap: &'static LinkedListAllocator,
+    inner: spin::Mutex<Allocations>,
+}
+
+fn size_class(size: usize) -> usize {
+    let class = size.max(16).next_power_of_two().trailing_zeros() as usize - 4;
+    class.min(SIZE_CLASSES - 1)
+}
+
+/// The layout of the block that holds an allocation of `layout`, and the offset of the
+/// allocation in it
+fn block_layout(layout: Layout) -> Option<(Layout, usize)> {
+    let align = layout.align().max(align_of::<Header>());
+    // SAFETY: Alignments are powers of two
+    let offset = unsafe { super::align_up(size_of::<Header>() + REDZONE_SIZE, align) };
+    let size = offset
+        .checked_add(layout.size())?
+        .checked_add(REDZONE_SIZE)?;
+    Some((Layout::from_size_align(size, align).ok()?, offset))
+}
+
+impl DebugAllocator {
+    pub const fn new(heap: &'static LinkedListAllocator) -> Self {
+        DebugAllocator {
+            heap,
+            inner: spin::Mutex::new(Allocations {
+                head: ptr::null_mut(),
+                classes: [SizeClassStats {
+                    max_size: 0,
+                    allocations: 0,
+                    bytes: 0,
+                }; SIZE_CLASSES],
+            }),
+        }
+    }
+
+    /// Locks the list of allocations, see [`with_locked`](super::with_locked)
+    fn with<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut Allocations) -> R,
+    {
+        super::with_locked(&self.inner, f)
+    }
+
+    /// Live allocations and bytes of every size class
+    pub fn stats(&self) -> [SizeClassStats; SIZE_CLASSES] {
+        let mut classes = self.with(|allocations| allocations.classes);
+        for (i, class) in classes.iter_mut().enumerate() {
+            class.max_size = match i {
+                i if i == SIZE_CLASSES - 1 => usize::MAX,
+                i => 16 << i,
+            };
+        }
+        classes
+    }
+
+    /// Prints every live allocation over serial, most recent first, followed by the totals of
+    /// each size class
+    pub fn print_allocations(&self) {
+        serial_println!("Outstanding heap allocations:");
+        self.with(|allocations| {
+            let mut header = allocations.head;
+            while !header.is_null() {
+                // SAFETY: Headers in the list belong to live allocations
+                unsafe {
+                    let (size, align) = ((*header).size, (*header).align);
+                    let layout = Layout::from_size_align_unchecked(size, align);
+                    let (_, offset) = block_layout(layout).expect("allocated layouts fit");
+                    serial_println!(
+                        "{:>#18x} {:>8} bytes, align {}",
+                        header as usize + offset,
+                        size,
+                        align
+                    );
+                    header = (*header).next;
+                }
+            }
+        });
+        for class in self.stats() {
+            if class.allocations == 0 {
+                continue;
+            }
+            if class.max_size == usize::MAX {
+                serial_println!(
+                    "{:>8} larger: {} allocations, {} bytes",
+                    "",
+                    class.allocations,
+                    class.bytes
+                );
+            } else {
+                serial_println!(
+                    "{:>8} bytes or less: {} allocations, {} bytes",
+                    class.max_size,
+                    class.allocations,
+                    class.bytes
+                );
+            }
+        }
+    }
+
+    /// Panics if anything around the allocation at `ptr` was overwritten
+    ///
+    /// # Safety
+    /// `ptr` must be a live allocation of `layout`, whose block starts `offset` bytes before it
+    unsafe fn check_redzones(ptr: *mut u8, layout: Layout, offset: usize) {
+        // SAFETY: Both redzones are part of the block
+        let (front, back) = unsafe {
+            (
+                slice::from_raw_parts(
+                    ptr.sub(offset - size_of::<Header>()),
+                    offset - size_of::<Header>(),
+                ),
+                slice::from_raw_parts(ptr.add(layout.size()), REDZONE_SIZE),
+            )
+        };
+        if let Some(i) = front.iter().rposition(|&byte| byte != REDZONE_BYTE) {
+            panic!(
+                "heap allocation {:p} ({:?}) was overwritten at offset -{}, in its redzone",
+                ptr,
+                layout,
+                front.len() - i
+            );
+        }
+        if let Some(i) = back.iter().position(|&byte| byte != REDZONE_BYTE) {
+            panic!(
+                "heap allocation {:p} ({:?}) was overwritten at offset {}, in its redzone",
+                ptr,
+                layout,
+                layout.size() + i
+            );
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for DebugAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let Some((block_layout, offset)) = block_layout(layout) else {
+            return ptr::null_mut();
+        };
+        // SAFETY: The block layout has a non-zero size
+        let block = unsafe { self.heap.alloc(block_layout) };
+        if block.is_null() {
+            return block;
+        }
+
+        let header = block as *mut Header;
+        // SAFETY: The block was just allocated, and has room for the header and both redzones
+        let ptr = unsafe {
+            block
+                .add(size_of::<Header>())
+                .write_bytes(REDZONE_BYTE, offset - size_of::<Header>());
+            let ptr = block.add(offset);
+            ptr.add(layout.size())
+                .write_bytes(REDZONE_BYTE, REDZONE_SIZE);
+            header.write(Header {
+                prev: ptr::null_mut(),
+                next: ptr::null_mut(),
+                size: layout.size(),
+                align: layout.align(),
+            });
+            ptr
+        };
+
+        self.with(|allocations| {
+            // SAFETY: The header is initialized, and the list head is a live allocation
+            unsafe {
+                (*header).next = allocations.head;
+                if !allocations.head.is_null() {
+                    (*allocations.head).prev = header;
+                }
+            }
+            allocations.head = header;
+            let class = &mut allocations.classes[size_class(layout.size())];
+            class.allocations += 1;
+            class.bytes += layout.size();
+        });
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (block_layout, offset) = block_layout(layout).expect("allocated layouts fit");
+        // SAFETY: The caller guarantees that `ptr` was allocated with `layout`, so it is preceded
+        // by a header
+        let block = unsafe { ptr.sub(offset) };
+        let header = block as *mut Header;
+        unsafe {
+            assert!(
+                (*header).size == layout.size() && (*header).align == layout.align(),
+                "heap allocation {:p} freed with {:?}, but allocated with size {} and align {}",
+                ptr,
+                layout,
+                (*header).size,
+                (*header).align
+            );
+            Self::check_redzones(ptr, layout, offset);
+        }
+
+        self.with(|allocations| {
+            // SAFETY: The header and its neighbors are live allocations
+            unsafe {
+                let (prev, next) = ((*header).prev, (*header).next);
+                if prev.is_null() {
+                    allocations.head = next;
+                } else {
+                    (*prev).next = next;
+                }
+                if !next.is_null() {
+                    (*next).prev = prev;
+                }
+            }
+            let class = &mut allocations.classes[size_class(layout.size())];
+            class.allocations -= 1;
+            class.bytes -= layout.size();
+        });
+
+        // SAFETY: The block isn't used anymore
+        unsafe {
+            block.write_bytes(POISON_BYTE, block_layout.size());
+            self.heap.dealloc(block, block_layout);
+        }
+    }
+}
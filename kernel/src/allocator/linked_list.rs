@@ -0,0 +1,127 @@
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+
+use free_list::{block_layout, FreeList};
+
+/// A first fit allocator that keeps the free parts of the heap in a list sorted by address, so
+/// that freed blocks are merged with their free neighbors.
+///
+/// The heap is grown through [`super::grow_heap`] when no free block is large enough
+pub struct LinkedListAllocator {
+    inner: spin::Mutex<Heap>,
+}
+
+struct Heap {
+    heap_start: usize,
+    /// End of the mapped part of the heap
+    heap_end: usize,
+    /// Bytes in allocated blocks, including the padding that keeps blocks aligned
+    used: usize,
+    free: FreeList,
+}
+
+// SAFETY: The free blocks are only accessed with the allocator's lock held
+unsafe impl Send for Heap {}
+
+impl LinkedListAllocator {
+    /// Creates a new empty allocator.
+    pub const fn new() -> Self {
+        LinkedListAllocator {
+            inner: spin::Mutex::new(Heap {
+                heap_start: 0,
+                heap_end: 0,
+                used: 0,
+                free: FreeList::new(),
+            }),
+        }
+    }
+
+    /// Initializes the allocator with the given heap bounds.
+    ///
+    /// # Safety
+    /// 1. The caller must ensure that the given memory range is unused.
+    /// 2. `heap_start` and `heap_size` must be aligned to
+    ///    [`BLOCK_ALIGN`](free_list::BLOCK_ALIGN)
+    /// 3. This method must be called only once.
+    pub unsafe fn init(&self, heap_start: usize, heap_size: usize) {
+        self.with(|heap| {
+            heap.heap_start = heap_start;
+            heap.heap_end = heap_start + heap_size;
+            // SAFETY: Guaranteed by the caller
+            unsafe { heap.free.free(heap_start, heap_size) };
+        })
+    }
+
+    /// Locks the allocator, see [`with_locked`](super::with_locked)
+    fn with<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut Heap) -> R,
+    {
+        super::with_locked(&self.inner, f)
+    }
+
+    /// The number of bytes in allocated blocks
+    pub fn used(&self) -> usize {
+        self.with(|heap| heap.used)
+    }
+
+    /// The number of bytes of the heap that are mapped
+    pub fn size(&self) -> usize {
+        self.with(|heap| heap.heap_end - heap.heap_start)
+    }
+
+    /// Gives the free end of the heap back through [`super::shrink_heap`], keeping at least
+    /// `min_size` bytes. Returns the number of bytes that were unmapped
+    pub fn shrink(&self, min_size: usize) -> usize {
+        self.with(|heap| {
+            let Some((last, _)) = heap.free.free_end(heap.heap_end) else {
+                return 0;
+            };
+            let keep = last.max(heap.heap_start + min_size);
+            let new_end = super::shrink_heap(heap.heap_end, keep);
+            heap.free.truncate(new_end);
+            let freed = heap.heap_end - new_end;
+            heap.heap_end = new_end;
+            freed
+        })
+    }
+}
+
+unsafe impl GlobalAlloc for LinkedListAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (size, align) = block_layout(layout);
+        self.with(|heap| {
+            // Allocating before `init` would grow a heap at address 0
+            if heap.heap_start == 0 {
+                return ptr::null_mut();
+            }
+            let addr = heap.free.allocate(size, align).or_else(|| {
+                // Room for the block even if the end of the heap is in use
+                let min_end = heap.heap_end.checked_add(size + align)?;
+                let old_end = heap.heap_end;
+                heap.heap_end = super::grow_heap(old_end, min_end);
+                if heap.heap_end > old_end {
+                    // SAFETY: The new pages were just mapped for the heap, and pages are aligned
+                    unsafe { heap.free.free(old_end, heap.heap_end - old_end) };
+                }
+                heap.free.allocate(size, align)
+            });
+            match addr {
+                Some(addr) => {
+                    heap.used += size;
+                    addr as *mut u8
+                }
+                None => ptr::null_mut(),
+            }
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = block_layout(layout);
+        self.with(|heap| {
+            heap.used -= size;
+            // SAFETY: The block was allocated with the same layout, so it is aligned and unused
+            unsafe { heap.free.free(ptr as usize, size) };
+        })
+    }
+}
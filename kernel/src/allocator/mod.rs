@@ -1,51 +1,255 @@
-pub mod bump;
+#[cfg(feature = "debug_alloc")]
+pub mod debug;
+pub mod linked_list;
+pub mod slab;
 
-pub use bump::*;
+pub use linked_list::*;
+pub use slab::{print_slab_stats, slab_stats, SlabBox, SlabCache, SlabStats, MAX_OBJECT_SIZE};
 
-use x86_64::structures::paging::mapper::MapToError;
-use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::memory::{self, FrameOwner};
+use x86_64::structures::paging::mapper::{MapToError, MappedFrame, TranslateResult};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, Page, PageSize, PageTableFlags, PhysFrame, Size2MiB,
+    Size4KiB, Translate,
+};
 use x86_64::VirtAddr;
 
-pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 100 * 1024;
+/// Start of the kernel heap. This used to be `0x4444_4444_0000`, and was moved down to the 2MiB
+/// boundary below it so that the heap can be mapped with huge pages
+pub const HEAP_START: usize = 0x_4444_4440_0000;
+/// The size that the heap starts with. It never shrinks below this. This used to be 100KiB, and
+/// is one huge page now
+pub const HEAP_SIZE: usize = 2 * 1024 * 1024;
+/// The default limit that the heap grows up to, see [`set_heap_max_size`]
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024;
+/// The heap grows by at least this much at once, so that small allocations don't map one page at
+/// a time
+const HEAP_GROWTH: usize = 256 * 1024;
+
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
+
+/// Runs `f` on the data behind one of the allocators' locks. Interrupts are disabled while it is
+/// held, so that an interrupt handler that allocates can't spin on a lock that the code it
+/// interrupted holds
+fn with_locked<T, F, R>(lock: &spin::Mutex<T>, f: F) -> R
+where
+    F: FnOnce(&mut T) -> R,
+{
+    crate::sys::without_interrupts(|| f(&mut lock.lock()))
+}
 
 /// Initializes the kernel heap by allocating same pages and then initializing the allocator
 ///
+/// The heap starts out [`HEAP_SIZE`] bytes large, and grows on demand after that
+///
 /// # Safety
 /// 1. The caller must ensure that this function is only called once
 /// 2. The caller must not allocate any objects before this function returns
 pub unsafe fn init_kernel_heap(
-    mapper: &mut impl Mapper<Size4KiB>,
+    mapper: &mut (impl Mapper<Size4KiB> + Mapper<Size2MiB>),
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
 ) -> Result<(), MapToError<Size4KiB>> {
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
+    let start = VirtAddr::new(HEAP_START as u64);
+    let end = VirtAddr::new((HEAP_START + HEAP_SIZE) as u64);
+    // SAFETY: The heap range is unused before the heap is initialized
+    unsafe { map_heap(mapper, frame_allocator, start, end) }?;
 
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    };
+    // # Safety: The caller has ensured that this function is only called once
+    unsafe { ALLOCATOR.init(HEAP_START, HEAP_SIZE) };
+    Ok(())
+}
 
-    for page in page_range {
+/// Maps the heap pages from `addr` to `heap_end`. Returns the error that stopped it early, in
+/// which case everything before the failing page stays mapped
+///
+/// The heap is mapped with 2MiB pages where the contiguous zone has room for them, and with 4KiB
+/// pages otherwise
+///
+/// # Safety
+/// The range must be an unmapped part of the heap, after which nothing is mapped yet
+unsafe fn map_heap(
+    mapper: &mut (impl Mapper<Size4KiB> + Mapper<Size2MiB>),
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    mut addr: VirtAddr,
+    heap_end: VirtAddr,
+) -> Result<(), MapToError<Size4KiB>> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    while addr < heap_end {
+        if addr.is_aligned(Size2MiB::SIZE) && heap_end - addr >= Size2MiB::SIZE {
+            if let Some(frame) = memory::allocate_huge_frame(FrameOwner::KernelHeap) {
+                let page = Page::<Size2MiB>::containing_address(addr);
+                // Fails if there is no frame for a page table, or if a shrunk heap left a 4KiB
+                // page table behind, in which case 4KiB pages are used instead
+                if let Ok(flush) = unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+                    flush.flush();
+                    addr += Size2MiB::SIZE;
+                    continue;
+                }
+                for part in memory::huge_frame_parts(frame) {
+                    // SAFETY: The huge frame was never mapped
+                    unsafe { memory::frame_allocator().deallocate_frame(part) };
+                }
+            }
+        }
+
+        let page = Page::<Size4KiB>::containing_address(addr);
         let frame = frame_allocator
             .allocate_frame()
             .ok_or(MapToError::FrameAllocationFailed)?;
+        match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(err) => {
+                // SAFETY: The frame was never mapped
+                unsafe { memory::frame_allocator().deallocate_frame(frame) };
+                return Err(err);
+            }
+        }
+        memory::set_frame_owner(frame, FrameOwner::KernelHeap);
+        addr += Size4KiB::SIZE;
+    }
+    Ok(())
+}
 
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe {
-            mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+/// Maps more memory after `heap_end` so that the heap reaches at least `min_end`, growing by at
+/// least [`HEAP_GROWTH`] but never past the limit. Returns the new end of the heap, which is
+/// before `min_end` if there isn't enough memory or the limit is in the way
+///
+/// Called by the allocator with its lock held
+fn grow_heap(heap_end: usize, min_end: usize) -> usize {
+    let limit = HEAP_START + HEAP_LIMIT.load(Ordering::Relaxed);
+    if min_end > limit {
+        return heap_end;
+    }
+    // SAFETY: `HEAP_GROWTH` and pages are powers of two
+    let target = unsafe { align_up(min_end.max(heap_end + HEAP_GROWTH), 4096) }.min(limit);
+
+    let start = VirtAddr::new(heap_end as u64);
+    let mut mapped = start;
+    memory::mapper().with(|mapper| {
+        let mut tables = memory::frame_allocator_for(FrameOwner::PageTable);
+        // SAFETY: Everything after the end of the heap is unmapped
+        let result = unsafe { map_heap(mapper, &mut tables, start, VirtAddr::new(target as u64)) };
+        mapped = match result {
+            Ok(()) => VirtAddr::new(target as u64),
+            // Keep whatever was mapped, which might still be enough
+            Err(_) => end_of_mapping(mapper, start),
         };
-        //TODO: optimize here
+    });
+    mapped.as_u64() as usize
+}
+
+/// Returns the end of the heap pages that are mapped contiguously from `start`
+fn end_of_mapping(mapper: &impl Translate, mut addr: VirtAddr) -> VirtAddr {
+    while let TranslateResult::Mapped { frame, .. } = mapper.translate(addr) {
+        addr += frame.size();
     }
+    addr
+}
 
-    // # Safety: The caller has ensured that this function is only called once
-    unsafe { ALLOCATOR.init(HEAP_START, HEAP_SIZE) };
-    Ok(())
+/// Unmaps whole pages at the end of the heap until it ends at `heap_end` or at the first page that
+/// overlaps `keep_end`, and frees their frames. Returns the new end of the heap
+///
+/// Called by the allocator with its lock held, which guarantees that nothing is allocated after
+/// `keep_end`
+fn shrink_heap(mut heap_end: usize, keep_end: usize) -> usize {
+    memory::mapper().with(|mapper| loop {
+        let last = VirtAddr::new(heap_end as u64 - 1);
+        let (start, parts, huge) = match mapper.translate(last) {
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size2MiB(frame),
+                ..
+            } => {
+                let start = last.align_down(Size2MiB::SIZE);
+                (start, memory::huge_frame_parts(frame), true)
+            }
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size4KiB(frame),
+                ..
+            } => {
+                let start = last.align_down(Size4KiB::SIZE);
+                (start, PhysFrame::range(frame, frame + 1), false)
+            }
+            _ => unreachable!("heap page {:?} is not mapped", last),
+        };
+        if (start.as_u64() as usize) < keep_end {
+            return heap_end;
+        }
+
+        // SAFETY: Nothing is allocated in the page, and the heap page tables are only changed
+        // with the allocator's lock held
+        unsafe {
+            if huge {
+                let page = Page::<Size2MiB>::containing_address(start);
+                Mapper::<Size2MiB>::unmap(mapper, page)
+                    .expect("heap huge page is mapped")
+                    .1
+                    .flush();
+            } else {
+                let page = Page::<Size4KiB>::containing_address(start);
+                Mapper::<Size4KiB>::unmap(mapper, page)
+                    .expect("heap page is mapped")
+                    .1
+                    .flush();
+            }
+            for frame in parts {
+                memory::frame_allocator().deallocate_frame(frame);
+            }
+        }
+        heap_end = start.as_u64() as usize;
+    })
 }
 
+/// Sets how large the heap may grow, rounded up to whole pages. The heap never shrinks because of
+/// this, it just stops growing
+pub fn set_heap_max_size(size: usize) {
+    // SAFETY: Pages are a power of two
+    let size = unsafe { align_up(size, 4096) };
+    HEAP_LIMIT.store(size.max(HEAP_SIZE), Ordering::Relaxed);
+}
+
+/// Unmaps the unused pages at the end of the heap and returns their frames to the frame
+/// allocator, keeping at least [`HEAP_SIZE`] bytes. Returns the number of bytes freed
+pub fn shrink_kernel_heap() -> usize {
+    ALLOCATOR.shrink(HEAP_SIZE)
+}
+
+#[cfg_attr(not(feature = "debug_alloc"), global_allocator)]
+static ALLOCATOR: LinkedListAllocator = LinkedListAllocator::new();
+
+#[cfg(feature = "debug_alloc")]
 #[global_allocator]
-static ALLOCATOR: BumpAllocator = BumpAllocator::new();
+static DEBUG_ALLOCATOR: debug::DebugAllocator = debug::DebugAllocator::new(&ALLOCATOR);
+
+/// Live allocations and bytes of every size class. Only available with the `debug_alloc` feature
+#[cfg(feature = "debug_alloc")]
+pub fn allocation_stats() -> [debug::SizeClassStats; debug::SIZE_CLASSES] {
+    DEBUG_ALLOCATOR.stats()
+}
+
+/// Prints every live heap allocation over serial, to track down leaks. Only available with the
+/// `debug_alloc` feature
+#[cfg(feature = "debug_alloc")]
+pub fn print_allocations() {
+    DEBUG_ALLOCATOR.print_allocations()
+}
+
+/// How much of the kernel heap is in use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Bytes of the heap that are mapped. This changes as the heap grows and shrinks
+    pub size: usize,
+    /// Bytes that are allocated
+    pub used: usize,
+}
+
+pub fn heap_stats() -> HeapStats {
+    HeapStats {
+        size: ALLOCATOR.size(),
+        used: ALLOCATOR.used(),
+    }
+}
 
 /// Align the given value `value` upwards to alignment `align`.
 ///
@@ -0,0 +1,388 @@
+use alloc::vec::Vec;
+use core::{
+    fmt,
+    marker::PhantomData,
+    mem::{align_of, size_of},
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame},
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    memory::{self, FrameOwner},
+    serial_println,
+};
+
+/// Every slab is one frame
+const SLAB_SIZE: usize = 4096;
+
+/// The largest object that a cache can hold, so that every slab fits a few objects
+pub const MAX_OBJECT_SIZE: usize = 1024;
+
+/// Sits at the start of every slab
+struct SlabHeader {
+    /// Neighbors in the cache's list of slabs that have free objects
+    prev: *mut SlabHeader,
+    next: *mut SlabHeader,
+    /// The first free object of this slab
+    free: *mut FreeObject,
+    /// How many objects of this slab are allocated
+    in_use: usize,
+}
+
+/// An unused object slot, which links to the next one
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+const fn round_up(value: usize, align: usize) -> usize {
+    (value + align - 1) / align * align
+}
+
+const fn max(a: usize, b: usize) -> usize {
+    if a > b {
+        a
+    } else {
+        b
+    }
+}
+
+/// How much memory the slabs of every cache use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlabStats {
+    /// Objects that are allocated
+    pub objects: usize,
+    /// Objects that fit into the cache's slabs
+    pub capacity: usize,
+    /// Slabs of the cache, each of which is one frame
+    pub slabs: usize,
+    /// Bytes of the slabs that can never hold an object, because they are taken by slab headers,
+    /// padding between objects or space at the end that is too small for another object
+    pub waste: usize,
+}
+
+/// A cache of fixed size objects of type `T`, such as processes or VMAs.
+///
+/// Objects are carved out of slabs, which are whole frames, so allocating and freeing only takes
+/// an object off or puts it back on a free list. Caches are usually statics, and show up in
+/// [`slab_stats`] under their name once they first allocate
+pub struct SlabCache<T> {
+    name: &'static str,
+    constructor: Option<fn() -> T>,
+    inner: spin::Mutex<Slabs>,
+    registered: AtomicBool,
+    _marker: PhantomData<T>,
+}
+
+struct Slabs {
+    /// Slabs that have at least one free object. Full slabs aren't tracked, as they are found
+    /// from their objects when those are freed
+    partial: *mut SlabHeader,
+    /// Every slab of the cache, full or not
+    slabs: usize,
+    /// Slabs without any allocated object. One of them is kept to avoid allocating a frame every
+    /// time the first object of a slab is allocated
+    empty: usize,
+    objects: usize,
+}
+
+// SAFETY: Slabs are only accessed with the cache's lock held
+unsafe impl Send for Slabs {}
+
+// SAFETY: The cache only hands out objects through `SlabBox`, which is only `Send` and `Sync` if
+// `T` is
+unsafe impl<T> Sync for SlabCache<T> {}
+
+impl<T> SlabCache<T> {
+    /// Size of an object slot, which is large enough and aligned for both `T` and `FreeObject`
+    const OBJECT_SIZE: usize = round_up(
+        max(size_of::<T>(), size_of::<FreeObject>()),
+        max(align_of::<T>(), align_of::<FreeObject>()),
+    );
+    /// Offset of the first object in a slab
+    const FIRST_OBJECT: usize = round_up(
+        size_of::<SlabHeader>(),
+        max(align_of::<T>(), align_of::<FreeObject>()),
+    );
+    /// Objects per slab
+    const CAPACITY: usize = (SLAB_SIZE - Self::FIRST_OBJECT) / Self::OBJECT_SIZE;
+
+    /// Creates an empty cache called `name`. No memory is allocated until the first object is
+    pub const fn new(name: &'static str) -> Self {
+        assert!(size_of::<T>() <= MAX_OBJECT_SIZE && align_of::<T>() <= MAX_OBJECT_SIZE);
+        SlabCache {
+            name,
+            constructor: None,
+            inner: spin::Mutex::new(Slabs {
+                partial: ptr::null_mut(),
+                slabs: 0,
+                empty: 0,
+                objects: 0,
+            }),
+            registered: AtomicBool::new(false),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Creates an empty cache called `name` whose objects are created by `constructor` in
+    /// [`Self::alloc_new`]
+    pub const fn with_constructor(name: &'static str, constructor: fn() -> T) -> Self {
+        let mut cache = Self::new(name);
+        cache.constructor = Some(constructor);
+        cache
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Moves `value` into an object of the cache. Returns `None` if there is no frame left for a
+    /// new slab
+    pub fn alloc(&'static self, value: T) -> Option<SlabBox<T>> {
+        let object = self.allocate()?;
+        // SAFETY: The object is unused memory that is large enough and aligned for a `T`
+        unsafe { object.as_ptr().write(value) };
+        Some(SlabBox {
+            object,
+            cache: self,
+        })
+    }
+
+    /// Allocates an object created by the cache's constructor. Panics if it has none
+    pub fn alloc_new(&'static self) -> Option<SlabBox<T>> {
+        let constructor = self.constructor.expect("slab cache has no constructor");
+        self.alloc(constructor())
+    }
+
+    /// How much memory the cache uses
+    pub fn stats(&self) -> SlabStats {
+        self.with(|slabs| SlabStats {
+            objects: slabs.objects,
+            capacity: slabs.slabs * Self::CAPACITY,
+            slabs: slabs.slabs,
+            waste: slabs.slabs * (SLAB_SIZE - Self::CAPACITY * size_of::<T>()),
+        })
+    }
+
+    /// Locks the cache, see [`with_locked`](super::with_locked)
+    fn with<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut Slabs) -> R,
+    {
+        super::with_locked(&self.inner, f)
+    }
+
+    /// Takes a free object, adding a slab if none is left
+    fn allocate(&'static self) -> Option<NonNull<T>> {
+        let object = self.with(|slabs| {
+            if slabs.partial.is_null() {
+                slabs.partial = Self::new_slab()?;
+                slabs.slabs += 1;
+                slabs.empty += 1;
+            }
+            let slab = slabs.partial;
+            // SAFETY: Slabs in the list are initialized and have at least one free object
+            unsafe {
+                let object = (*slab).free;
+                (*slab).free = (*object).next;
+                if (*slab).in_use == 0 {
+                    slabs.empty -= 1;
+                }
+                (*slab).in_use += 1;
+                if (*slab).in_use == Self::CAPACITY {
+                    slabs.partial = (*slab).next;
+                    if !slabs.partial.is_null() {
+                        (*slabs.partial).prev = ptr::null_mut();
+                    }
+                    (*slab).next = ptr::null_mut();
+                }
+                slabs.objects += 1;
+                NonNull::new(object as *mut T)
+            }
+        })?;
+
+        if !self.registered.swap(true, Ordering::Relaxed) {
+            crate::sys::without_interrupts(|| CACHES.lock().push(self));
+        }
+        Some(object)
+    }
+
+    /// Allocates a frame for a slab and links all of its objects into its free list
+    fn new_slab() -> Option<*mut SlabHeader> {
+        let frame = memory::frame_allocator_for(FrameOwner::Slab).allocate_frame()?;
+        let start = memory::phys_to_virt(frame.start_address()).as_u64() as usize;
+        let slab = start as *mut SlabHeader;
+        let mut free = ptr::null_mut();
+        // SAFETY: The frame was just allocated, so nothing else uses it. Objects are pushed last
+        // to first so that they are handed out in address order
+        unsafe {
+            for i in (0..Self::CAPACITY).rev() {
+                let object =
+                    (start + Self::FIRST_OBJECT + i * Self::OBJECT_SIZE) as *mut FreeObject;
+                object.write(FreeObject { next: free });
+                free = object;
+            }
+            slab.write(SlabHeader {
+                prev: ptr::null_mut(),
+                next: ptr::null_mut(),
+                free,
+                in_use: 0,
+            });
+        }
+        Some(slab)
+    }
+
+    /// Puts `object` back on its slab's free list, freeing the slab if it is empty and another
+    /// empty slab is already kept
+    ///
+    /// # Safety
+    /// `object` must have been allocated by this cache, and must not be used afterwards
+    unsafe fn free(&self, object: NonNull<T>) {
+        let released = self.with(|slabs| {
+            let slab = (object.as_ptr() as usize & !(SLAB_SIZE - 1)) as *mut SlabHeader;
+            // SAFETY: Objects are always inside of a slab, which starts with its header
+            unsafe {
+                let was_full = (*slab).in_use == Self::CAPACITY;
+                let object = object.as_ptr() as *mut FreeObject;
+                object.write(FreeObject { next: (*slab).free });
+                (*slab).free = object;
+                (*slab).in_use -= 1;
+                slabs.objects -= 1;
+
+                if was_full {
+                    (*slab).next = slabs.partial;
+                    if !slabs.partial.is_null() {
+                        (*slabs.partial).prev = slab;
+                    }
+                    slabs.partial = slab;
+                }
+                if (*slab).in_use > 0 {
+                    return None;
+                }
+                if slabs.empty == 0 {
+                    slabs.empty += 1;
+                    return None;
+                }
+
+                // Unlink the slab from the list of partial slabs
+                let (prev, next) = ((*slab).prev, (*slab).next);
+                if prev.is_null() {
+                    slabs.partial = next;
+                } else {
+                    (*prev).next = next;
+                }
+                if !next.is_null() {
+                    (*next).prev = prev;
+                }
+                slabs.slabs -= 1;
+                Some(slab)
+            }
+        });
+
+        if let Some(slab) = released {
+            let addr = VirtAddr::from_ptr(slab) - memory::physical_memory_offset();
+            let frame = PhysFrame::containing_address(PhysAddr::new(addr));
+            // SAFETY: Every object of the slab is free, and it was unlinked from the cache above
+            unsafe { memory::frame_allocator().deallocate_frame(frame) };
+        }
+    }
+}
+
+/// An object allocated from a [`SlabCache`], which is dropped and given back to the cache when
+/// this is dropped. Works like a `Box`
+pub struct SlabBox<T: 'static> {
+    object: NonNull<T>,
+    cache: &'static SlabCache<T>,
+}
+
+// SAFETY: The box owns its object like a `Box` does
+unsafe impl<T: Send> Send for SlabBox<T> {}
+unsafe impl<T: Sync> Sync for SlabBox<T> {}
+
+impl<T> Deref for SlabBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: The object is initialized and owned by the box
+        unsafe { self.object.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: The object is initialized and owned by the box
+        unsafe { self.object.as_mut() }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for SlabBox<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        // SAFETY: The object is initialized and owned by the box, which is never used again
+        unsafe {
+            ptr::drop_in_place(self.object.as_ptr());
+            self.cache.free(self.object);
+        }
+    }
+}
+
+/// Lets caches of different object types be listed together
+trait Cache: Sync {
+    fn name(&self) -> &'static str;
+    fn stats(&self) -> SlabStats;
+}
+
+impl<T> Cache for SlabCache<T> {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn stats(&self) -> SlabStats {
+        SlabCache::stats(self)
+    }
+}
+
+/// Every cache that has allocated an object, in the order they first did
+static CACHES: spin::Mutex<Vec<&'static dyn Cache>> = spin::Mutex::new(Vec::new());
+
+/// The name and statistics of every cache that has allocated an object
+pub fn slab_stats() -> Vec<(&'static str, SlabStats)> {
+    let caches = crate::sys::without_interrupts(|| CACHES.lock().clone());
+    caches
+        .into_iter()
+        .map(|cache| (cache.name(), cache.stats()))
+        .collect()
+}
+
+/// Prints the statistics of every slab cache over serial
+pub fn print_slab_stats() {
+    serial_println!("Slab caches:");
+    serial_println!(
+        "{:>16} {:>8} {:>8} {:>6} {:>8}",
+        "name",
+        "objects",
+        "capacity",
+        "slabs",
+        "waste"
+    );
+    for (name, stats) in slab_stats() {
+        serial_println!(
+            "{:>16} {:>8} {:>8} {:>6} {:>8}",
+            name,
+            stats.objects,
+            stats.capacity,
+            stats.slabs,
+            stats.waste
+        );
+    }
+}
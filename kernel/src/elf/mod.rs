@@ -2,32 +2,59 @@ mod types;
 pub use types::*;
 
 use {
+    crate::memory::{AddressSpace, Vma, VmaError, VmaKind, USER_START},
     alloc::{collections::BTreeMap, vec::Vec},
-    core::slice,
     object::{
         elf::FileHeader64,
         read::elf::{FileHeader, ProgramHeader},
         LittleEndian,
     },
-    types::ElfFile,
     x86_64::{
-        structures::paging::{
-            FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags, Size4KiB,
-        },
+        structures::paging::{mapper::MapToError, Page, PageTableFlags, Size4KiB},
         VirtAddr,
     },
 };
 
-// syncs up with constant in gdb.sh so gdb knows where to look when were debugging this
-const LOAD_TEXT_SECTION_AT: u64 = 0x660000;
+/// Reasons why an elf file couldn't be loaded
+#[derive(Debug)]
+pub enum MapError {
+    /// The file isn't a 64 bit elf file, or it has no executable segment
+    InvalidElf,
+    /// A page of the image couldn't be mapped
+    Map(MapToError<Size4KiB>),
+    /// The image couldn't be described with VMAs, because a page is both writable and executable
+    /// or there was no memory for them
+    Vma(VmaError),
+}
 
+impl From<MapToError<Size4KiB>> for MapError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        MapError::Map(err)
+    }
+}
+
+impl From<VmaError> for MapError {
+    fn from(err: VmaError) -> Self {
+        MapError::Vma(err)
+    }
+}
+
+/// Loads the elf file in `bytes` into the user half of `space`, moving it so that its executable
+/// segment starts at `image_base`. If the image has segments linked so far below that they would
+/// start below [`USER_START`], it is moved up just enough for them to fit
+///
+/// The address space doesn't need to be active, all segment data is copied in through the
+/// physical memory mapping. Fails if the file is malformed or there isn't enough memory for the
+/// image, in which case whatever was already mapped is freed with `space`
 pub fn load(
     bytes: &[u8],
-    mapper: &mut OffsetPageTable<'static>,
-    allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> ElfFile {
-    let elf = FileHeader64::<LittleEndian>::parse(bytes).unwrap();
-    let program_headers = elf.program_headers(LittleEndian, bytes).unwrap();
+    space: &mut AddressSpace,
+    image_base: VirtAddr,
+) -> Result<ElfFile, MapError> {
+    let elf = FileHeader64::<LittleEndian>::parse(bytes).map_err(|_| MapError::InvalidElf)?;
+    let program_headers = elf
+        .program_headers(LittleEndian, bytes)
+        .map_err(|_| MapError::InvalidElf)?;
 
     let mut segments = Vec::new();
     for segment in program_headers {
@@ -85,12 +112,20 @@ pub fn load(
     };
 
     let mut default_text_addr = None;
+    let mut lowest_addr = u64::MAX;
     for section in &elf_file.segments {
         if !section.flags.contains(PageTableFlags::NO_EXECUTE) {
             default_text_addr = Some(section.addr.start.as_u64());
         }
+        lowest_addr = lowest_addr.min(section.addr.start.as_u64());
     }
-    let offset_to_apply = LOAD_TEXT_SECTION_AT as i64 - default_text_addr.unwrap() as i64;
+    let default_text_addr = default_text_addr.ok_or(MapError::InvalidElf)?;
+    // Segments linked below the text keep their distance to it, so a base close to `USER_START`
+    // is moved up until they still start in user memory
+    let image_base = image_base.max(VirtAddr::new(
+        USER_START + (default_text_addr - lowest_addr),
+    ));
+    let offset_to_apply = image_base.as_u64() as i64 - default_text_addr as i64;
 
     //println!("rel offset is 0x{:X?}", offset_to_apply);
     elf_file.remap(|src| VirtAddr::new((src.as_u64() as i64 + offset_to_apply) as u64));
@@ -114,41 +149,42 @@ pub fn load(
                 .or_insert(segment.flags);
         }
     }
+    // Describe the image with as few VMAs as possible by merging neighboring pages with the same
+    // flags. This comes before mapping, so that pages that are writable and executable are
+    // rejected before they are ever mapped
+    let mut image_vma: Option<Vma> = None;
+    for (&page, &flags) in &pages_to_map {
+        match &mut image_vma {
+            Some(vma) if vma.end == page.start_address() && vma.flags == flags => {
+                vma.end += page.size();
+            }
+            _ => {
+                if let Some(vma) = image_vma.take() {
+                    space.add_vma(vma)?;
+                }
+                let end = page.start_address() + page.size();
+                image_vma = Some(Vma::new(page.start_address(), end, flags, VmaKind::Image));
+            }
+        }
+    }
+    if let Some(vma) = image_vma {
+        space.add_vma(vma)?;
+    }
+
     //println!("mapping: {:?}", pages_to_map);
-    for (&page, &original_flags) in &pages_to_map {
-        let frame = allocator.allocate_frame().unwrap();
-        // unconditionally add writable flag so we can copy the section data to it
-        let flags = original_flags | PageTableFlags::WRITABLE;
-
-        //println!("  mapping {:?} to {:?} with {:?}", page, frame, flags);
-        unsafe {
-            mapper
-                .map_to(page, frame, flags, allocator)
-                .unwrap()
-                .flush();
-        };
+    for (&page, &flags) in &pages_to_map {
+        //println!("  mapping {:?} with {:?}", page, flags);
+        space.map(page, flags)?;
     }
 
     for section in &elf_file.segments {
         if section.ty == ElfSegmentType::Load {
             let section_src = &bytes[section.file_range.clone()];
-            let section_dst: &mut [u8] = unsafe {
-                slice::from_raw_parts_mut(section.addr.start.as_mut_ptr(), section.file_range.len())
-            };
-            section_dst.copy_from_slice(section_src);
+            space.write(section.addr.start, section_src);
             //let to_print = &section_src[..cmp::min(16, section_src.len())];
             //println!("  loaded segment {:X?}", to_print);
         }
     }
 
-    // remove writable flag for pages that dont want to be writable
-    for (&page, &original_flags) in &pages_to_map {
-        if !original_flags.contains(PageTableFlags::WRITABLE) {
-            unsafe {
-                mapper.update_flags(page, original_flags).unwrap().flush();
-            }
-        }
-    }
-
-    elf_file
+    Ok(elf_file)
 }
@@ -1,3 +1,6 @@
+use crate::memory::{KernelStack, StackKind};
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
 use x86_64::instructions::segmentation::{CS, DS, GS};
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
@@ -6,11 +9,22 @@ use x86_64::VirtAddr;
 pub const DOUBLE_FAULT_STACK_INDEX: u16 = 0;
 pub const PAGE_FAULT_STACK_INDEX: u16 = 1;
 
+/// Size of each of the interrupt stacks
+const STACK_SIZE: usize = 1024 * 20;
+
+/// The task state segment, which the CPU reads whenever an interrupt switches stacks
+struct Tss(UnsafeCell<TaskStateSegment>);
+
+// SAFETY: The TSS is only written by `init_stacks` while interrupts are disabled, and the CPU only
+// reads it when an interrupt arrives
+unsafe impl Sync for Tss {}
+
 lazy_static::lazy_static! {
-    static ref TSS: TaskStateSegment = {
+    /// Starts out with stacks in static memory so that faults during boot can be handled before
+    /// the heap exists. [`init_stacks`] replaces them with guard paged stacks
+    static ref TSS: Tss = {
         let mut tss = TaskStateSegment::new();
         tss.interrupt_stack_table[DOUBLE_FAULT_STACK_INDEX as usize] = {
-            const STACK_SIZE: usize = 1024 * 20;
             static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
 
             let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
@@ -18,7 +32,6 @@ lazy_static::lazy_static! {
         };
 
         tss.interrupt_stack_table[PAGE_FAULT_STACK_INDEX as usize] = {
-            const STACK_SIZE: usize = 1024 * 20;
             static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
 
             let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
@@ -26,41 +39,123 @@ lazy_static::lazy_static! {
         };
 
         tss.privilege_stack_table[0] = {
-            const STACK_SIZE: usize = 1024 * 20;
             static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
 
             let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
             stack_start + STACK_SIZE
         };
 
-        tss
+        Tss(UnsafeCell::new(tss))
     };
 }
 
+/// Allocates the interrupt stacks of one CPU from the vmalloc area, with a guard page below each
+/// of them, and points `tss` at them. The stacks are in use for as long as the kernel runs, so
+/// they are never freed
+fn allocate_stacks(tss: &mut TaskStateSegment) {
+    let stack = |kind| {
+        let stack = KernelStack::new(STACK_SIZE, kind).expect("no memory for interrupt stacks");
+        let top = stack.top();
+        core::mem::forget(stack);
+        top
+    };
+    let double_fault = stack(StackKind::DoubleFault);
+    let page_fault = stack(StackKind::PageFault);
+    let privilege = stack(StackKind::Privilege);
+
+    crate::sys::without_interrupts(|| {
+        tss.interrupt_stack_table[DOUBLE_FAULT_STACK_INDEX as usize] = double_fault;
+        tss.interrupt_stack_table[PAGE_FAULT_STACK_INDEX as usize] = page_fault;
+        tss.privilege_stack_table[0] = privilege;
+    });
+}
+
+/// Moves the interrupt stacks onto stacks from the vmalloc area with a guard page below them, so
+/// that overflowing one is caught and reported instead of corrupting memory.
+///
+/// Must be called once, after the kernel heap is initialized
+pub fn init_stacks() {
+    // SAFETY: The CPU only reads the TSS when an interrupt arrives, and `allocate_stacks` writes
+    // the new stacks with interrupts disabled. We are the only writer
+    allocate_stacks(unsafe { &mut *TSS.0.get() });
+}
+
+/// The TSS of the bootstrap processor
+pub fn tss() -> *const TaskStateSegment {
+    TSS.0.get()
+}
+
+/// The GDT and TSS of an application processor. The bootstrap processor uses the static ones
+pub struct CpuTables {
+    gdt: GlobalDescriptorTable,
+    selectors: Selectors,
+    tss: &'static TaskStateSegment,
+}
+
+impl CpuTables {
+    /// Creates the tables for another CPU, with its own guard paged interrupt stacks. They are
+    /// never freed, as the CPU uses them for as long as the kernel runs
+    ///
+    /// Must be called after the kernel heap is initialized
+    pub fn new() -> &'static CpuTables {
+        let mut tss = TaskStateSegment::new();
+        allocate_stacks(&mut tss);
+        let tss: &'static TaskStateSegment = Box::leak(Box::new(tss));
+        let (gdt, selectors) = build_gdt(tss);
+        Box::leak(Box::new(CpuTables {
+            gdt,
+            selectors,
+            tss,
+        }))
+    }
+
+    /// The TSS that holds the CPU's interrupt stacks
+    pub fn tss(&self) -> *const TaskStateSegment {
+        self.tss
+    }
+
+    /// Loads the tables on the calling CPU, in place of the ones it was started with
+    ///
+    /// # Safety
+    /// Must be called once on the CPU that the tables were created for, with interrupts disabled
+    pub unsafe fn load(&'static self) {
+        // SAFETY: Guaranteed by the caller
+        unsafe { load_gdt(&self.gdt, &self.selectors) };
+    }
+}
+
 lazy_static::lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
+        // SAFETY: Only the stack pointers in the TSS ever change, the descriptor just needs its
+        // address
+        build_gdt(unsafe { &*TSS.0.get() })
+    };
+}
 
-        let kernel_code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let kernel_data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+/// Creates a GDT with a descriptor for `tss`. Every GDT has the same layout, so the selectors are
+/// the same on every CPU
+fn build_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
 
-        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
-        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+    let kernel_code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let kernel_data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
 
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        let kernel_data_selector2 = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
 
-        let selectors = Selectors {
-            kernel_code_selector,
-            kernel_data_selector,
-            user_code_selector,
-            user_data_selector,
-            tss_selector,
-            kernel_data_selector2,
-        };
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    let kernel_data_selector2 = gdt.add_entry(Descriptor::kernel_data_segment());
 
-        (gdt, selectors)
+    let selectors = Selectors {
+        kernel_code_selector,
+        kernel_data_selector,
+        user_code_selector,
+        user_data_selector,
+        tss_selector,
+        kernel_data_selector2,
     };
+
+    (gdt, selectors)
 }
 
 struct Selectors {
@@ -74,32 +169,33 @@ struct Selectors {
 
 #[no_mangle]
 pub fn gdt_init() {
+    // SAFETY: The GDT and TSS are statics that live for as long as the kernel runs
+    unsafe { load_gdt(&GDT.0, &GDT.1) };
+}
+
+/// Loads `gdt`, sets up the segment registers and the `syscall` segments, and loads the TSS
+///
+/// # Safety
+/// `gdt` and its TSS must never be freed or changed, apart from the stacks in the TSS
+unsafe fn load_gdt(gdt: &'static GlobalDescriptorTable, selectors: &Selectors) {
     use x86_64::instructions::tables::load_tss;
     use x86_64::registers::model_specific::Star;
     use x86_64::registers::segmentation::Segment;
 
-    use raw_cpuid::CpuId;
-    let cpuid = CpuId::new();
-
-    let has_fsgbase = cpuid
-        .get_extended_feature_info()
-        .map_or(false, |info| info.has_fsgsbase());
-    assert!(has_fsgbase);
-
-    GDT.0.load();
+    gdt.load();
     unsafe {
-        CS::set_reg(GDT.1.kernel_code_selector);
-        DS::set_reg(GDT.1.kernel_data_selector);
-        GS::set_reg(GDT.1.kernel_data_selector2);
+        CS::set_reg(selectors.kernel_code_selector);
+        DS::set_reg(selectors.kernel_data_selector);
+        GS::set_reg(selectors.kernel_data_selector2);
 
         Star::write(
-            GDT.1.user_code_selector,
-            GDT.1.user_data_selector,
-            GDT.1.kernel_code_selector,
-            GDT.1.kernel_data_selector,
+            selectors.user_code_selector,
+            selectors.user_data_selector,
+            selectors.kernel_code_selector,
+            selectors.kernel_data_selector,
         )
         .unwrap();
 
-        load_tss(GDT.1.tss_selector);
+        load_tss(selectors.tss_selector);
     }
 }
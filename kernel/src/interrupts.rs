@@ -1,8 +1,20 @@
 use {
-    crate::{print, println, QemuExitCode},
+    crate::{
+        memory::{is_user_page, overflowed_stack, FaultError},
+        percpu::InterruptGuard,
+        print, println,
+        syscall::{exception_fixup, smap_enabled, smep_enabled, with_user_access},
+        QemuExitCode,
+    },
     core::{arch::asm, slice},
     pic8259::ChainedPics,
-    x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+    x86_64::{
+        registers::{control::Cr2, rflags::RFlags},
+        structures::{
+            idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+            paging::Page,
+        },
+    },
 };
 
 pub const PIC_1_OFFSET: u8 = 32;
@@ -23,6 +35,9 @@ lazy_static::lazy_static! {
         };
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        idt[crate::smp::SPURIOUS_INTERRUPT_VECTOR as usize]
+            .set_handler_fn(spurious_interrupt_handler);
+        idt[crate::smp::CALL_FUNCTION_VECTOR as usize].set_handler_fn(call_function_handler);
         idt
     };
 }
@@ -34,6 +49,15 @@ pub fn init_idt() {
     IDT.load();
 }
 
+/// Called on every timer interrupt, see [`set_timer_hook`]
+static TIMER_HOOK: spin::Mutex<Option<fn()>> = spin::Mutex::new(None);
+
+/// Calls `hook` from the timer interrupt handler, or stops calling anything if it is `None`. Lets
+/// tests run code in interrupt context at any point of the code they interrupt
+pub fn set_timer_hook(hook: Option<fn()>) {
+    crate::sys::without_interrupts(|| *TIMER_HOOK.lock() = hook);
+}
+
 #[no_mangle]
 extern "sysv64" fn my_write(ptr: *const u8, len: usize) {
     let slice = unsafe { slice::from_raw_parts(ptr, len) };
@@ -86,21 +110,97 @@ extern "x86-interrupt" fn divide_error_handler(frame: InterruptStackFrame) {
 
 #[no_mangle]
 extern "x86-interrupt" fn double_fault_handler(frame: InterruptStackFrame, code: u64) -> ! {
+    // Overflowing the page fault stack turns the page fault into a double fault
+    if let Some(stack) = overflowed_stack(Cr2::read()) {
+        println!("{} overflowed", stack);
+    }
     println!("DOUBLE FAULT. Code: {}\n{:#?}", code, frame);
     crate::exit_qemu(QemuExitCode::Failed);
 }
 
 #[no_mangle]
-extern "x86-interrupt" fn page_fault_handler(frame: InterruptStackFrame, code: PageFaultErrorCode) {
-    let top_of_stack: u64 = unsafe { *frame.stack_pointer.as_ptr() };
+extern "x86-interrupt" fn page_fault_handler(
+    mut frame: InterruptStackFrame,
+    code: PageFaultErrorCode,
+) {
+    let guard = InterruptGuard::enter(&frame);
+    let addr = Cr2::read();
+    let user_page = is_user_page(Page::containing_address(addr));
+    if user_page && !code.contains(PageFaultErrorCode::USER_MODE) {
+        // These are kernel bugs that the VMAs must not paper over, for example by resolving a copy
+        // on write fault that the kernel was never allowed to cause
+        if smep_enabled() && code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            panic!(
+                "kernel tried to execute user address {:?} rip: {:?}",
+                addr, frame.instruction_pointer
+            );
+        }
+        let in_window = frame.cpu_flags & RFlags::ALIGNMENT_CHECK.bits() != 0;
+        if smap_enabled() && code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) && !in_window {
+            panic!(
+                "kernel accessed user address {:?} outside of a user access window rip: {:?}",
+                addr, frame.instruction_pointer
+            );
+        }
+    }
+    if user_page {
+        // Faults on user addresses are resolved by the VMAs of the current process.
+        // `try_with_current` fails if the kernel faulted while already borrowing the process,
+        // which is a kernel bug so we fall through and panic below
+        let result = crate::process::try_with_current(|p| {
+            (p.pid(), p.address_space.handle_fault(addr, code))
+        });
+        let user_mode = code.contains(PageFaultErrorCode::USER_MODE);
+        match result {
+            Some((_, Ok(()))) => return,
+            // The faulting instruction runs again once a larger process made room
+            Some((_, Err(FaultError::OutOfMemory))) if crate::process::kill_larger_process() => {
+                return
+            }
+            // Only faults from user mode kill the process. When the kernel faulted on its behalf,
+            // while copying user memory during a syscall, the syscall fails instead
+            Some((pid, Err(err))) if user_mode => {
+                println!(
+                    "pid {} killed: page fault at {:?} ({:?}, {:?}) rip: {:?}",
+                    pid, addr, err, code, frame.instruction_pointer
+                );
+                // The handler never returns, so the guard is left as it is: GS already holds the
+                // kernel's base for `sysret` to swap out, and the next process starts with a depth
+                // of 0
+                core::mem::forget(guard);
+                crate::process::exit_current(crate::process::ExitStatus::Killed);
+            }
+            _ => {}
+        }
+        // `copy_from_user` and `copy_to_user` fail gracefully instead
+        let out_of_memory = matches!(result, Some((_, Err(FaultError::OutOfMemory))));
+        if let Some(fixup) = exception_fixup(frame.instruction_pointer, out_of_memory) {
+            // SAFETY: The fixup expects to continue right where the faulting instruction left off
+            unsafe { frame.as_mut().update(|f| f.instruction_pointer = fixup) };
+            return;
+        }
+    }
+
+    if let Some(stack) = overflowed_stack(addr) {
+        panic!("{} overflowed", stack);
+    }
+
+    // The fault may have come from user mode, in which case this is the user's stack
+    let top_of_stack: u64 = with_user_access(|| unsafe { *frame.stack_pointer.as_ptr() });
     panic!(
-        "PAGE FAULT. Code: {:?}\n{:?}\ntop of stack: 0x{:X}",
-        code, frame, top_of_stack
+        "PAGE FAULT at {:?}. Code: {:?}\n{:?}\ntop of stack: 0x{:X}",
+        addr, code, frame, top_of_stack
     )
 }
 
 #[no_mangle]
-extern "x86-interrupt" fn timer_interrupt_handler(_frame: InterruptStackFrame) {
+extern "x86-interrupt" fn timer_interrupt_handler(frame: InterruptStackFrame) {
+    let _guard = InterruptGuard::enter(&frame);
+    // Interrupts are disabled while the hook is set, so the lock is free
+    if let Some(hook) = *TIMER_HOOK.lock() {
+        hook();
+    }
+    crate::memory::report_pressure();
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::Timer.as_u8())
@@ -108,7 +208,8 @@ extern "x86-interrupt" fn timer_interrupt_handler(_frame: InterruptStackFrame) {
 }
 
 #[no_mangle]
-extern "x86-interrupt" fn keyboard_interrupt_handler(_frame: InterruptStackFrame) {
+extern "x86-interrupt" fn keyboard_interrupt_handler(frame: InterruptStackFrame) {
+    let _guard = InterruptGuard::enter(&frame);
     use x86_64::instructions::port::Port;
 
     let mut port = Port::new(0x60);
@@ -121,6 +222,16 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_frame: InterruptStackFrame
     };
 }
 
+/// The local APIC sends these when an interrupt goes away before the CPU accepts it. They must not
+/// be acknowledged
+extern "x86-interrupt" fn spurious_interrupt_handler(_frame: InterruptStackFrame) {}
+
+/// Another CPU asked this one to run a function, see [`crate::smp::run_on`]
+extern "x86-interrupt" fn call_function_handler(frame: InterruptStackFrame) {
+    let _guard = InterruptGuard::enter(&frame);
+    crate::smp::handle_call();
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
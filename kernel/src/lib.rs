@@ -23,8 +23,12 @@
 //! 4. `cd` into the kernel directory: `cd kernel`
 //! 5. Run it! `cargo run`
 //! 
+//! The kernel boots on Qemu's default CPU model. It uses the FSGSBASE instructions, SMEP and SMAP where the CPU has them, and falls back to the GS base MSRs otherwise.
+//! 
 //! NOTE: The first time may take a few minutes while `cargo` downloads all the dependencies, compiles the standard library from scratch plus Zulu-OS for our special CPU target
 //! 
+//! Every process is loaded at randomized addresses. To debug a user program with `gdb.sh`, run `cargo run --features no_aslr` so that it is loaded at the fixed address that `gdb.sh` expects. This is a build feature rather than a boot option, because the bootloader doesn't pass a command line to the kernel.
+//! 
 //! ## Design
 //! 
 //! 
@@ -32,6 +36,10 @@
 //! 
 //! Zulu-OS maps all physical memory at a fixed fixed virtual address, making it trivially easy to modify any physical page and thus, modify or create any page mapping.
 //! Fixed offset mapping simplifies the process of allocating new kernel memory as well as loading userspace processes.
+//! At boot the kernel prints a table over serial that sums up the regions of each type in the bootloader's memory map.
+//! Every usable physical frame also has an entry in a frame table that records its reference count, its owner (kernel heap, page table, DMA or the pid of a user process) and flags. Shared frames are reference counted there, double frees panic, and any frame a process still owns after it exits is reported as leaked.
+//! Other kernel mappings, such as stacks and device memory, get their addresses from `vmalloc`, which hands out non-overlapping ranges of a dedicated region with an unmapped guard page below each one. The interrupt stacks and each process's syscall stack are allocated this way, so overflowing one faults on its guard page and the fault handler reports which stack overflowed. Page tables that are left empty when a range is freed are freed with it.
+//! The kernel heap is the one kernel region that keeps a fixed address (`HEAP_START`), because `vmalloc` keeps its own bookkeeping on the heap, and the heap has to grow in place. User stacks and images are placed by each process's randomized layout instead.
 //! 
 //! 
 //! ### Bootloading
@@ -48,27 +56,43 @@
 //! 
 //! ### Syscalls
 //! 
-//! Zulu-OS currently supports three user space syscalls:
+//! Zulu-OS currently supports fourteen user space syscalls:
 //! 1. Read. A userspace program can read one or more bytes from the keyboard.
 //! 2. Write. A userspace program can ask the kernel to print the given text by writing to the VGA buffer.
 //! 3. Exit. 
+//! 4. Fork. Creates a copy of the calling process that shares its memory copy-on-write until either side writes to it.
+//! 5. MemInfo. Reports total, free and kernel-reserved physical frames, kernel heap usage, and how many pages the calling process has mapped.
+//! 6. Mmap. Maps zeroed, private anonymous memory, either at a hint address if it is free, at an exact address with `MAP_FIXED`, or above the process's randomized mmap base.
+//! 7. Munmap. Unmaps a range of pages, splitting any mappings that are only partly inside of it.
+//! 8. Mprotect. Changes the permissions of mapped pages. Memory can never be both writable and executable, and there is no `PROT_NONE`: mmap and mprotect reject an empty set of permissions.
+//! 9. ShmCreate. Creates a shared memory object and returns a handle to it. Handles are numbered per process, like file descriptors, and forked children inherit them. The creator chooses whether other processes may open the object read-write, read-only, or not at all.
+//! 10. ShmOpen. Opens a handle to a shared memory object by its id, with the access that its creator allows.
+//! 11. ShmMap. Maps a whole shared memory object, writable only through a read-write handle. Writes are seen by every process that maps it, and the mapping keeps the memory alive.
+//! 12. ShmClose. Closes a handle to a shared memory object. Its memory is freed once it is also unmapped everywhere.
+//! 13. Brk. Moves the end of the process's heap, which starts at a randomized heap base. With its `alloc` feature, the `syscall` crate provides a global allocator on top of it, so user programs can use `Vec`, `String` and the other `alloc` collections.
+//! 14. ShmId. Returns the id of the shared memory object that a handle refers to, for passing to another process.
 //!
 //! This set of syscalls, while limited, it does allow for creation of simple games and text programs running in userspace. 
 //! The current test userspace program that is run after the kernel is initialized (found inside the [userspace_test](./userspace_test/) directory)
-//! calls write to show that printing works, and then calls exit.
+//! calls write to show that printing works, forks to check that the child's writes stay private, checks the memory statistics, passes bad pointers to write, maps and protects memory, passes a message to a child through shared memory, builds and frees a large `Vec` and `BTreeMap`, lets a child run the machine out of memory, and then calls exit. The kernel's `out_of_memory` test runs it too, and checks that the kernel killed the child and got its memory back.
 //! A goal of this project is to extend the available syscalls to allow for more complex programs without compromising the security of the kernel.
+//! Syscall arguments that point to user memory are wrapped in `UserPtr`, `UserSlice` or the length-bounded `UserStr`, which check the range, overflow and alignment up front and then only touch user memory through `copy_from_user` and `copy_to_user`. A page fault inside them is recovered through an exception fixup table, so bad pointers make the syscall fail with `BadAddress` instead of crashing the kernel, and a page that can't be backed makes it fail with `OutOfMemory`. Only faults from user mode kill the process.
+//! On CPUs that support them, SMEP and SMAP are enabled so the kernel faults if it executes user memory or touches it outside of an explicit `with_user_access` window, which syscalls open only around their accesses to user buffers.
 //! 
 //! A userspace syscall library is provided in [syscall](./syscall/) directory and used in [userspace_test](./userspace_test/).
 //! 
 //! 
 //! ### Kernel Memory Allocation
 //! 
-//! We use a bump allocator that is given 100KB worth of pages on kernel init. Memory is never reclaimed.
-//! We did this to keep the allocation implementation simple due to kernel memory rarely being allocated.
+//! The kernel heap starts at `0x4444_4440_0000` and is given 2MiB of memory on kernel init, mapped with a single huge page when a contiguous 2MiB frame is available. It used to start at `0x4444_4444_0000` with 100KiB, and was moved to the 2MiB boundary below that so that huge pages fit. Large anonymous user mappings are backed with huge pages the same way, while the physical memory window is left as the bootloader mapped it. It is managed by a first fit allocator that keeps free blocks in a list sorted by address, so freed blocks are merged with their free neighbors and reused by later allocations of any size. The allocator runs with interrupts disabled, so interrupt handlers can't deadlock on it. Its free list is the `free_list` crate, which the userspace allocator in the `syscall` crate uses too, without it being part of the syscall ABI.
+//! When the heap runs out of room it maps more pages after its end, up to a configurable maximum of 64MiB by default, and `shrink_kernel_heap` gives unused pages at the end back to the frame allocator.
+//! Fixed size kernel objects can come from named slab caches instead, such as `SlabCache::<Process>::new("process")`. Each slab is a single frame cut into equally sized objects, so allocating and freeing an object is O(1), and caches can run a constructor for new objects. The VMAs of every address space come from the `vma` cache. `print_slab_stats` prints the objects in use, slabs and wasted bytes of every cache over serial.
+//! Building with `--features debug_alloc` wraps the heap allocator in a debug allocator, which surrounds every allocation with redzones that are checked when it is freed, poisons freed memory, and counts live allocations per size class. `print_allocations` dumps every outstanding allocation over serial to track down leaks.
 //! 
 //! ### Interrupt handling
 //! 
-//! Illegal instructions, page faults, and floating point exceptions are currently not handled while executing in user mode, which causes a kernel panic.
+//! Page faults inside a VMA are resolved by backing the page with a zeroed frame, and any other user page fault kills the process. Illegal instructions and floating point exceptions are currently not handled while executing in user mode, which causes a kernel panic.
+//! When no frame is left to back a page, the largest user process is killed to make room, and the fault is retried if that wasn't the faulting process. Syscalls that need memory fail with `OutOfMemory` instead, and the kernel logs when free memory runs low and when it recovers. The frame allocator only records this in atomics, and the timer interrupt logs it, so that allocating never waits for the screen's lock.
 //! More work is needed on the scheduler to make processes dynamic enough to support stopping at any time
 //! 
 //! 
@@ -76,27 +100,43 @@
 //! 
 //! Once the kernel is initialized the embedded userspace binary is executed.
 //! Execution occurs until the userspace program either crashes the kernel or invokes the exit syscall.
-//! Because only running a single process is currently supported, the kernel enters a wait-for-interrupt loop to save power until the CPU it is reset.
-//! This will be expanded upon in the future to context switch to another process
+//! Processes only switch at syscall boundaries: a forked child runs first while its parent waits in a ready queue, and whenever a process exits the next ready process is resumed.
+//! Once no processes are left, the kernel enters a wait-for-interrupt loop to save power until the CPU it is reset.
 //!
+//! ### Multiprocessing
+//! 
+//! At boot the other CPUs are found in the ACPI MADT and started with the INIT-SIPI-SIPI sequence through the local APIC. They begin in real mode at a small trampoline that is copied below 1MiB, which switches straight to long mode on the kernel's page tables.
+//! Each CPU then loads its own GDT and TSS with its own guard paged interrupt stacks, points GS at its own per-CPU area, reports its APIC ID over serial and idles, since processes only run on the bootstrap processor for now. `smp::run_on` runs a function on another CPU by sending it an IPI, which the tests use to allocate on every CPU.
+//! The per-CPU area holds the syscall stack pointers, the running process, the level 4 table of the address space that is active on the CPU, the run queue, a pointer to the TSS and the interrupt nesting depth. The kernel's own page tables are shared by every CPU, so `memory::mapper()` is a single global behind a lock that is taken with interrupts disabled, and any CPU can grow the heap or map vmalloc pages. Kernel code reaches its fields with `percpu!(field)`, and interrupts from user mode swap in the kernel's GS base before touching it.
+//! Qemu is run with `-smp 4` so that this is covered by the tests.
+//! 
 //! ### Testing
 //!
 //! Like any other complex project, testing is essential to ensuring functionality while preventing
 //! regressions. Qemu is used to execute the integration tests inside [kernel/tests](./kernel/tests)
 //! in the same context thet we run the OS in, as well as isolated from one another
+//! `./test.sh` in the kernel directory is the entry point for running them: it runs them twice, on a CPU model with the FSGSBASE instructions and SMAP, and on Qemu's default model that has neither, where the GS bases are accessed through MSRs instead. A plain `cargo test` only covers the first model.
 //!
 
 extern crate alloc;
 
 use bootloader::BootInfo;
 use core::panic::PanicInfo;
+use x86_64::{
+    structures::paging::{Page, PageTableFlags},
+    VirtAddr,
+};
 
 pub mod allocator;
 pub mod elf;
 pub mod gdt;
 pub mod interrupts;
 pub mod memory;
+pub mod percpu;
+pub mod process;
+pub mod random;
 pub mod serial;
+pub mod smp;
 pub mod sys;
 pub mod syscall;
 pub mod task;
@@ -107,6 +147,7 @@ pub fn init(_boot_info: &'static BootInfo) {
     interrupts::init_idt();
     unsafe { interrupts::PICS.lock().initialize() };
     syscall::init();
+    percpu::init();
 }
 
 pub trait Testable {
@@ -138,6 +179,39 @@ pub fn test_panic_handler(info: &PanicInfo) -> ! {
     exit_qemu(QemuExitCode::Failed);
 }
 
+/// Collects the start of a formatted message, dropping whatever doesn't fit
+struct Message {
+    bytes: [u8; 256],
+    len: usize,
+}
+
+impl core::fmt::Write for Message {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let len = s.len().min(self.bytes.len() - self.len);
+        self.bytes[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+/// The panic handler of tests that are meant to panic. The test passes if the panic message
+/// contains `expected`, and fails if it panicked for any other reason
+pub fn expected_panic_handler(info: &PanicInfo, expected: &str) -> ! {
+    use core::fmt::Write;
+
+    let mut message = Message {
+        bytes: [0; 256],
+        len: 0,
+    };
+    let _ = write!(message, "{}", info);
+    let message = core::str::from_utf8(&message.bytes[..message.len]).unwrap_or("");
+    if message.contains(expected) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    }
+    test_panic_handler(info)
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u32)]
 pub enum QemuExitCode {
@@ -155,6 +229,26 @@ pub fn exit_qemu(exit_code: QemuExitCode) -> ! {
     crate::sys::hlt_loop();
 }
 
+/// The flags that tests map user data with. VMAs can't be both writable and executable
+pub const TEST_USER_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::USER_ACCESSIBLE)
+    .union(PageTableFlags::NO_EXECUTE);
+
+/// The `n`th page of the user half, for tests that map user memory
+pub fn test_user_page(n: u64) -> Page {
+    Page::containing_address(VirtAddr::new(memory::USER_START + n * 4096))
+}
+
+/// Uses the VMA cache once. It keeps its first slab from then on, so tests that compare frame
+/// counts call this before they start
+pub fn warm_vma_cache() {
+    let start = test_user_page(0).start_address();
+    let kind = memory::VmaKind::Anonymous;
+    let vma = memory::Vma::new(start, start + 4096u64, TEST_USER_FLAGS, kind);
+    memory::AddressSpace::new().unwrap().add_vma(vma).unwrap();
+}
+
 #[cfg(test)]
 bootloader::entry_point!(kernel_main_test);
 
@@ -171,7 +265,16 @@ fn panic(info: &PanicInfo) -> ! {
     test_panic_handler(info)
 }
 
+/// Called when an allocation that can't fail doesn't fit in the kernel heap. Allocations made on
+/// behalf of a process use `try_reserve` and the like, so that its syscall fails with
+/// `OutOfMemory` instead. What is left is the kernel's own bookkeeping, which can't be given up
+/// halfway, and no process can safely be killed from here while the allocator's callers may hold
+/// its locks
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
-    panic!("allocation error: {:?}", layout)
+    let heap = allocator::heap_stats();
+    panic!(
+        "allocation error: {:?} with {} of {} heap bytes in use",
+        layout, heap.used, heap.size
+    )
 }
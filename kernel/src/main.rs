@@ -12,12 +12,11 @@ extern crate alloc;
 use {
     bootloader::BootInfo,
     core::{arch::asm, num::NonZeroU64, panic::PanicInfo},
-    x86_64::{
-        registers::rflags::RFlags,
-        structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags},
-        VirtAddr,
+    x86_64::VirtAddr,
+    zulu_os::{
+        memory,
+        process::{self, Process},
     },
-    zulu_os::{memory, memory::BootInfoFrameAllocator, syscall},
 };
 
 #[repr(C)] // guarantee 'bytes' comes after '_align'
@@ -65,80 +64,27 @@ extern "C" fn kernel_main(boot_info: &'static BootInfo, rsp: u64) -> ! {
     zulu_os::init(boot_info);
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut frame_allocator = memory::frame_allocator();
     // SAFETY:
     // 1. interrupts are disabled as they off by default, and havent been enabled yet
     // 2. The bootloader has mapped all of physical memory at `physical_memory_offset`
-    let mut frame_allocator = unsafe { memory::init(phys_mem_offset) }.with(|mapper| {
+    // 3. The bootloader only marks frames it doesn't use as usable
+    unsafe { memory::init(phys_mem_offset, &boot_info.memory_map) }.with(|mapper| {
         // setup heap while we have mapper
-        let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
-
         unsafe { zulu_os::allocator::init_kernel_heap(mapper, &mut frame_allocator) }
             .expect("Failed to init heap");
-        frame_allocator
     });
+    zulu_os::gdt::init_stacks();
+    zulu_os::smp::init();
+    memory::print_memory_map(&boot_info.memory_map);
 
-    syscall::init_thread_data(syscall::ThreadData {
-        kernel_rsp: NonZeroU64::new(rsp),
-        user_tmp_rsp: None,
-    });
+    zulu_os::percpu!(kernel_rsp).set(NonZeroU64::new(rsp));
 
     #[cfg(test)]
     test_main();
 
-    let lowest_stack_page = Page::containing_address(VirtAddr::new(0xDEADBEEF));
-    let stack_size = 4096u64 * 4;
-    let highest_stack_page =
-        Page::containing_address(lowest_stack_page.start_address() + stack_size);
-    let user_stack = Page::range(lowest_stack_page, highest_stack_page);
-
-    let flags =
-        PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE;
-
-    // SAFETY: 1. Interrupts are disabled 2. `memory::init` has been called 3. No recursion
-    let bin = unsafe { memory::mapper() }.with(|mapper| {
-        for page in user_stack {
-            let frame = frame_allocator.allocate_frame().unwrap();
-            unsafe {
-                mapper
-                    .map_to(page, frame, flags, &mut frame_allocator)
-                    .unwrap()
-                    .flush();
-            };
-        }
-
-        zulu_os::elf::load(CHILD_PROCESS, mapper, &mut frame_allocator)
-    });
-
-    let top_of_stack = lowest_stack_page.start_address().as_u64() + stack_size;
-
-    unsafe { enter_user_mode(bin.entry_point.as_u64(), top_of_stack) };
-}
-
-/// Sets the CPU to user mode (Ring 3) and jumps to `addr` using the stack starting at `user_stack`
-/// Also enables interrupts
-#[no_mangle]
-#[naked]
-pub unsafe extern "sysv64" fn enter_user_mode(addr: u64, user_stack: u64) -> ! {
-    unsafe {
-        asm!(
-            // rip gets set to rcx when sysret is invoked, so write our first parameter there
-            "mov rcx, rdi",
-            "mov r11, {user_flags}",
-            "mov rsp, rsi", // setup stack with `user_stack` (second param)
-            "mov rbp, rsi",
-            "swapgs",
-            "sysretq",
-            user_flags = const user_mode_flags(),
-            options(noreturn)
-        )
-    };
-}
-
-const fn user_mode_flags() -> usize {
-    // enable interrupts while in user mode
-    RFlags::INTERRUPT_FLAG.bits() as usize
-        // set the "resered, always 1" flag
-        | 2
+    let process = Process::spawn(CHILD_PROCESS).expect("Failed to load user process");
+    process::run(process);
 }
 
 /// This function is called on panic.
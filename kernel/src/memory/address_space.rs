@@ -0,0 +1,1069 @@
+use alloc::vec::Vec;
+use core::{cmp, ptr};
+
+use x86_64::{
+    instructions::tlb,
+    registers::control::Cr3,
+    structures::idt::PageFaultErrorCode,
+    structures::paging::{
+        frame::PhysFrameRange,
+        mapper::{
+            FlagUpdateError, MapToError, MappedFrame, MapperFlush, TranslateResult, UnmapError,
+        },
+        page::PageRange,
+        page_table::PageTableEntry,
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable,
+        PageTableFlags, PhysFrame, Size2MiB, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    allocator::{SlabBox, SlabCache},
+    percpu,
+};
+
+use super::{
+    allocate_huge_frame, check_vma_flags, deallocate_contiguous, frame_allocator,
+    frame_allocator_for, frame_refcount, huge_frame_parts, kernel_level_4_frame, phys_to_virt,
+    physical_memory_offset, release_frame, share_frame, FrameOwner, GlobalFrameAllocator, Vma,
+    VmaError, VmaKind, MAX_ORDER,
+};
+
+/// The lowest address that user programs may map.
+///
+/// The bootloader puts the kernel, the physical memory mapping and its own data into the first
+/// few level 4 slots, the kernel heap lives at slot 136 and the vmalloc area at slot 160, so
+/// userspace gets its own range of level 4 slots that the kernel never touches.
+pub const USER_START: u64 = 0x0000_1000_0000_0000;
+
+/// One past the highest address that user programs may map
+pub const USER_END: u64 = 0x0000_4000_0000_0000;
+
+/// Level 4 slots that belong to userspace, every other slot is shared with the kernel
+const USER_SLOTS: core::ops::Range<usize> = (USER_START >> 39) as usize..(USER_END >> 39) as usize;
+
+/// Returns true if `page` lies inside the user part of the address space
+pub fn is_user_page(page: Page) -> bool {
+    (USER_START..USER_END).contains(&page.start_address().as_u64())
+}
+
+/// Marks a read-only page whose frame is shared with another address space. Writing to it makes
+/// a private copy of the frame, see [`AddressSpace::fork`]
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Marks a page of a shared memory object. Its frame is shared on purpose, so writes always go
+/// to the frame itself, even after a fork, see [`AddressSpace::map_shared`]
+pub const SHARED: PageTableFlags = PageTableFlags::BIT_10;
+
+/// Reasons why a page fault could not be resolved
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultError {
+    /// The address isn't inside any VMA
+    NoVma,
+    /// The VMA doesn't allow this kind of access
+    AccessViolation,
+    /// No frame could be allocated to back the page
+    OutOfMemory,
+}
+
+/// A virtual address space with its own level 4 page table.
+///
+/// Every kernel mapping is shared with the boot page table by pointing the kernel's level 4 slots
+/// at the same lower level tables, so only user mappings are private to the address space.
+/// All user frames and page tables are given back to the frame allocator when it is dropped.
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+    /// Every VMA in this address space, sorted by start address. A `Vec` instead of a map, so that
+    /// room for new VMAs can be reserved without aborting when the heap is full
+    vmas: Vec<SlabBox<Vma>>,
+    /// Who the user frames of this address space are accounted to. Uses pid 0 until
+    /// [`Self::set_owner`] is called
+    owner: FrameOwner,
+}
+
+/// Allocates frames for page tables
+fn table_allocator() -> GlobalFrameAllocator {
+    frame_allocator_for(FrameOwner::PageTable)
+}
+
+impl AddressSpace {
+    /// Creates a new address space with no user mappings.
+    pub fn new() -> Result<Self, MapToError<Size4KiB>> {
+        let level_4_frame = table_allocator()
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+
+        // SAFETY: Both tables are mapped through the physical memory mapping. The kernel's table
+        // is only read, and the new table was just allocated so nobody else can see it
+        let (kernel_table, table) = unsafe {
+            (
+                &*phys_to_virt(kernel_level_4_frame().start_address()).as_ptr::<PageTable>(),
+                &mut *phys_to_virt(level_4_frame.start_address()).as_mut_ptr::<PageTable>(),
+            )
+        };
+        table.zero();
+        for (i, entry) in kernel_table.iter().enumerate() {
+            if entry.is_unused() {
+                continue;
+            }
+            assert!(
+                !USER_SLOTS.contains(&i),
+                "kernel mapping in user level 4 slot {}",
+                i
+            );
+            table[i] = entry.clone();
+        }
+
+        Ok(AddressSpace {
+            level_4_frame,
+            vmas: Vec::new(),
+            owner: FrameOwner::User { pid: 0 },
+        })
+    }
+
+    /// Accounts the user frames that are allocated from now on to the process `pid`
+    pub fn set_owner(&mut self, pid: u64) {
+        self.owner = FrameOwner::User { pid };
+    }
+
+    /// The physical frame holding this address space's level 4 table
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// Returns a mapper that modifies this address space's page tables
+    ///
+    /// The user half is private to this address space, but changes to kernel mappings through
+    /// the returned mapper are visible in every address space.
+    pub fn mapper(&mut self) -> OffsetPageTable<'_> {
+        let table = phys_to_virt(self.level_4_frame.start_address()).as_mut_ptr();
+        // SAFETY: We own the level 4 table, and physical memory is mapped at the offset.
+        // The returned mapper borrows `self` mutably so there can be only one at a time
+        unsafe { OffsetPageTable::new(&mut *table, physical_memory_offset()) }
+    }
+
+    /// Maps a newly allocated, zeroed frame at `page` with `flags`.
+    ///
+    /// Returns the frame that was mapped
+    pub fn map(
+        &mut self,
+        page: Page,
+        flags: PageTableFlags,
+    ) -> Result<PhysFrame, MapToError<Size4KiB>> {
+        let mut allocator = frame_allocator_for(self.owner);
+        let frame = allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        // SAFETY: The frame was just allocated, so nothing else refers to it
+        unsafe {
+            ptr::write_bytes(
+                phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(),
+                0,
+                4096,
+            )
+        };
+
+        // SAFETY: The frame is unused, and the page is a user page so the kernel does not rely on
+        // it
+        match unsafe { self.map_to(page, frame, flags) } {
+            Ok(()) => Ok(frame),
+            Err(e) => {
+                // SAFETY: The frame was never mapped
+                unsafe { allocator.deallocate_frame(frame) };
+                Err(e)
+            }
+        }
+    }
+
+    /// Maps `page` to `frame` with `flags`.
+    ///
+    /// The address space takes ownership of `frame`, and will release it when the page is
+    /// unmapped or the address space is dropped.
+    ///
+    /// # Safety
+    /// `frame` must not be used by anything else, unless it is shared through [`share_frame`]
+    /// and this mapping owns one of its references
+    pub unsafe fn map_to(
+        &mut self,
+        page: Page,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        assert!(is_user_page(page), "{:?} is not a user page", page);
+        // SAFETY: `page` is in the user half, so changing it can't break the kernel, and the
+        // caller guarantees that `frame` is unused
+        let flush = unsafe {
+            self.mapper()
+                .map_to(page, frame, flags, &mut table_allocator())?
+        };
+        self.flush(flush);
+        Ok(())
+    }
+
+    /// Unmaps `page` and drops this address space's reference to its frame, freeing the frame if
+    /// no other address space shares it.
+    ///
+    /// If `page` is part of a huge page, the huge page is split and the rest of it stays mapped
+    pub fn unmap(&mut self, page: Page) -> Result<(), UnmapError> {
+        assert!(is_user_page(page), "{:?} is not a user page", page);
+        self.split_huge_page(page.start_address())
+            .map_err(|_| UnmapError::ParentEntryHugePage)?;
+        let (frame, flush) = self.mapper().unmap(page)?;
+        self.flush(flush);
+        // SAFETY: The frame was owned by this address space and is no longer mapped
+        unsafe { release_frame(frame, self.owner) };
+        Ok(())
+    }
+
+    /// Changes the flags that `page` is mapped with.
+    ///
+    /// If `page` is part of a huge page, the huge page is split and the rest of it keeps its flags
+    pub fn update_flags(
+        &mut self,
+        page: Page,
+        flags: PageTableFlags,
+    ) -> Result<(), FlagUpdateError> {
+        assert!(is_user_page(page), "{:?} is not a user page", page);
+        self.split_huge_page(page.start_address())
+            .map_err(|_| FlagUpdateError::ParentEntryHugePage)?;
+        // SAFETY: Only the flags of a user page change
+        let flush = unsafe { self.mapper().update_flags(page, flags) }?;
+        self.flush(flush);
+        Ok(())
+    }
+
+    /// Replaces the huge page containing `addr` with 4KiB pages that map the same frames with the
+    /// same flags. Does nothing if `addr` isn't mapped by a huge page
+    fn split_huge_page(&mut self, addr: VirtAddr) -> Result<(), MapToError<Size4KiB>> {
+        let TranslateResult::Mapped {
+            frame: MappedFrame::Size2MiB(frame),
+            flags,
+            ..
+        } = self.translate(addr) else {
+            return Ok(());
+        };
+        let huge_page = Page::<Size2MiB>::containing_address(addr);
+        let (_, flush) = self.mapper().unmap(huge_page).expect("huge page is mapped");
+        self.flush(flush);
+
+        let flags = flags - PageTableFlags::HUGE_PAGE;
+        let first_page = Page::<Size4KiB>::containing_address(huge_page.start_address());
+        for (i, part) in huge_frame_parts(frame).enumerate() {
+            let page = first_page + i as u64;
+            // SAFETY: The frames belonged to the huge page that was just unmapped
+            let result = unsafe {
+                self.mapper()
+                    .map_to(page, part, flags, &mut table_allocator())
+            };
+            match result {
+                // Nothing in the range can be cached anymore after the flush above
+                Ok(flush) => flush.ignore(),
+                Err(e) => {
+                    // Only allocating the level 1 table can fail, before anything was mapped
+                    debug_assert_eq!(i, 0);
+                    // SAFETY: Puts back the mapping that was removed above
+                    let flush = unsafe {
+                        self.mapper()
+                            .map_to(huge_page, frame, flags, &mut table_allocator())
+                    }
+                    .expect("restoring huge page");
+                    self.flush(flush);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Backs the whole huge page around `addr` with a zeroed 2MiB frame, if it lies entirely
+    /// inside `vma` and none of it is mapped yet.
+    ///
+    /// Returns false if the fault should be resolved with a 4KiB page instead
+    fn map_huge(&mut self, addr: VirtAddr, vma: &Vma) -> bool {
+        let page = Page::<Size2MiB>::containing_address(addr);
+        if page.start_address() < vma.start || vma.end < page.start_address() + page.size() {
+            return false;
+        }
+        let Some(frame) = allocate_huge_frame(self.owner) else {
+            return false;
+        };
+
+        // SAFETY: The frame is unused, and the page is a user page so the kernel does not rely on
+        // it
+        let result = unsafe {
+            self.mapper()
+                .map_to(page, frame, vma.flags, &mut table_allocator())
+        };
+        match result {
+            Ok(flush) => {
+                // Zeroed only now, as clearing 2MiB is wasted if the mapping fails. Nothing runs
+                // in this address space until the fault handler returns
+                // SAFETY: The frame was just allocated, so nothing else refers to it
+                unsafe {
+                    ptr::write_bytes(
+                        phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(),
+                        0,
+                        Size2MiB::SIZE as usize,
+                    )
+                };
+                self.flush(flush);
+                true
+            }
+            Err(_) => {
+                // Part of the range is already mapped with 4KiB pages
+                let first = PhysFrame::containing_address(frame.start_address());
+                // SAFETY: The frame was never mapped
+                unsafe { deallocate_contiguous(first, MAX_ORDER) };
+                false
+            }
+        }
+    }
+
+    /// Creates a copy of this address space for a forked process.
+    ///
+    /// No user memory is copied. Every mapped frame is shared between both address spaces, and
+    /// writable pages are made read-only and marked [`COPY_ON_WRITE`] in both of them, so that
+    /// the first write from either side gets its own copy in [`Self::handle_fault`]. [`SHARED`]
+    /// pages stay writable in both
+    pub fn fork(&mut self) -> Result<AddressSpace, MapToError<Size4KiB>> {
+        let no_memory = |_| MapToError::FrameAllocationFailed;
+        let mut child = AddressSpace::new()?;
+        child
+            .vmas
+            .try_reserve_exact(self.vmas.len())
+            .map_err(no_memory)?;
+        for vma in &self.vmas {
+            let vma = VMA_CACHE
+                .alloc(Vma::clone(vma))
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            child.vmas.push(vma);
+        }
+
+        // Everything that can fail before the parent's pages are made copy-on-write
+        let (start, end) = (VirtAddr::new(USER_START), VirtAddr::new(USER_END));
+        let mut mappings = Vec::new();
+        mappings
+            .try_reserve_exact(self.leaf_count(start, end))
+            .map_err(no_memory)?;
+        // SAFETY: The closure only changes flags, and has room for every leaf entry
+        unsafe {
+            self.for_each_leaf(start, end, |addr, entry| {
+                let mut flags = entry.flags();
+                if flags.contains(PageTableFlags::WRITABLE) && !flags.contains(SHARED) {
+                    flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                    entry.set_flags(flags);
+                }
+                for frame in leaf_frames(entry.addr(), flags) {
+                    share_frame(frame);
+                }
+                mappings.push((addr, entry.addr(), flags));
+            })
+        };
+        if self.is_active() {
+            // Pages that were writable before may still be cached as writable
+            tlb::flush_all();
+        }
+
+        for (i, &(addr, frame, flags)) in mappings.iter().enumerate() {
+            // SAFETY: The frames' refcounts were raised above, so the child owns a reference to
+            // them
+            if let Err(e) = unsafe { child.map_leaf(addr, frame, flags) } {
+                for &(_, frame, flags) in &mappings[i..] {
+                    for frame in leaf_frames(frame, flags) {
+                        // SAFETY: These references were never mapped into the child
+                        unsafe { release_frame(frame, child.owner) };
+                    }
+                }
+                return Err(e);
+            }
+        }
+        Ok(child)
+    }
+
+    /// The number of 4KiB pages mapped in the user half, counting each huge page as 512 pages
+    pub fn resident_pages(&mut self) -> usize {
+        let mut pages = 0;
+        let (start, end) = (VirtAddr::new(USER_START), VirtAddr::new(USER_END));
+        // SAFETY: The closure doesn't change the entries
+        unsafe {
+            self.for_each_leaf(start, end, |_, entry| {
+                pages += leaf_frames(entry.addr(), entry.flags()).count();
+            })
+        };
+        pages
+    }
+
+    /// Calls `f` with every mapped leaf entry that overlaps `start..end`, and the address it maps.
+    /// Huge pages are passed as their level 2 entry
+    ///
+    /// # Safety
+    /// `f` may only change the flags of the entries
+    unsafe fn for_each_leaf(
+        &mut self,
+        start: VirtAddr,
+        end: VirtAddr,
+        mut f: impl FnMut(VirtAddr, &mut PageTableEntry),
+    ) {
+        let level_4 = self.mapper().level_4_table() as *mut PageTable;
+        for slot in USER_SLOTS {
+            let slot_start = VirtAddr::new((slot as u64) << 39);
+            if slot_start >= end || slot_start + (1u64 << 39) <= start {
+                continue;
+            }
+            // SAFETY: We own every table in the user half, and the caller guarantees that only
+            // flags change
+            unsafe {
+                for_each_page(&mut (*level_4)[slot], 3, slot_start, &mut |addr, entry| {
+                    let size = leaf_frames(entry.addr(), entry.flags()).count() as u64 * 4096;
+                    if addr < end && start < addr + size {
+                        f(addr, entry);
+                    }
+                })
+            };
+        }
+    }
+
+    /// The number of leaf entries that [`Self::for_each_leaf`] visits for `start..end`
+    fn leaf_count(&mut self, start: VirtAddr, end: VirtAddr) -> usize {
+        let mut count = 0;
+        // SAFETY: The closure doesn't change the entries
+        unsafe { self.for_each_leaf(start, end, |_, _| count += 1) };
+        count
+    }
+
+    /// Maps `addr` to `frame` with a 2MiB page if `flags` contains `HUGE_PAGE`, and a 4KiB page
+    /// otherwise
+    ///
+    /// # Safety
+    /// Same as [`Self::map_to`]
+    unsafe fn map_leaf(
+        &mut self,
+        addr: VirtAddr,
+        frame: PhysAddr,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        if !flags.contains(PageTableFlags::HUGE_PAGE) {
+            let page = Page::containing_address(addr);
+            // SAFETY: Guaranteed by the caller
+            return unsafe { self.map_to(page, PhysFrame::containing_address(frame), flags) };
+        }
+
+        let page = Page::<Size2MiB>::containing_address(addr);
+        assert!(is_user_page(Page::containing_address(addr)));
+        // SAFETY: Guaranteed by the caller
+        let flush = unsafe {
+            self.mapper().map_to(
+                page,
+                PhysFrame::containing_address(frame),
+                flags,
+                &mut table_allocator(),
+            )
+        }
+        .map_err(|e| match e {
+            MapToError::FrameAllocationFailed => MapToError::FrameAllocationFailed,
+            MapToError::ParentEntryHugePage => MapToError::ParentEntryHugePage,
+            MapToError::PageAlreadyMapped(frame) => {
+                MapToError::PageAlreadyMapped(PhysFrame::containing_address(frame.start_address()))
+            }
+        })?;
+        self.flush(flush);
+        Ok(())
+    }
+
+    /// Registers a new VMA. Its pages will be backed lazily by [`Self::handle_fault`]
+    pub fn add_vma(&mut self, vma: Vma) -> Result<(), VmaError> {
+        check_range(vma.start, vma.end)?;
+        check_vma_flags(vma.flags)?;
+        if self.vmas.iter().any(|other| other.overlaps(&vma)) {
+            return Err(VmaError::Overlaps);
+        }
+        self.reserve_vmas(1)?;
+        self.insert_vma(new_vma(vma)?);
+        Ok(())
+    }
+
+    /// Inserts `vma` in address order. Room for it must have been made with [`Self::reserve_vmas`]
+    fn insert_vma(&mut self, vma: SlabBox<Vma>) {
+        debug_assert!(self.vmas.len() < self.vmas.capacity(), "no room for a VMA");
+        let index = self.vmas.partition_point(|other| other.start < vma.start);
+        self.vmas.insert(index, vma);
+    }
+
+    /// Registers a new VMA in place of everything in its range, which is unmapped first like
+    /// `mmap` with `MAP_FIXED` does.
+    ///
+    /// The VMA is checked before anything is unmapped, so the old mappings are left alone if it
+    /// can't be added
+    pub fn replace_vma(&mut self, vma: Vma) -> Result<(), VmaError> {
+        check_range(vma.start, vma.end)?;
+        check_vma_flags(vma.flags)?;
+        let vma = new_vma(vma)?;
+        // Room for splitting the VMAs at both ends, and for the new one
+        self.reserve_vmas(3)?;
+        self.unmap_range(vma.start, vma.end)?;
+        self.insert_vma(vma);
+        Ok(())
+    }
+
+    /// Returns the VMA containing `addr`, if any
+    pub fn vma(&self, addr: VirtAddr) -> Option<&Vma> {
+        self.vma_index(addr).map(|index| &*self.vmas[index])
+    }
+
+    fn vma_index(&self, addr: VirtAddr) -> Option<usize> {
+        let after = self.vmas.partition_point(|vma| vma.start <= addr);
+        after
+            .checked_sub(1)
+            .filter(|&index| self.vmas[index].contains(addr))
+    }
+
+    /// Every VMA in this address space, in address order
+    pub fn vmas(&self) -> impl Iterator<Item = &Vma> {
+        self.vmas.iter().map(|vma| &**vma)
+    }
+
+    /// The indices of the VMAs that start inside `start..end`
+    fn vmas_starting_in(&self, start: VirtAddr, end: VirtAddr) -> core::ops::Range<usize> {
+        let first = self.vmas.partition_point(|vma| vma.start < start);
+        let last = self.vmas.partition_point(|vma| vma.start < end);
+        first..last
+    }
+
+    /// Makes room for `additional` more VMAs, so that adding or splitting them can't fail later
+    fn reserve_vmas(&mut self, additional: usize) -> Result<(), VmaError> {
+        self.vmas
+            .try_reserve(additional)
+            .map_err(|_| VmaError::OutOfMemory)
+    }
+
+    /// Returns the lowest page aligned address at or above `start` where `len` bytes fit in user
+    /// memory without overlapping a VMA
+    pub fn find_free_range(&self, start: VirtAddr, len: u64) -> Option<VirtAddr> {
+        let mut candidate = start.align_up(Size4KiB::SIZE).as_u64().max(USER_START);
+        for vma in &self.vmas {
+            if vma.end.as_u64() <= candidate {
+                continue;
+            }
+            if candidate.checked_add(len)? <= vma.start.as_u64() {
+                break;
+            }
+            candidate = vma.end.as_u64();
+        }
+        let end = candidate.checked_add(len)?;
+        (end <= USER_END).then(|| VirtAddr::new(candidate))
+    }
+
+    /// Splits the VMA containing `addr`, if any, so that one VMA ends and the next starts at
+    /// `addr`. The two halves describe the same memory as the whole did, so splitting early never
+    /// changes what a failed operation leaves behind
+    fn split_vma(&mut self, addr: VirtAddr) -> Result<(), VmaError> {
+        let Some(index) = self.vma_index(addr) else {
+            return Ok(());
+        };
+        if self.vmas[index].start == addr {
+            return Ok(());
+        }
+        self.reserve_vmas(1)?;
+        let upper = new_vma(Vma {
+            start: addr,
+            ..Vma::clone(&self.vmas[index])
+        })?;
+        self.vmas[index].end = addr;
+        self.vmas.insert(index + 1, upper);
+        Ok(())
+    }
+
+    /// Maps `frames` one after another starting at `start` with `flags`, and adds a
+    /// [`VmaKind::Shared`] VMA for them.
+    ///
+    /// Each page takes a reference to its frame, which is dropped when the page is unmapped. The
+    /// pages are marked [`SHARED`], so writes go to the frames directly instead of being copied
+    /// on write, even in forked address spaces
+    pub fn map_shared(
+        &mut self,
+        start: VirtAddr,
+        frames: &[PhysFrame],
+        flags: PageTableFlags,
+    ) -> Result<(), VmaError> {
+        let end = (frames.len() as u64)
+            .checked_mul(Size4KiB::SIZE)
+            .and_then(|len| start.as_u64().checked_add(len))
+            .and_then(|end| VirtAddr::try_new(end).ok())
+            .ok_or(VmaError::InvalidRange)?;
+        self.add_vma(Vma::new(start, end, flags, VmaKind::Shared))?;
+
+        let first_page = Page::containing_address(start);
+        for (i, &frame) in frames.iter().enumerate() {
+            share_frame(frame);
+            // SAFETY: The mapping owns the reference that was just taken
+            let result = unsafe { self.map_to(first_page + i as u64, frame, flags | SHARED) };
+            if result.is_err() {
+                // SAFETY: The reference was never mapped
+                unsafe { release_frame(frame, self.owner) };
+                for page in Page::range(first_page, first_page + i as u64) {
+                    self.unmap(page).expect("shared page is mapped");
+                }
+                let index = self.vma_index(start).expect("shared VMA exists");
+                self.vmas.remove(index);
+                return Err(VmaError::OutOfMemory);
+            }
+        }
+        Ok(())
+    }
+
+    /// Every mapped leaf entry that overlaps `start..end`, as the address it maps, its frame and
+    /// its flags. Fails if there is no memory for the list
+    fn mappings_in(
+        &mut self,
+        start: VirtAddr,
+        end: VirtAddr,
+    ) -> Result<Vec<(VirtAddr, PhysAddr, PageTableFlags)>, VmaError> {
+        let mut mappings = Vec::new();
+        mappings
+            .try_reserve_exact(self.leaf_count(start, end))
+            .map_err(|_| VmaError::OutOfMemory)?;
+        // SAFETY: The closure doesn't change the entries, and has room for all of them
+        unsafe {
+            self.for_each_leaf(start, end, |addr, entry| {
+                mappings.push((addr, entry.addr(), entry.flags()))
+            })
+        };
+        Ok(mappings)
+    }
+
+    /// Unmaps every page in `start..end` and removes the range from the VMAs, shrinking or
+    /// splitting the VMAs that are only partly inside of it.
+    ///
+    /// Parts of the range that aren't mapped are skipped. Everything that needs memory is done
+    /// before anything is unmapped, so if that fails the range is left as it was
+    pub fn unmap_range(&mut self, start: VirtAddr, end: VirtAddr) -> Result<(), VmaError> {
+        check_range(start, end)?;
+        self.split_vma(start)?;
+        self.split_vma(end)?;
+        self.unmap_pages(start, end)?;
+        let removed = self.vmas_starting_in(start, end);
+        self.vmas.drain(removed);
+        Ok(())
+    }
+
+    /// Moves the end of the VMA that ends at `end` to `new_end`, in place. Pages that it loses are
+    /// unmapped, and it may only grow into addresses that no other VMA covers.
+    ///
+    /// `new_end` has to stay above the start of the VMA, see [`Self::unmap_range`] for removing
+    /// more than that
+    pub fn resize_vma(&mut self, end: VirtAddr, new_end: VirtAddr) -> Result<(), VmaError> {
+        let index = self.vmas.partition_point(|vma| vma.end < end);
+        if self.vmas.get(index).map_or(true, |vma| vma.end != end) {
+            return Err(VmaError::NotMapped);
+        }
+        check_range(self.vmas[index].start, new_end)?;
+        match new_end.cmp(&end) {
+            cmp::Ordering::Greater => {
+                let next = self.vmas.get(index + 1);
+                if next.map_or(false, |next| next.start < new_end) {
+                    return Err(VmaError::Overlaps);
+                }
+            }
+            cmp::Ordering::Less => self.unmap_pages(new_end, end)?,
+            cmp::Ordering::Equal => {}
+        }
+        self.vmas[index].end = new_end;
+        Ok(())
+    }
+
+    /// Unmaps every page in `start..end`, without changing the VMAs. Everything that needs memory
+    /// is done before anything is unmapped, so if that fails the range is left as it was
+    fn unmap_pages(&mut self, start: VirtAddr, end: VirtAddr) -> Result<(), VmaError> {
+        self.split_huge_edges(start, end)?;
+        for (addr, _, flags) in self.mappings_in(start, end)? {
+            if flags.contains(PageTableFlags::HUGE_PAGE) {
+                let huge_page = Page::<Size2MiB>::containing_address(addr);
+                if start <= addr && addr + huge_page.size() <= end {
+                    let (frame, flush) =
+                        self.mapper().unmap(huge_page).expect("huge page is mapped");
+                    self.flush(flush);
+                    for part in huge_frame_parts(frame) {
+                        // SAFETY: The frames were owned by this address space and are no longer
+                        // mapped
+                        unsafe { release_frame(part, self.owner) };
+                    }
+                    continue;
+                }
+            }
+            for page in overlapping_pages(addr, flags, start, end) {
+                self.unmap(page).expect("unmapping 4KiB pages can't fail");
+            }
+        }
+        Ok(())
+    }
+
+    /// Splits the huge pages that are only partly inside `start..end`
+    fn split_huge_edges(&mut self, start: VirtAddr, end: VirtAddr) -> Result<(), VmaError> {
+        for edge in [start, end] {
+            if !edge.is_aligned(Size2MiB::SIZE) {
+                self.split_huge_page(edge)
+                    .map_err(|_| VmaError::OutOfMemory)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Changes the flags of every VMA and mapped page in `start..end`, splitting the VMAs that are
+    /// only partly inside of it. The whole range must be covered by VMAs.
+    ///
+    /// Pages whose frame is shared with another address space stay read-only and become
+    /// [`COPY_ON_WRITE`] if `flags` is writable, unless they are [`SHARED`]. Everything that
+    /// needs memory is done before any flags change, so if that fails the range is left as it was
+    pub fn protect(
+        &mut self,
+        start: VirtAddr,
+        end: VirtAddr,
+        flags: PageTableFlags,
+    ) -> Result<(), VmaError> {
+        check_range(start, end)?;
+        check_vma_flags(flags)?;
+        let mut covered = start;
+        for vma in self.vmas.iter().take_while(|vma| vma.start < end) {
+            if vma.end <= covered {
+                continue;
+            }
+            if vma.start > covered {
+                break;
+            }
+            covered = vma.end;
+        }
+        if covered < end {
+            return Err(VmaError::NotMapped);
+        }
+
+        self.split_vma(start)?;
+        self.split_vma(end)?;
+        self.split_huge_edges(start, end)?;
+        for (addr, frame, page_flags) in self.mappings_in(start, end)? {
+            let mut new_flags = flags;
+            if page_flags.contains(SHARED) {
+                new_flags |= SHARED;
+            } else if page_flags.contains(COPY_ON_WRITE)
+                || frame_refcount(PhysFrame::containing_address(frame)) > 1
+            {
+                new_flags = (new_flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+            }
+            if page_flags.contains(PageTableFlags::HUGE_PAGE) {
+                let huge_page = Page::<Size2MiB>::containing_address(addr);
+                if start <= addr && addr + huge_page.size() <= end {
+                    let new_flags = new_flags | PageTableFlags::HUGE_PAGE;
+                    // SAFETY: Only the flags of a user page change
+                    let flush = unsafe { self.mapper().update_flags(huge_page, new_flags) }
+                        .expect("huge page is mapped");
+                    self.flush(flush);
+                    continue;
+                }
+            }
+            for page in overlapping_pages(addr, page_flags, start, end) {
+                self.update_flags(page, new_flags)
+                    .expect("updating 4KiB pages can't fail");
+            }
+        }
+
+        let changed = self.vmas_starting_in(start, end);
+        for vma in &mut self.vmas[changed] {
+            vma.flags = flags;
+        }
+        Ok(())
+    }
+
+    /// Resolves a page fault at `addr` if the VMA it lies in allows the access described by
+    /// `code`.
+    ///
+    /// Unmapped pages are backed with a zeroed frame, and writes to [`COPY_ON_WRITE`] pages get a
+    /// private copy of the shared frame
+    pub fn handle_fault(
+        &mut self,
+        addr: VirtAddr,
+        code: PageFaultErrorCode,
+    ) -> Result<(), FaultError> {
+        let vma = self.vma(addr).ok_or(FaultError::NoVma)?.clone();
+        let flags = vma.flags;
+        let page = Page::containing_address(addr);
+
+        let write = code.contains(PageFaultErrorCode::CAUSED_BY_WRITE);
+        let fetch = code.contains(PageFaultErrorCode::INSTRUCTION_FETCH);
+        if (write && !flags.contains(PageTableFlags::WRITABLE))
+            || (fetch && flags.contains(PageTableFlags::NO_EXECUTE))
+        {
+            return Err(FaultError::AccessViolation);
+        }
+        match self.translate(addr) {
+            // Shared VMAs are fully mapped when they are created, so a missing page must not be
+            // backed with private memory
+            TranslateResult::NotMapped if vma.kind == VmaKind::Shared => {
+                Err(FaultError::AccessViolation)
+            }
+            TranslateResult::NotMapped => {
+                // Large anonymous regions are backed with huge pages where possible
+                if vma.kind == VmaKind::Anonymous && self.map_huge(addr, &vma) {
+                    return Ok(());
+                }
+                self.map(page, flags).map_err(|_| FaultError::OutOfMemory)?;
+                Ok(())
+            }
+            TranslateResult::Mapped {
+                flags: page_flags, ..
+            } if write && page_flags.contains(COPY_ON_WRITE) => {
+                // Only copy the 4KiB page that was written to
+                self.split_huge_page(addr)
+                    .map_err(|_| FaultError::OutOfMemory)?;
+                let TranslateResult::Mapped {
+                    frame: MappedFrame::Size4KiB(frame),
+                    flags: page_flags,
+                    ..
+                } = self.translate(addr) else {
+                    unreachable!("split page is mapped");
+                };
+                self.copy_on_write(page, frame, page_flags)
+            }
+            // The page is already backed, so the fault came from the page's own permissions
+            _ => Err(FaultError::AccessViolation),
+        }
+    }
+
+    /// Gives this address space a private, writable copy of the copy-on-write `page`
+    fn copy_on_write(
+        &mut self,
+        page: Page,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+    ) -> Result<(), FaultError> {
+        let flags = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+        if frame_refcount(frame) == 1 {
+            // Every other mapping of the frame is gone, so it can be written in place
+            // SAFETY: Only the flags of a user page change
+            let flush = unsafe { self.mapper().update_flags(page, flags) }
+                .expect("copy-on-write page is mapped");
+            self.flush(flush);
+            return Ok(());
+        }
+
+        let copy = frame_allocator_for(self.owner)
+            .allocate_frame()
+            .ok_or(FaultError::OutOfMemory)?;
+        // SAFETY: `copy` was just allocated, and `frame` is mapped read-only everywhere so nobody
+        // can be writing to it
+        unsafe {
+            ptr::copy_nonoverlapping(
+                phys_to_virt(frame.start_address()).as_ptr::<u8>(),
+                phys_to_virt(copy.start_address()).as_mut_ptr::<u8>(),
+                4096,
+            )
+        };
+
+        let (_, flush) = self
+            .mapper()
+            .unmap(page)
+            .expect("copy-on-write page is mapped");
+        // The new mapping below flushes the same page
+        flush.ignore();
+        // SAFETY: The frame is shared, so this only drops our reference
+        unsafe { release_frame(frame, self.owner) };
+        // SAFETY: `copy` is only used by this mapping. The page tables already exist, so this
+        // can't fail
+        unsafe { self.map_to(page, copy, flags) }.expect("remapping copy-on-write page");
+        Ok(())
+    }
+
+    /// Translates `addr` using this address space's page tables
+    pub fn translate(&mut self, addr: VirtAddr) -> TranslateResult {
+        self.mapper().translate(addr)
+    }
+
+    /// Copies `data` into this address space starting at `addr`, going through the physical
+    /// memory mapping so that the address space doesn't need to be active.
+    ///
+    /// Panics if any of the destination pages are not mapped
+    pub fn write(&mut self, addr: VirtAddr, data: &[u8]) {
+        let mut written = 0;
+        while written < data.len() {
+            let dst = addr + written;
+            let TranslateResult::Mapped { frame, offset, .. } = self.translate(dst) else {
+                panic!("writing to unmapped user address {:?}", dst);
+            };
+            let page_remaining = (frame.size() - offset) as usize;
+            let len = page_remaining.min(data.len() - written);
+            let dst = phys_to_virt(frame.start_address() + offset);
+            // SAFETY: The frame is mapped in this address space, so it is owned by it and we are
+            // allowed to write to it
+            unsafe {
+                ptr::copy_nonoverlapping(data[written..].as_ptr(), dst.as_mut_ptr(), len);
+            }
+            written += len;
+        }
+    }
+
+    /// Flushes a changed page from the TLB if this address space is loaded in CR3
+    fn flush<S: PageSize>(&self, flush: MapperFlush<S>) {
+        if self.is_active() {
+            flush.flush();
+        } else {
+            flush.ignore();
+        }
+    }
+
+    /// Returns true if this address space is active on the calling CPU, as recorded in its
+    /// per-CPU area
+    pub fn is_active(&self) -> bool {
+        percpu!(address_space).get() == Some(self.level_4_frame)
+    }
+
+    /// Switches to this address space by loading its level 4 table into CR3, and records it as
+    /// the calling CPU's active address space
+    ///
+    /// # Safety
+    /// The caller must ensure that the address space stays alive for as long as it is active
+    pub unsafe fn activate(&self) {
+        crate::sys::without_interrupts(|| {
+            let (_, flags) = Cr3::read();
+            // SAFETY: The kernel half is shared with the boot page table, so the kernel keeps
+            // running normally after the switch
+            unsafe { Cr3::write(self.level_4_frame, flags) };
+            percpu!(address_space).set(Some(self.level_4_frame));
+        })
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if self.is_active() {
+            crate::sys::without_interrupts(|| {
+                let (_, flags) = Cr3::read();
+                // SAFETY: The kernel page table is always valid
+                unsafe { Cr3::write(kernel_level_4_frame(), flags) };
+                percpu!(address_space).set(None);
+            })
+        }
+
+        let mut allocator = frame_allocator();
+        let level_4 = self.mapper().level_4_table() as *mut PageTable;
+        // SAFETY: We own every table in the user half, and the address space is no longer active
+        // so nothing can be using these frames
+        unsafe {
+            for slot in USER_SLOTS {
+                free_table(&mut (*level_4)[slot], 3, self.owner, &mut allocator);
+            }
+            allocator.deallocate_frame(self.level_4_frame);
+        }
+    }
+}
+
+/// Frees the table that `entry` points to, along with every frame and table below it. User
+/// frames are released on behalf of `owner`.
+///
+/// # Safety
+/// Every frame reachable from `entry` must be owned by the caller and no longer in use
+unsafe fn free_table(
+    entry: &mut PageTableEntry,
+    level: usize,
+    owner: FrameOwner,
+    allocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    if entry.is_unused() {
+        return;
+    }
+    if level > 0 && !entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+        let frame = PhysFrame::containing_address(entry.addr());
+        // SAFETY: The entry is present and points to the next level table
+        let table = unsafe { &mut *phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>() };
+        for entry in table.iter_mut() {
+            // SAFETY: Passed on from our caller
+            unsafe { free_table(entry, level - 1, owner, allocator) };
+        }
+        // SAFETY: Guaranteed by the caller
+        unsafe { allocator.deallocate_frame(frame) };
+    } else {
+        for frame in leaf_frames(entry.addr(), entry.flags()) {
+            // SAFETY: Guaranteed by the caller. The frame may still be mapped by a forked process
+            unsafe { release_frame(frame, owner) };
+        }
+    }
+    entry.set_unused();
+}
+
+/// Where the VMAs of every address space are allocated from, as they come and go with every
+/// `mmap`, `munmap` and fork
+static VMA_CACHE: SlabCache<Vma> = SlabCache::new("vma");
+
+/// Moves `vma` into an object of [`VMA_CACHE`]
+fn new_vma(vma: Vma) -> Result<SlabBox<Vma>, VmaError> {
+    VMA_CACHE.alloc(vma).ok_or(VmaError::OutOfMemory)
+}
+
+/// Checks that `start..end` is a non-empty, page aligned range of user addresses
+fn check_range(start: VirtAddr, end: VirtAddr) -> Result<(), VmaError> {
+    let aligned = |addr: VirtAddr| addr.is_aligned(Size4KiB::SIZE);
+    if start >= end
+        || !aligned(start)
+        || !aligned(end)
+        || start.as_u64() < USER_START
+        || end.as_u64() > USER_END
+    {
+        return Err(VmaError::InvalidRange);
+    }
+    Ok(())
+}
+
+/// The 4KiB pages of the leaf entry mapping `addr` with `flags` that are inside `start..end`
+fn overlapping_pages(
+    addr: VirtAddr,
+    flags: PageTableFlags,
+    start: VirtAddr,
+    end: VirtAddr,
+) -> PageRange {
+    let size = if flags.contains(PageTableFlags::HUGE_PAGE) {
+        Size2MiB::SIZE
+    } else {
+        Size4KiB::SIZE
+    };
+    Page::range(
+        Page::containing_address(addr.max(start)),
+        Page::containing_address((addr + size).min(end)),
+    )
+}
+
+/// The 4KiB frames mapped by a leaf entry that points at `addr` with `flags`
+fn leaf_frames(addr: PhysAddr, flags: PageTableFlags) -> PhysFrameRange {
+    if flags.contains(PageTableFlags::HUGE_PAGE) {
+        huge_frame_parts(PhysFrame::containing_address(addr))
+    } else {
+        let frame = PhysFrame::containing_address(addr);
+        PhysFrame::range(frame, frame + 1)
+    }
+}
+
+/// Calls `f` with the address of every mapped page below `entry`, along with the entry that maps
+/// it. Huge pages are passed to `f` as their entry in the level 2 table.
+///
+/// `start` is the first address covered by `entry`, which is a level `level` entry
+///
+/// # Safety
+/// Every table reachable from `entry` must be owned by the caller
+unsafe fn for_each_page(
+    entry: &mut PageTableEntry,
+    level: usize,
+    start: VirtAddr,
+    f: &mut impl FnMut(VirtAddr, &mut PageTableEntry),
+) {
+    if entry.is_unused() {
+        return;
+    }
+    if level == 0 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+        f(start, entry);
+        return;
+    }
+    // SAFETY: The entry is present and points to the next level table
+    let table = unsafe { &mut *phys_to_virt(entry.addr()).as_mut_ptr::<PageTable>() };
+    for (i, entry) in table.iter_mut().enumerate() {
+        let start = start + ((i as u64) << (12 + 9 * (level - 1)));
+        // SAFETY: Passed on from our caller
+        unsafe { for_each_page(entry, level - 1, start, f) };
+    }
+}
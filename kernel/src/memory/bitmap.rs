@@ -0,0 +1,188 @@
+use core::slice;
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::{
+    structures::paging::{
+        frame::PhysFrameRange, FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+const FRAME_SIZE: u64 = 4096;
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+/// A physical frame allocator that tracks every frame with a single bit.
+///
+/// The bitmap itself lives inside the first usable region that is large enough to hold it, and
+/// is accessed through the physical memory mapping setup by the bootloader.
+/// A set bit means the frame is in use (or was never usable to begin with).
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    /// Index of the first word in `bitmap` that may contain a free frame.
+    /// Every word before `next` is known to be full
+    next: usize,
+    /// The number of frames marked as usable by the bootloader
+    usable_frames: usize,
+    free_frames: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// Create a frame allocator from the passed memory map.
+    ///
+    /// # Safety
+    /// 1. The caller must guarantee that the passed memory map is valid.
+    ///    The main requirement is that all frames that are marked as `USABLE` in it are really
+    ///    unused
+    /// 2. The complete physical memory must be mapped at `physical_memory_offset`
+    /// 3. Only one allocator may be created from the same memory map
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable_regions = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+        };
+
+        // We only need to track frames up to the highest usable one
+        let frame_count = usable_regions()
+            .map(|r| r.range.end_frame_number)
+            .max()
+            .unwrap_or(0) as usize;
+        let words = (frame_count + BITS_PER_WORD - 1) / BITS_PER_WORD;
+        let bitmap_frames = ((words * 8) as u64 + FRAME_SIZE - 1) / FRAME_SIZE;
+
+        // Steal the first frames of a usable region to store the bitmap itself
+        let bitmap_region = usable_regions()
+            .find(|r| r.range.end_frame_number - r.range.start_frame_number >= bitmap_frames)
+            .expect("no usable region is large enough to hold the frame bitmap");
+        let bitmap_start = bitmap_region.range.start_frame_number;
+
+        let bitmap_addr = physical_memory_offset + bitmap_start * FRAME_SIZE;
+        // SAFETY:
+        // 1. The region is usable so nothing else is using it, and the caller guarantees we are
+        //    the only allocator handing out frames from this memory map
+        // 2. The caller guarantees that physical memory is mapped at `physical_memory_offset`
+        let bitmap = unsafe { slice::from_raw_parts_mut(bitmap_addr.as_mut_ptr(), words) };
+
+        // Mark everything as used, then free the frames that the bootloader told us are usable
+        bitmap.fill(u64::MAX);
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            next: 0,
+            usable_frames: 0,
+            free_frames: 0,
+        };
+        for region in usable_regions() {
+            let start = region.range.start_frame_number as usize;
+            let end = region.range.end_frame_number as usize;
+            for index in start..end {
+                allocator.clear(index);
+            }
+            allocator.usable_frames += end - start;
+            allocator.free_frames += end - start;
+        }
+        for index in bitmap_start..bitmap_start + bitmap_frames {
+            allocator.set(index as usize);
+            allocator.free_frames -= 1;
+        }
+
+        allocator
+    }
+
+    /// The number of frames that are currently free
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// The number of usable frames that are currently allocated (including the frames used to
+    /// store the bitmap)
+    pub fn used_frames(&self) -> usize {
+        self.usable_frames - self.free_frames
+    }
+
+    /// The number of frames that the bootloader reported as usable
+    pub fn usable_frames(&self) -> usize {
+        self.usable_frames
+    }
+
+    /// Returns true if `frame` is tracked by this allocator and is currently allocated
+    pub fn is_used(&self, frame: PhysFrame) -> bool {
+        let index = frame_index(frame);
+        index < self.bitmap.len() * BITS_PER_WORD && self.get(index)
+    }
+
+    /// Allocates `count` physically contiguous frames, with the first frame aligned to `align`
+    /// frames.
+    ///
+    /// This does a linear scan of the bitmap, so it is meant for carving out large regions during
+    /// boot rather than for general use.
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<PhysFrameRange> {
+        let total = self.bitmap.len() * BITS_PER_WORD;
+        let mut start = 0;
+        while start + count <= total {
+            match (start..start + count).rev().find(|&index| self.get(index)) {
+                // Every run starting at or before `used` contains it, so skip past it
+                Some(used) => start = (used + 1 + align - 1) / align * align,
+                None => {
+                    for index in start..start + count {
+                        self.set(index);
+                    }
+                    self.free_frames -= count;
+                    let frame = |index: usize| {
+                        PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE))
+                    };
+                    return Some(PhysFrame::range(frame(start), frame(start + count)));
+                }
+            }
+        }
+        None
+    }
+
+    fn get(&self, index: usize) -> bool {
+        self.bitmap[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+
+    fn set(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+    }
+
+    fn clear(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_WORD] &= !(1 << (index % BITS_PER_WORD));
+    }
+}
+
+fn frame_index(frame: PhysFrame) -> usize {
+    (frame.start_address().as_u64() / FRAME_SIZE) as usize
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if self.free_frames == 0 {
+            return None;
+        }
+        // Every word before `next` is full, and there is at least one free frame, so this loop
+        // will find one. Each word we skip here stays skipped until something is freed into it
+        for word_index in self.next..self.bitmap.len() {
+            let word = self.bitmap[word_index];
+            if word != u64::MAX {
+                let index = word_index * BITS_PER_WORD + (!word).trailing_zeros() as usize;
+                self.set(index);
+                self.next = word_index;
+                self.free_frames -= 1;
+                return Some(PhysFrame::containing_address(PhysAddr::new(
+                    index as u64 * FRAME_SIZE,
+                )));
+            }
+        }
+        unreachable!("bitmap frame allocator has free frames but none were found");
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let index = frame_index(frame);
+        assert!(self.is_used(frame), "double free of frame {:?}", frame);
+        self.clear(index);
+        self.free_frames += 1;
+        self.next = self.next.min(index / BITS_PER_WORD);
+    }
+}
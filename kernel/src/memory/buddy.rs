@@ -0,0 +1,231 @@
+use core::ptr;
+
+use x86_64::{
+    structures::paging::{frame::PhysFrameRange, PhysFrame},
+    PhysAddr, VirtAddr,
+};
+
+/// The largest block handed out by the buddy allocator is `2^MAX_ORDER` frames (2MiB)
+pub const MAX_ORDER: usize = 9;
+
+const FRAME_SIZE: u64 = 4096;
+
+/// Marker in `block_orders` for frames that are not the first frame of a free block
+const NOT_FREE: u8 = u8::MAX;
+
+/// Header written to the start of every free block, forming a doubly linked list per order
+#[repr(C)]
+struct FreeBlock {
+    prev: Option<PhysFrame>,
+    next: Option<PhysFrame>,
+}
+
+/// A buddy system allocator that hands out naturally aligned, physically contiguous runs of
+/// `2^order` frames for orders `0..=MAX_ORDER`.
+///
+/// Free blocks are kept on intrusive free lists stored inside the free memory itself (accessed
+/// through the physical memory mapping), so the only extra memory needed is one byte per frame
+/// to remember which frames start a free block and of what order.
+pub struct BuddyAllocator {
+    frames: PhysFrameRange,
+    /// Indexed by frame number relative to `frames.start`.
+    /// Holds the order of the free block starting at that frame, or [`NOT_FREE`]
+    block_orders: &'static mut [u8],
+    free_lists: [Option<PhysFrame>; MAX_ORDER + 1],
+    free_blocks: [usize; MAX_ORDER + 1],
+    free_frames: usize,
+    physical_memory_offset: VirtAddr,
+}
+
+impl BuddyAllocator {
+    /// Creates a buddy allocator that manages every frame inside `frames`.
+    ///
+    /// Blocks are aligned to their size in physical memory, so only the parts of `frames` that
+    /// are aligned to `2^MAX_ORDER` frames can form the largest blocks.
+    ///
+    /// # Safety
+    /// 1. Every frame in `frames` must be unused, and must not be handed out by any other allocator
+    /// 2. The complete physical memory must be mapped at `physical_memory_offset`
+    /// 3. `block_orders` must have at least one entry per frame in `frames`
+    pub unsafe fn new(
+        frames: PhysFrameRange,
+        block_orders: &'static mut [u8],
+        physical_memory_offset: VirtAddr,
+    ) -> Self {
+        let len = frame_count(frames);
+        assert!(block_orders.len() >= len, "buddy metadata is too small");
+        block_orders.fill(NOT_FREE);
+
+        let mut allocator = BuddyAllocator {
+            frames,
+            block_orders,
+            free_lists: [None; MAX_ORDER + 1],
+            free_blocks: [0; MAX_ORDER + 1],
+            free_frames: 0,
+            physical_memory_offset,
+        };
+
+        // Carve the range into the largest naturally aligned blocks that fit
+        let mut frame = frames.start;
+        while frame < frames.end {
+            let number = frame_number(frame);
+            let remaining = frame_count(PhysFrame::range(frame, frames.end));
+            let order = (0..=MAX_ORDER)
+                .rev()
+                .find(|&order| number % (1 << order) == 0 && (1 << order) <= remaining)
+                .unwrap();
+            allocator.push(frame, order);
+            allocator.free_frames += 1 << order;
+            frame += 1 << order;
+        }
+        allocator
+    }
+
+    /// Allocates `2^order` physically contiguous frames, aligned to `2^order` frames
+    pub fn allocate(&mut self, order: usize) -> Option<PhysFrame> {
+        let found = (order..=MAX_ORDER).find(|&o| self.free_lists[o].is_some())?;
+        let block = self.pop(found).unwrap();
+
+        // Split the block in half until it is the requested size, giving back the upper halves
+        for split in (order..found).rev() {
+            self.push(block + (1 << split), split);
+        }
+        self.free_frames -= 1 << order;
+        Some(block)
+    }
+
+    /// Returns a block of `2^order` frames starting at `frame`, merging it with its buddies
+    ///
+    /// The block does not need to have been allocated with the same order, so one large
+    /// allocation can be given back a frame at a time.
+    ///
+    /// # Safety
+    /// The block must have been allocated from this allocator, and must be unused
+    pub unsafe fn deallocate(&mut self, frame: PhysFrame, order: usize) {
+        assert!(order <= MAX_ORDER);
+        assert!(
+            frame_number(frame) % (1 << order) == 0,
+            "{:?} is not aligned for order {}",
+            frame,
+            order
+        );
+        assert!(
+            self.contains(frame) && self.contains(frame + ((1 << order) - 1)),
+            "{:?} was not allocated from this buddy allocator",
+            frame
+        );
+        assert!(
+            !self.overlaps_free_block(frame, order),
+            "double free of {:?}",
+            frame
+        );
+        self.free_frames += 1 << order;
+
+        let mut block = frame;
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy_number = frame_number(block) ^ (1 << order);
+            let buddy = PhysFrame::containing_address(PhysAddr::new(buddy_number * FRAME_SIZE));
+            if !self.contains(buddy) || self.block_orders[self.index(buddy)] != order as u8 {
+                break;
+            }
+            self.remove(buddy, order);
+            block = block.min(buddy);
+            order += 1;
+        }
+        self.push(block, order);
+    }
+
+    /// Returns true if `frame` is managed by this allocator
+    pub fn contains(&self, frame: PhysFrame) -> bool {
+        self.frames.start <= frame && frame < self.frames.end
+    }
+
+    /// The frames managed by this allocator
+    pub fn frames(&self) -> PhysFrameRange {
+        self.frames
+    }
+
+    /// The total number of free frames, across every order
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// The number of free blocks of exactly `order`
+    pub fn free_blocks(&self, order: usize) -> usize {
+        self.free_blocks[order]
+    }
+
+    /// Returns true if any frame of the block of `2^order` frames at `frame` is free.
+    ///
+    /// Blocks are naturally aligned, so a free block either starts inside this one, or is larger
+    /// and contains it. A frame that was merged into a bigger block is only found by the second
+    /// check, as `block_orders` only marks the first frame of each free block
+    fn overlaps_free_block(&self, frame: PhysFrame, order: usize) -> bool {
+        let index = self.index(frame);
+        let starts_inside = self.block_orders[index..index + (1 << order)]
+            .iter()
+            .any(|&block_order| block_order != NOT_FREE);
+        let contained = (order + 1..=MAX_ORDER).any(|larger| {
+            let start = frame_number(frame) & !((1 << larger) - 1);
+            let start = PhysFrame::containing_address(PhysAddr::new(start * FRAME_SIZE));
+            self.contains(start) && self.block_orders[self.index(start)] == larger as u8
+        });
+        starts_inside || contained
+    }
+
+    fn index(&self, frame: PhysFrame) -> usize {
+        frame_count(PhysFrame::range(self.frames.start, frame))
+    }
+
+    fn header(&self, frame: PhysFrame) -> *mut FreeBlock {
+        (self.physical_memory_offset + frame.start_address().as_u64()).as_mut_ptr()
+    }
+
+    fn push(&mut self, frame: PhysFrame, order: usize) {
+        let next = self.free_lists[order];
+        // SAFETY: `frame` is a free block inside our range, so we own its memory, and physical
+        // memory is mapped at `physical_memory_offset`
+        unsafe {
+            ptr::write(self.header(frame), FreeBlock { prev: None, next });
+            if let Some(next) = next {
+                (*self.header(next)).prev = Some(frame);
+            }
+        }
+        self.free_lists[order] = Some(frame);
+        self.free_blocks[order] += 1;
+        let index = self.index(frame);
+        self.block_orders[index] = order as u8;
+    }
+
+    fn pop(&mut self, order: usize) -> Option<PhysFrame> {
+        let frame = self.free_lists[order]?;
+        self.remove(frame, order);
+        Some(frame)
+    }
+
+    fn remove(&mut self, frame: PhysFrame, order: usize) {
+        // SAFETY: `frame` is on a free list, so it holds a valid header that we own
+        unsafe {
+            let FreeBlock { prev, next } = ptr::read(self.header(frame));
+            match prev {
+                Some(prev) => (*self.header(prev)).next = next,
+                None => self.free_lists[order] = next,
+            }
+            if let Some(next) = next {
+                (*self.header(next)).prev = prev;
+            }
+        }
+        self.free_blocks[order] -= 1;
+        let index = self.index(frame);
+        self.block_orders[index] = NOT_FREE;
+    }
+}
+
+fn frame_number(frame: PhysFrame) -> u64 {
+    frame.start_address().as_u64() / FRAME_SIZE
+}
+
+fn frame_count(range: PhysFrameRange) -> usize {
+    ((range.end.start_address() - range.start.start_address()) / FRAME_SIZE) as usize
+}
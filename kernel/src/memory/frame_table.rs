@@ -0,0 +1,291 @@
+use alloc::vec::Vec;
+use core::{mem::size_of, slice};
+
+use bitflags::bitflags;
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::{
+    structures::paging::{FrameDeallocator, PageSize, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use super::{frame_allocator, BitmapFrameAllocator, BuddyAllocator};
+use crate::serial_println;
+
+/// What a physical frame is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FrameOwner {
+    /// The frame can be allocated
+    Free,
+    /// The frame is not usable RAM, or the bootloader uses it for the kernel image and boot data
+    Reserved,
+    /// Kernel memory that doesn't fit any other category, such as stacks and allocator metadata
+    Kernel,
+    /// Backing memory of the kernel heap
+    KernelHeap,
+    /// A slab of a [`SlabCache`](crate::allocator::SlabCache)
+    Slab,
+    /// A page table
+    PageTable,
+    /// User memory that was allocated by the process with this pid
+    User { pid: u64 },
+    /// User memory whose process exited while other processes still map it
+    SharedUser,
+    /// Backing memory of a shared memory object
+    SharedMemory,
+    /// Physically contiguous memory, for example for devices doing DMA
+    Dma,
+}
+
+bitflags! {
+    /// Extra state kept about a frame
+    pub struct FrameFlags: u8 {
+        /// The frame is part of the contiguous zone managed by the buddy allocator
+        const CONTIGUOUS_ZONE = 1 << 0;
+        /// The frame was allocated as part of a 2MiB huge frame
+        const HUGE = 1 << 1;
+    }
+}
+
+/// Everything the kernel knows about one physical frame, similar to Linux's `struct page`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameInfo {
+    /// How many mappings or owners refer to the frame. Zero if and only if the frame is free
+    pub refcount: u32,
+    pub owner: FrameOwner,
+    pub flags: FrameFlags,
+}
+
+impl FrameInfo {
+    const FREE: FrameInfo = FrameInfo {
+        refcount: 0,
+        owner: FrameOwner::Free,
+        flags: FrameFlags::empty(),
+    };
+
+    const RESERVED: FrameInfo = FrameInfo {
+        refcount: 0,
+        owner: FrameOwner::Reserved,
+        flags: FrameFlags::empty(),
+    };
+}
+
+/// One [`FrameInfo`] per physical frame, indexed by frame number
+struct FrameTable {
+    frames: &'static mut [FrameInfo],
+}
+
+static FRAME_TABLE: spin::Mutex<Option<FrameTable>> = spin::Mutex::new(None);
+
+fn with_frame_table<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut [FrameInfo]) -> R,
+{
+    crate::sys::without_interrupts(|| match FRAME_TABLE.lock().as_mut() {
+        Some(table) => f(table.frames),
+        None => f(&mut []),
+    })
+}
+
+/// Calls `f` with the entry of `frame`. Panics if `frame` isn't usable memory
+fn with_frame<F, R>(frame: PhysFrame, f: F) -> R
+where
+    F: FnOnce(&mut FrameInfo) -> R,
+{
+    with_frame_table(|frames| {
+        let index = frame_number(frame);
+        let info = frames
+            .get_mut(index)
+            .unwrap_or_else(|| panic!("{:?} is not usable memory", frame));
+        f(info)
+    })
+}
+
+fn frame_number(frame: PhysFrame) -> usize {
+    (frame.start_address().as_u64() / Size4KiB::SIZE) as usize
+}
+
+/// Builds the frame table from `memory_map`, storing it in frames taken from `allocator`.
+///
+/// Frames that are already allocated when this is called are tagged as [`FrameOwner::Kernel`],
+/// except for the free frames of `buddy`
+///
+/// # Safety
+/// All of physical memory must be mapped at `physical_memory_offset`
+pub(super) unsafe fn init(
+    memory_map: &MemoryMap,
+    allocator: &mut BitmapFrameAllocator,
+    buddy: Option<&BuddyAllocator>,
+    physical_memory_offset: VirtAddr,
+) {
+    let usable_regions = || {
+        memory_map
+            .iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable)
+    };
+    // Only usable frames are ever allocated, so nothing above the highest one needs an entry
+    let frame_count = usable_regions()
+        .map(|r| r.range.end_frame_number)
+        .max()
+        .unwrap_or(0) as usize;
+    let table_frames = (frame_count * size_of::<FrameInfo>() + 4095) / 4096;
+    let storage = allocator
+        .allocate_contiguous(table_frames, 1)
+        .expect("no room for the frame table");
+
+    let addr = physical_memory_offset + storage.start.start_address().as_u64();
+    // SAFETY: The frames were just allocated so nothing else is using them, and the caller
+    // guarantees that physical memory is mapped at `physical_memory_offset`
+    let frames: &mut [FrameInfo] =
+        unsafe { slice::from_raw_parts_mut(addr.as_mut_ptr(), frame_count) };
+    frames.fill(FrameInfo::RESERVED);
+
+    for region in usable_regions() {
+        let start = region.range.start_frame_number as usize;
+        let end = region.range.end_frame_number as usize;
+        for (index, info) in frames.iter_mut().enumerate().take(end).skip(start) {
+            let frame = PhysFrame::containing_address(PhysAddr::new(index as u64 * 4096));
+            *info = if buddy.map_or(false, |buddy| buddy.contains(frame)) {
+                FrameInfo {
+                    flags: FrameFlags::CONTIGUOUS_ZONE,
+                    ..FrameInfo::FREE
+                }
+            } else if allocator.is_used(frame) {
+                // The bitmap, the buddy allocator's metadata and this table
+                FrameInfo {
+                    refcount: 1,
+                    owner: FrameOwner::Kernel,
+                    flags: FrameFlags::empty(),
+                }
+            } else {
+                FrameInfo::FREE
+            };
+        }
+    }
+
+    *FRAME_TABLE.lock() = Some(FrameTable { frames });
+}
+
+/// Records that `frame` was handed out to `owner`. Panics if it wasn't free
+pub(super) fn mark_allocated(frame: PhysFrame, owner: FrameOwner, flags: FrameFlags) {
+    with_frame(frame, |info| {
+        assert!(
+            info.owner == FrameOwner::Free,
+            "allocated {:?}, which is owned by {:?}",
+            frame,
+            info.owner
+        );
+        info.refcount = 1;
+        info.owner = owner;
+        info.flags = (info.flags & FrameFlags::CONTIGUOUS_ZONE) | flags;
+    })
+}
+
+/// Records that `frame` was given back to an allocator. Panics on double frees and on frames that
+/// are still shared
+pub(super) fn mark_freed(frame: PhysFrame) {
+    with_frame(frame, |info| {
+        assert!(info.owner != FrameOwner::Free, "double free of {:?}", frame);
+        assert!(
+            info.owner != FrameOwner::Reserved,
+            "freed reserved {:?}",
+            frame
+        );
+        assert!(
+            info.refcount == 1,
+            "freed {:?} while it has {} references",
+            frame,
+            info.refcount
+        );
+        *info = FrameInfo {
+            flags: info.flags & FrameFlags::CONTIGUOUS_ZONE,
+            ..FrameInfo::FREE
+        };
+    })
+}
+
+/// Returns the metadata of `frame`. Frames that the table doesn't cover are reported as
+/// [`FrameOwner::Reserved`]
+pub fn frame_info(frame: PhysFrame) -> FrameInfo {
+    with_frame_table(|frames| {
+        frames
+            .get(frame_number(frame))
+            .copied()
+            .unwrap_or(FrameInfo::RESERVED)
+    })
+}
+
+/// Changes who `frame` is accounted to. The frame must be allocated
+pub fn set_frame_owner(frame: PhysFrame, owner: FrameOwner) {
+    with_frame(frame, |info| {
+        assert!(info.refcount > 0, "changing the owner of free {:?}", frame);
+        info.owner = owner;
+    })
+}
+
+/// Returns how many mappings refer to `frame`
+pub fn frame_refcount(frame: PhysFrame) -> usize {
+    frame_info(frame).refcount as usize
+}
+
+/// Records that `frame` gained another mapping
+pub fn share_frame(frame: PhysFrame) {
+    with_frame(frame, |info| {
+        assert!(info.refcount > 0, "sharing free {:?}", frame);
+        info.refcount += 1;
+    })
+}
+
+/// Drops one reference to `frame` that is held by `owner`, giving the frame back to the frame
+/// allocator if it was the last one.
+///
+/// If the frame stays shared and `owner` is the user that allocated it, the frame is tagged as
+/// [`FrameOwner::SharedUser`] so that it isn't reported as leaked once `owner` exits
+///
+/// # Safety
+/// The caller must own one of the references to `frame`, and must no longer use the frame through
+/// that reference
+pub unsafe fn release_frame(frame: PhysFrame, owner: FrameOwner) {
+    let last = with_frame(frame, |info| {
+        assert!(info.refcount > 0, "releasing free {:?}", frame);
+        if info.refcount == 1 {
+            return true;
+        }
+        info.refcount -= 1;
+        if info.owner == owner && matches!(owner, FrameOwner::User { .. }) {
+            info.owner = FrameOwner::SharedUser;
+        }
+        false
+    });
+    if last {
+        // SAFETY: The caller gave up the only reference to the frame
+        unsafe { frame_allocator().deallocate_frame(frame) };
+    }
+}
+
+/// The number of frames that are accounted to `owner`.
+///
+/// Once a process has exited and its address space is gone, any frame still owned by its pid has
+/// leaked
+pub fn frames_owned_by(owner: FrameOwner) -> usize {
+    with_frame_table(|frames| frames.iter().filter(|info| info.owner == owner).count())
+}
+
+/// Prints how many frames each owner holds over serial, to track down leaks
+pub fn print_frame_owners() {
+    // Sorted by owner. Entries are read one at a time so that the table isn't locked while the
+    // heap grows
+    let mut owners: Vec<(FrameOwner, usize)> = Vec::new();
+    let frame_count = with_frame_table(|frames| frames.len());
+    for index in 0..frame_count {
+        let owner = with_frame_table(|frames| frames[index].owner);
+        match owners.binary_search_by_key(&owner, |&(owner, _)| owner) {
+            Ok(i) => owners[i].1 += 1,
+            Err(i) => owners.insert(i, (owner, 1)),
+        }
+    }
+
+    serial_println!("Frame owners:");
+    for (owner, frames) in owners {
+        serial_println!("{:>10} {:?}", frames, owner);
+    }
+}
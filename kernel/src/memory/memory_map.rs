@@ -0,0 +1,96 @@
+use alloc::{format, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+
+use crate::serial_println;
+
+static TOTAL_FRAMES: AtomicU64 = AtomicU64::new(0);
+static KERNEL_RESERVED_FRAMES: AtomicU64 = AtomicU64::new(0);
+
+/// Returns true for regions that the bootloader set aside for the kernel itself
+fn is_kernel_reserved(region_type: MemoryRegionType) -> bool {
+    matches!(
+        region_type,
+        MemoryRegionType::Kernel
+            | MemoryRegionType::KernelStack
+            | MemoryRegionType::PageTable
+            | MemoryRegionType::Bootloader
+            | MemoryRegionType::BootInfo
+            | MemoryRegionType::Package
+            | MemoryRegionType::FrameZero
+    )
+}
+
+/// Remembers the frame counts from `memory_map` that don't change after boot
+pub(super) fn record(memory_map: &MemoryMap) {
+    let mut total = 0;
+    let mut kernel_reserved = 0;
+    for region in memory_map.iter() {
+        let frames = region.range.end_frame_number - region.range.start_frame_number;
+        total += frames;
+        if is_kernel_reserved(region.region_type) {
+            kernel_reserved += frames;
+        }
+    }
+    TOTAL_FRAMES.store(total, Ordering::Relaxed);
+    KERNEL_RESERVED_FRAMES.store(kernel_reserved, Ordering::Relaxed);
+}
+
+/// The number of frames in the bootloader's memory map, of any type
+pub fn total_frames() -> u64 {
+    TOTAL_FRAMES.load(Ordering::Relaxed)
+}
+
+/// The number of frames that the bootloader set aside for the kernel image, its stack, page tables
+/// and boot information
+pub fn kernel_reserved_frames() -> u64 {
+    KERNEL_RESERVED_FRAMES.load(Ordering::Relaxed)
+}
+
+/// Prints how many regions and frames of each [`MemoryRegionType`] are in `memory_map` over serial
+pub fn print_memory_map(memory_map: &MemoryMap) {
+    // (type, regions, frames) in the order each type first appears
+    let mut summary: Vec<(MemoryRegionType, usize, u64)> = Vec::new();
+    for region in memory_map.iter() {
+        let frames = region.range.end_frame_number - region.range.start_frame_number;
+        match summary
+            .iter_mut()
+            .find(|(ty, _, _)| *ty == region.region_type)
+        {
+            Some((_, regions, total)) => {
+                *regions += 1;
+                *total += frames;
+            }
+            None => summary.push((region.region_type, 1, frames)),
+        }
+    }
+
+    serial_println!("Memory map:");
+    serial_println!(
+        "{:<20} {:>8} {:>10} {:>10}",
+        "type",
+        "regions",
+        "frames",
+        "KiB"
+    );
+    for (ty, regions, frames) in summary.iter() {
+        let ty = format!("{:?}", ty);
+        serial_println!(
+            "{:<20} {:>8} {:>10} {:>10}",
+            ty,
+            regions,
+            frames,
+            frames * 4
+        );
+    }
+    let regions: usize = summary.iter().map(|(_, regions, _)| regions).sum();
+    let frames: u64 = summary.iter().map(|(_, _, frames)| frames).sum();
+    serial_println!(
+        "{:<20} {:>8} {:>10} {:>10}",
+        "total",
+        regions,
+        frames,
+        frames * 4
+    );
+}
@@ -0,0 +1,391 @@
+mod address_space;
+mod bitmap;
+mod buddy;
+mod frame_table;
+mod memory_map;
+mod pressure;
+mod shared_memory;
+mod stack;
+mod vma;
+mod vmalloc;
+
+pub use address_space::{
+    is_user_page, AddressSpace, FaultError, COPY_ON_WRITE, SHARED, USER_END, USER_START,
+};
+pub use bitmap::BitmapFrameAllocator;
+pub use buddy::{BuddyAllocator, MAX_ORDER};
+pub use frame_table::{
+    frame_info, frame_refcount, frames_owned_by, print_frame_owners, release_frame,
+    set_frame_owner, share_frame, FrameFlags, FrameInfo, FrameOwner,
+};
+pub use memory_map::{kernel_reserved_frames, print_memory_map, total_frames};
+pub use pressure::{low_memory, out_of_memory_count, report_pressure, LOW_MEMORY_FRAMES};
+pub use shared_memory::{shared_memory_objects, Access, SharedMemoryError, SharedMemoryHandles};
+pub use stack::{overflowed_stack, KernelStack, StackKind};
+pub use vma::{check_vma_flags, Vma, VmaError, VmaKind};
+pub use vmalloc::{
+    vfree, vmalloc, vmalloc_free_bytes, vmap, VirtualRangeAllocator, VMALLOC_END, VMALLOC_START,
+};
+
+use core::{
+    slice,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use bootloader::bootinfo::MemoryMap;
+use x86_64::{
+    structures::paging::{
+        frame::PhysFrameRange, FrameAllocator, FrameDeallocator, OffsetPageTable, PageSize,
+        PageTable, PageTableFlags, PhysFrame, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// Initialize a new OffsetPageTable and the global frame allocator, returning a guard to the new
+/// page mapper.
+///
+/// Call [`mapper`] to obtain the kernel's mapper later, and [`frame_allocator`] to allocate
+/// physical frames.
+///
+/// # Safety
+///
+/// 1. The caller must guarantee that the complete physical memory is mapped to virtual memory at
+///     the passed `physical_memory_offset`.
+///
+/// 2. This function must be only called once, on the bootstrap processor
+///
+/// 3. All frames marked as `USABLE` in `memory_map` must really be unused
+pub unsafe fn init(
+    physical_memory_offset: VirtAddr,
+    memory_map: &'static MemoryMap,
+) -> MapperGuard {
+    memory_map::record(memory_map);
+    // SAFETY: Guaranteed by the caller
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(memory_map, physical_memory_offset) };
+    reserve_low_memory_frame(&mut frame_allocator);
+    // SAFETY: `frame_allocator` has just been created from the valid memory map
+    let buddy_allocator = unsafe { init_buddy(&mut frame_allocator, physical_memory_offset) };
+    // SAFETY: Guaranteed by the caller
+    unsafe {
+        frame_table::init(
+            memory_map,
+            &mut frame_allocator,
+            buddy_allocator.as_ref(),
+            physical_memory_offset,
+        )
+    };
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+    *BUDDY_ALLOCATOR.lock() = buddy_allocator;
+
+    use x86_64::registers::control::Cr3;
+    let (level4_frame, _) = Cr3::read();
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    KERNEL_LEVEL_4_FRAME.store(level4_frame.start_address().as_u64(), Ordering::Relaxed);
+
+    let phys_addr = level4_frame.start_address();
+    let virt_addr = physical_memory_offset + phys_addr.as_u64();
+    let page_table_ptr: *mut PageTable = virt_addr.as_mut_ptr();
+
+    // SAFETY: Caller has guaranteed that physical memory is mapped at `physical_memory_offset`
+    let level_4_table = unsafe { &mut *page_table_ptr };
+    reserve_vmalloc_table(level_4_table);
+
+    let mapper = unsafe { OffsetPageTable::new(level_4_table, physical_memory_offset) };
+    crate::sys::without_interrupts(|| *MAPPER.lock() = Some(mapper));
+    MapperGuard { _private: () }
+}
+
+/// Creates the level 3 table for the vmalloc area up front, so that address spaces created later
+/// share it with the kernel
+fn reserve_vmalloc_table(level_4_table: &mut PageTable) {
+    let entry = &mut level_4_table[(VMALLOC_START >> 39) as usize];
+    assert!(entry.is_unused(), "vmalloc area is already in use");
+    let frame = frame_allocator_for(FrameOwner::PageTable)
+        .allocate_frame()
+        .expect("no frame for the vmalloc page table");
+    // SAFETY: The frame was just allocated, so nothing else refers to it
+    unsafe { (*phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>()).zero() };
+    entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+}
+
+/// A frame below 1MiB that is set aside at boot, or 0 if none was free. Application processors
+/// start running in real mode, where they can only reach the first MiB
+static LOW_MEMORY_FRAME: AtomicU64 = AtomicU64::new(0);
+
+/// Takes the lowest free frame out of `frame_allocator` if it is below 1MiB. This has to happen
+/// before anything else is allocated, as the allocator hands out the lowest frames first
+fn reserve_low_memory_frame(frame_allocator: &mut BitmapFrameAllocator) {
+    let Some(frames) = frame_allocator.allocate_contiguous(1, 1) else {
+        return;
+    };
+    if frames.start.start_address().as_u64() < 0x10_0000 {
+        LOW_MEMORY_FRAME.store(frames.start.start_address().as_u64(), Ordering::Relaxed);
+    } else {
+        // SAFETY: The frame was just allocated and never used
+        unsafe { frame_allocator.deallocate_frame(frames.start) };
+    }
+}
+
+/// A frame below 1MiB that is reserved for starting application processors, if there was one.
+/// It belongs to [`FrameOwner::Kernel`] and is never freed
+pub fn low_memory_frame() -> Option<PhysFrame> {
+    match LOW_MEMORY_FRAME.load(Ordering::Relaxed) {
+        0 => None,
+        addr => Some(PhysFrame::containing_address(PhysAddr::new(addr))),
+    }
+}
+
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+static KERNEL_LEVEL_4_FRAME: AtomicU64 = AtomicU64::new(0);
+
+/// The virtual address where the bootloader mapped all of physical memory
+pub fn physical_memory_offset() -> VirtAddr {
+    let offset = PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed);
+    debug_assert!(offset != 0, "memory::init has not been called");
+    VirtAddr::new(offset)
+}
+
+/// Returns the virtual address that `addr` is mapped at inside the physical memory mapping
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    physical_memory_offset() + addr.as_u64()
+}
+
+/// The level 4 page table that the bootloader set up, which holds the kernel's mappings
+pub fn kernel_level_4_frame() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_LEVEL_4_FRAME.load(Ordering::Relaxed)))
+}
+
+/// Maps pages in the kernel's page tables, which every CPU shares. Set by [`init`]
+static MAPPER: spin::Mutex<Option<OffsetPageTable<'static>>> = spin::Mutex::new(None);
+
+/// Gets a handle to the kernel's page mapper, which can be used from any CPU
+///
+/// Panics when used before [`crate::memory::init`] is called
+#[must_use]
+pub fn mapper() -> MapperGuard {
+    MapperGuard { _private: () }
+}
+
+/// Handle to the kernel's page mapper, see [`mapper`]
+pub struct MapperGuard {
+    _private: (),
+}
+
+impl MapperGuard {
+    /// Locks the mapper with interrupts disabled, as interrupt handlers may use it too, and runs
+    /// `f` with it. `f` must not use the mapper again, or allocate from the kernel heap which may
+    /// have to grow
+    pub fn with<F, R>(self, f: F) -> R
+    where
+        F: FnOnce(&mut OffsetPageTable<'static>) -> R,
+    {
+        crate::sys::without_interrupts(|| {
+            let mut mapper = MAPPER.lock();
+            f(mapper.as_mut().expect("memory::init has not been called"))
+        })
+    }
+}
+
+/// Handle to the kernel's global physical frame allocator.
+///
+/// Each call locks the global allocator with interrupts disabled, so this can be passed to any
+/// [`x86_64`] paging function that expects a `FrameAllocator` or `FrameDeallocator`.
+/// Allocated frames are recorded in the frame table as belonging to the handle's owner
+#[derive(Debug, Clone, Copy)]
+pub struct GlobalFrameAllocator {
+    owner: FrameOwner,
+}
+
+static FRAME_ALLOCATOR: spin::Mutex<Option<BitmapFrameAllocator>> = spin::Mutex::new(None);
+
+/// Returns a handle to the global frame allocator that hands out [`FrameOwner::Kernel`] frames
+///
+/// Allocations made through this handle will panic if [`init`] has not been called
+pub fn frame_allocator() -> GlobalFrameAllocator {
+    frame_allocator_for(FrameOwner::Kernel)
+}
+
+/// Returns a handle to the global frame allocator whose frames are accounted to `owner`
+pub fn frame_allocator_for(owner: FrameOwner) -> GlobalFrameAllocator {
+    GlobalFrameAllocator { owner }
+}
+
+fn with_frame_allocator<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut BitmapFrameAllocator) -> R,
+{
+    crate::sys::without_interrupts(|| {
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        f(allocator
+            .as_mut()
+            .expect("frame allocator used before memory::init"))
+    })
+}
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let allocated = with_frame_allocator(|allocator| {
+            let frame = allocator.allocate_frame()?;
+            Some((frame, allocator.free_frames()))
+        });
+        match allocated {
+            Some((frame, free)) => {
+                frame_table::mark_allocated(frame, self.owner, FrameFlags::empty());
+                // Recorded outside of the allocator's lock
+                pressure::update(free);
+                Some(frame)
+            }
+            // Only dip into the contiguous zone once everything else is gone
+            None => {
+                let frame = allocate_contiguous_for(0, self.owner, FrameFlags::empty());
+                if frame.is_none() {
+                    pressure::out_of_memory();
+                }
+                frame
+            }
+        }
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let in_buddy = with_buddy_allocator(|buddy| buddy.map_or(false, |b| b.contains(frame)));
+        if in_buddy {
+            // SAFETY: The caller guarantees that `frame` is unused
+            unsafe { deallocate_contiguous(frame, 0) };
+        } else {
+            frame_table::mark_freed(frame);
+            let free = with_frame_allocator(|allocator| {
+                // SAFETY: The caller guarantees that `frame` is unused
+                unsafe { allocator.deallocate_frame(frame) };
+                allocator.free_frames()
+            });
+            pressure::update(free);
+        }
+    }
+}
+
+/// The number of frames set aside at boot for physically contiguous allocations (8MiB)
+const CONTIGUOUS_ZONE_FRAMES: usize = 4 << MAX_ORDER;
+
+static BUDDY_ALLOCATOR: spin::Mutex<Option<BuddyAllocator>> = spin::Mutex::new(None);
+
+/// Moves a naturally aligned zone of frames out of `frame_allocator` and builds a buddy allocator
+/// for it. Returns `None` if physical memory is too fragmented to find such a zone
+///
+/// # Safety
+/// The frames handed out by `frame_allocator` must be unused, and all of physical memory must be
+/// mapped at `physical_memory_offset`
+unsafe fn init_buddy(
+    frame_allocator: &mut BitmapFrameAllocator,
+    physical_memory_offset: VirtAddr,
+) -> Option<BuddyAllocator> {
+    let zone = frame_allocator.allocate_contiguous(CONTIGUOUS_ZONE_FRAMES, 1 << MAX_ORDER)?;
+
+    // One byte of metadata for every frame in the zone
+    let metadata_frames = (CONTIGUOUS_ZONE_FRAMES + 4095) / 4096;
+    let Some(metadata) = frame_allocator.allocate_contiguous(metadata_frames, 1) else {
+        for frame in zone {
+            // SAFETY: The zone was just allocated and never used
+            unsafe { frame_allocator.deallocate_frame(frame) };
+        }
+        return None;
+    };
+    let metadata_addr = physical_memory_offset + metadata.start.start_address().as_u64();
+    // SAFETY: The metadata frames were just allocated so nothing else is using them, and the
+    // caller guarantees that physical memory is mapped at `physical_memory_offset`
+    let block_orders =
+        unsafe { slice::from_raw_parts_mut(metadata_addr.as_mut_ptr(), CONTIGUOUS_ZONE_FRAMES) };
+    // SAFETY: The zone was just allocated, so we are the only owner of its frames
+    Some(unsafe { BuddyAllocator::new(zone, block_orders, physical_memory_offset) })
+}
+
+fn with_buddy_allocator<F, R>(f: F) -> R
+where
+    F: FnOnce(Option<&mut BuddyAllocator>) -> R,
+{
+    crate::sys::without_interrupts(|| f(BUDDY_ALLOCATOR.lock().as_mut()))
+}
+
+/// Allocates `2^order` physically contiguous frames aligned to their size, for `order` in
+/// `0..=MAX_ORDER`. The frames are accounted to [`FrameOwner::Dma`].
+///
+/// Returns `None` if there is no free block large enough
+pub fn allocate_contiguous(order: usize) -> Option<PhysFrame> {
+    allocate_contiguous_for(order, FrameOwner::Dma, FrameFlags::empty())
+}
+
+fn allocate_contiguous_for(
+    order: usize,
+    owner: FrameOwner,
+    flags: FrameFlags,
+) -> Option<PhysFrame> {
+    if order > MAX_ORDER {
+        return None;
+    }
+    let first = with_buddy_allocator(|buddy| buddy?.allocate(order))?;
+    for frame in PhysFrame::range(first, first + (1 << order)) {
+        frame_table::mark_allocated(frame, owner, flags);
+    }
+    Some(first)
+}
+
+/// Frees `2^order` frames starting at `frame` that were returned by [`allocate_contiguous`].
+///
+/// # Safety
+/// The frames must have been allocated by [`allocate_contiguous`] and must no longer be in use
+pub unsafe fn deallocate_contiguous(frame: PhysFrame, order: usize) {
+    for part in PhysFrame::range(frame, frame + (1 << order)) {
+        frame_table::mark_freed(part);
+    }
+    with_buddy_allocator(|buddy| {
+        let buddy = buddy.expect("contiguous frame freed without a buddy allocator");
+        // SAFETY: Guaranteed by the caller
+        unsafe { buddy.deallocate(frame, order) }
+    })
+}
+
+/// Allocates a 2MiB aligned frame for a huge page from the contiguous zone.
+///
+/// Huge frames are freed one 4KiB frame at a time through [`frame_allocator`], so that huge pages
+/// can be split without any bookkeeping. Every part is accounted to `owner`.
+///
+/// Returns `None` if no max order block is free
+pub fn allocate_huge_frame(owner: FrameOwner) -> Option<PhysFrame<Size2MiB>> {
+    // A max order block is exactly the size and alignment of a huge page
+    let frame = allocate_contiguous_for(MAX_ORDER, owner, FrameFlags::HUGE)?;
+    Some(
+        PhysFrame::from_start_address(frame.start_address())
+            .expect("max order block is 2MiB aligned"),
+    )
+}
+
+/// The 4KiB frames that make up `frame`
+pub fn huge_frame_parts(frame: PhysFrame<Size2MiB>) -> PhysFrameRange {
+    let start = PhysFrame::containing_address(frame.start_address());
+    PhysFrame::range(start, start + Size2MiB::SIZE / Size4KiB::SIZE)
+}
+
+/// A snapshot of how many physical frames are in use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStats {
+    /// Frames that the bootloader reported as usable
+    pub usable: usize,
+    /// Free frames, not including the contiguous zone
+    pub free: usize,
+    /// Used frames, including the contiguous zone
+    pub used: usize,
+    /// Free frames inside the contiguous zone
+    pub contiguous_free: usize,
+}
+
+pub fn frame_stats() -> FrameStats {
+    let contiguous_free = with_buddy_allocator(|buddy| buddy.map_or(0, |b| b.free_frames()));
+    with_frame_allocator(|allocator| FrameStats {
+        usable: allocator.usable_frames(),
+        free: allocator.free_frames(),
+        used: allocator.used_frames(),
+        contiguous_free,
+    })
+}
@@ -0,0 +1,71 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::println;
+
+/// Memory is low once fewer than this many frames (4MiB) are free outside of the contiguous zone
+pub const LOW_MEMORY_FRAMES: usize = 1024;
+
+/// Memory counts as low until twice [`LOW_MEMORY_FRAMES`] are free again, so that allocations
+/// near the limit don't log over and over
+const RELIEVED_FRAMES: usize = 2 * LOW_MEMORY_FRAMES;
+
+static LOW_MEMORY: AtomicBool = AtomicBool::new(false);
+/// How many frames were free when memory last became low or recovered
+static FREE_AT_CHANGE: AtomicUsize = AtomicUsize::new(0);
+static OUT_OF_MEMORY: AtomicUsize = AtomicUsize::new(0);
+
+/// What [`report`] logged last, so that it only logs changes
+static REPORTED_LOW_MEMORY: AtomicBool = AtomicBool::new(false);
+static REPORTED_OUT_OF_MEMORY: AtomicUsize = AtomicUsize::new(0);
+
+/// Records that `free` frames are left after a frame was allocated or freed
+pub(super) fn update(free: usize) {
+    let low = if free < LOW_MEMORY_FRAMES {
+        true
+    } else if free >= RELIEVED_FRAMES {
+        false
+    } else {
+        return;
+    };
+    if LOW_MEMORY.swap(low, Ordering::Relaxed) != low {
+        FREE_AT_CHANGE.store(free, Ordering::Relaxed);
+    }
+}
+
+/// Records that no frame was left for an allocation
+pub(super) fn out_of_memory() {
+    OUT_OF_MEMORY.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Logs whether memory became low or recovered, and how many frame allocations failed, since the
+/// last call. The frame allocator only records these, as it runs with the heap and page table
+/// locks held, so they are logged from the timer interrupt instead
+pub fn report_pressure() {
+    let low = LOW_MEMORY.load(Ordering::Relaxed);
+    if REPORTED_LOW_MEMORY.swap(low, Ordering::Relaxed) != low {
+        let free = FREE_AT_CHANGE.load(Ordering::Relaxed);
+        if low {
+            println!("memory pressure: only {} frames are free", free);
+        } else {
+            println!("memory pressure relieved: {} frames are free", free);
+        }
+    }
+    let failed = OUT_OF_MEMORY.load(Ordering::Relaxed);
+    let reported = REPORTED_OUT_OF_MEMORY.swap(failed, Ordering::Relaxed);
+    if failed > reported {
+        println!(
+            "out of memory: {} frame allocations failed",
+            failed - reported
+        );
+    }
+}
+
+/// Returns true if fewer than [`LOW_MEMORY_FRAMES`] frames are free
+pub fn low_memory() -> bool {
+    LOW_MEMORY.load(Ordering::Relaxed)
+}
+
+/// The number of frame allocations that failed since boot
+pub fn out_of_memory_count() -> usize {
+    OUT_OF_MEMORY.load(Ordering::Relaxed)
+}
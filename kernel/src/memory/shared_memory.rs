@@ -0,0 +1,288 @@
+use alloc::vec::Vec;
+use core::{
+    ptr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use x86_64::structures::paging::{FrameAllocator, PhysFrame};
+
+use super::{frame_allocator_for, phys_to_virt, release_frame, FrameOwner};
+
+/// Memory that several processes can map at once, for passing data without copying it.
+///
+/// The object holds one reference to each of its frames, and every mapping of it holds another, so
+/// the frames are freed once the object is gone and the last mapping is unmapped
+struct SharedMemory {
+    frames: Vec<PhysFrame>,
+    /// How many handles processes hold to the object. It is destroyed when this reaches zero
+    handles: usize,
+    /// The pid of the process that created the object, which may always open it read-write
+    owner: u64,
+    /// What other processes get when they open the object by id, if they may open it at all
+    shared: Option<Access>,
+}
+
+/// What a handle allows its process to do with a shared memory object
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Access {
+    ReadOnly,
+    ReadWrite,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SharedMemoryError {
+    /// There is no such object or handle
+    NotFound,
+    /// The object isn't shared with the process, or only with less access than asked for
+    PermissionDenied,
+    OutOfMemory,
+}
+
+/// Every shared memory object that still has a handle, with its id. Ids only grow, so new objects
+/// are pushed and the list stays sorted by id
+static OBJECTS: spin::Mutex<Vec<(u64, SharedMemory)>> = spin::Mutex::new(Vec::new());
+
+fn with_objects<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut Vec<(u64, SharedMemory)>) -> R,
+{
+    crate::sys::without_interrupts(|| f(&mut OBJECTS.lock()))
+}
+
+/// The index of the object `id` in `objects`
+fn find(objects: &[(u64, SharedMemory)], id: u64) -> Option<usize> {
+    objects.binary_search_by_key(&id, |&(other, _)| other).ok()
+}
+
+/// Creates an object of `pages` zeroed pages with a single handle, owned by the process `owner`,
+/// and returns its id. Returns `None` if there isn't enough memory
+fn create(pages: usize, owner: u64, shared: Option<Access>) -> Option<u64> {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+    let mut allocator = frame_allocator_for(FrameOwner::SharedMemory);
+    let mut frames = Vec::new();
+    frames.try_reserve_exact(pages).ok()?;
+    for _ in 0..pages {
+        let Some(frame) = allocator.allocate_frame() else {
+            release(frames);
+            return None;
+        };
+        // SAFETY: The frame was just allocated, so nothing else refers to it
+        unsafe {
+            ptr::write_bytes(
+                phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(),
+                0,
+                4096,
+            )
+        };
+        frames.push(frame);
+    }
+
+    let object = SharedMemory {
+        frames,
+        handles: 1,
+        owner,
+        shared,
+    };
+    let id = with_objects(|objects| {
+        if objects.try_reserve(1).is_err() {
+            return Err(object);
+        }
+        // Taken with the lock held, so that ids are pushed in order
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        objects.push((id, object));
+        Ok(id)
+    });
+    match id {
+        Ok(id) => Some(id),
+        // Frames are released outside of the lock, see `close`
+        Err(object) => {
+            release(object.frames);
+            None
+        }
+    }
+}
+
+/// Adds a handle to the object `id` for the process `pid`, and returns the access that the handle
+/// gives
+fn open(id: u64, pid: u64) -> Result<Access, SharedMemoryError> {
+    with_objects(|objects| {
+        let index = find(objects, id).ok_or(SharedMemoryError::NotFound)?;
+        let object = &mut objects[index].1;
+        let access = if object.owner == pid {
+            Access::ReadWrite
+        } else {
+            object.shared.ok_or(SharedMemoryError::PermissionDenied)?
+        };
+        object.handles += 1;
+        Ok(access)
+    })
+}
+
+/// Adds a handle to the object `id`, which a forked process inherits
+fn duplicate(id: u64) {
+    with_objects(|objects| {
+        let index = find(objects, id).expect("handle to missing shared memory");
+        objects[index].1.handles += 1;
+    })
+}
+
+/// Drops a handle to the object `id`, destroying it if it was the last one
+fn close(id: u64) {
+    let destroyed = with_objects(|objects| {
+        let index = find(objects, id).expect("closing unknown shared memory");
+        let object = &mut objects[index].1;
+        object.handles -= 1;
+        if object.handles == 0 {
+            Some(objects.remove(index).1)
+        } else {
+            None
+        }
+    });
+    // Frames are released outside of the lock, as that can take the frame allocator's locks
+    if let Some(object) = destroyed {
+        release(object.frames);
+    }
+}
+
+/// Drops the object's reference to each of `frames`
+fn release(frames: Vec<PhysFrame>) {
+    for frame in frames {
+        // SAFETY: The object owned a reference to the frame, and never uses it again
+        unsafe { release_frame(frame, FrameOwner::SharedMemory) };
+    }
+}
+
+/// The number of shared memory objects that have at least one handle
+pub fn shared_memory_objects() -> usize {
+    with_objects(|objects| objects.len())
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Handle {
+    id: u64,
+    access: Access,
+}
+
+/// The shared memory objects that a process holds handles to, like a table of file descriptors.
+///
+/// Handles are numbered per process, starting at 0, and numbers of closed handles are reused.
+/// Objects also have a global id, which a process can pass to another process so that it can open
+/// its own handle, if the object's creator shared it. Every handle is closed when this is dropped
+#[derive(Debug)]
+pub struct SharedMemoryHandles {
+    /// The process that the handles belong to
+    pid: u64,
+    handles: Vec<Option<Handle>>,
+}
+
+impl SharedMemoryHandles {
+    /// Creates an empty table for the process `pid`
+    pub fn new(pid: u64) -> Self {
+        SharedMemoryHandles {
+            pid,
+            handles: Vec::new(),
+        }
+    }
+
+    /// Creates an object of `pages` zeroed pages and returns a read-write handle to it. Other
+    /// processes may open it with `shared` access, or not at all if that is `None`
+    pub fn create(
+        &mut self,
+        pages: usize,
+        shared: Option<Access>,
+    ) -> Result<usize, SharedMemoryError> {
+        let slot = self.free_slot()?;
+        let id = create(pages, self.pid, shared).ok_or(SharedMemoryError::OutOfMemory)?;
+        Ok(self.insert(
+            slot,
+            Handle {
+                id,
+                access: Access::ReadWrite,
+            },
+        ))
+    }
+
+    /// Opens a new handle to the object `id` and returns it. The creator of the object gets
+    /// read-write access, and other processes get whatever the creator shared
+    pub fn open(&mut self, id: u64) -> Result<usize, SharedMemoryError> {
+        let slot = self.free_slot()?;
+        let access = open(id, self.pid)?;
+        Ok(self.insert(slot, Handle { id, access }))
+    }
+
+    /// Closes `handle`. Returns `None` if there is no such handle
+    pub fn close(&mut self, handle: usize) -> Option<()> {
+        let Handle { id, .. } = self.handles.get_mut(handle)?.take()?;
+        close(id);
+        Some(())
+    }
+
+    /// The global id of the object that `handle` refers to
+    pub fn id(&self, handle: usize) -> Option<u64> {
+        self.get(handle).map(|handle| handle.id)
+    }
+
+    /// Returns the frames of the object that `handle` refers to, for mapping them writable if
+    /// `write` is set
+    pub fn frames(&self, handle: usize, write: bool) -> Result<Vec<PhysFrame>, SharedMemoryError> {
+        let handle = self.get(handle).ok_or(SharedMemoryError::NotFound)?;
+        if write && handle.access != Access::ReadWrite {
+            return Err(SharedMemoryError::PermissionDenied);
+        }
+        with_objects(|objects| {
+            let index = find(objects, handle.id).expect("handle to missing shared memory");
+            let frames = &objects[index].1.frames;
+            let mut copy = Vec::new();
+            copy.try_reserve_exact(frames.len())
+                .map_err(|_| SharedMemoryError::OutOfMemory)?;
+            copy.extend_from_slice(frames);
+            Ok(copy)
+        })
+    }
+
+    /// Gives the forked process `pid` the same handles, with the same numbers and access. Returns
+    /// `None` if there is no memory for the table
+    pub fn fork(&self, pid: u64) -> Option<Self> {
+        let mut handles = Vec::new();
+        handles.try_reserve_exact(self.handles.len()).ok()?;
+        handles.extend_from_slice(&self.handles);
+        for handle in handles.iter().flatten() {
+            duplicate(handle.id);
+        }
+        Some(SharedMemoryHandles { pid, handles })
+    }
+
+    fn get(&self, handle: usize) -> Option<&Handle> {
+        self.handles.get(handle)?.as_ref()
+    }
+
+    /// The lowest unused handle number, with room for it in the table
+    fn free_slot(&mut self) -> Result<usize, SharedMemoryError> {
+        if let Some(slot) = self.handles.iter().position(Option::is_none) {
+            return Ok(slot);
+        }
+        self.handles
+            .try_reserve(1)
+            .map_err(|_| SharedMemoryError::OutOfMemory)?;
+        Ok(self.handles.len())
+    }
+
+    /// Stores `handle` at `slot`, which [`Self::free_slot`] returned
+    fn insert(&mut self, slot: usize, handle: Handle) -> usize {
+        if slot == self.handles.len() {
+            self.handles.push(Some(handle));
+        } else {
+            self.handles[slot] = Some(handle);
+        }
+        slot
+    }
+}
+
+impl Drop for SharedMemoryHandles {
+    fn drop(&mut self) {
+        for handle in self.handles.drain(..).flatten() {
+            close(handle.id);
+        }
+    }
+}
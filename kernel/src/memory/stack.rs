@@ -0,0 +1,117 @@
+use alloc::vec::Vec;
+use core::fmt;
+
+use x86_64::{
+    structures::paging::{Page, PageSize, Size4KiB},
+    VirtAddr,
+};
+
+use super::{vfree, vmalloc};
+
+/// What a [`KernelStack`] is used for, so that overflows can be reported by name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackKind {
+    /// The IST stack for double faults
+    DoubleFault,
+    /// The IST stack for page faults
+    PageFault,
+    /// The stack that interrupts from user mode switch to (rsp0 in the TSS)
+    Privilege,
+    /// The stack a process's syscalls run on
+    Syscall { pid: u64 },
+    /// The stack that an application processor starts and idles on
+    Idle { cpu: usize },
+}
+
+impl fmt::Display for StackKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StackKind::DoubleFault => write!(f, "double fault stack"),
+            StackKind::PageFault => write!(f, "page fault stack"),
+            StackKind::Privilege => write!(f, "privilege stack"),
+            StackKind::Syscall { pid } => write!(f, "syscall stack of pid {}", pid),
+            StackKind::Idle { cpu } => write!(f, "idle stack of cpu {}", cpu),
+        }
+    }
+}
+
+/// The start of the guard page of every live kernel stack, and what the stack is used for
+static GUARD_PAGES: spin::Mutex<Vec<(VirtAddr, StackKind)>> = spin::Mutex::new(Vec::new());
+
+/// A kernel stack in the vmalloc area, with an unmapped guard page below it so that overflowing
+/// it page faults instead of corrupting whatever is mapped below.
+///
+/// The memory is freed when the stack is dropped, so it must not be in use by then
+#[derive(Debug)]
+pub struct KernelStack {
+    bottom: VirtAddr,
+    size: u64,
+    kind: StackKind,
+}
+
+impl KernelStack {
+    /// Allocates a stack of `size` bytes, which must be a multiple of the page size.
+    ///
+    /// Returns `None` if there is not enough memory
+    pub fn new(size: usize, kind: StackKind) -> Option<Self> {
+        assert!(
+            size as u64 % Size4KiB::SIZE == 0,
+            "stack size must be page aligned"
+        );
+        let bottom = vmalloc(size)?;
+        let stack = KernelStack {
+            bottom,
+            size: size as u64,
+            kind,
+        };
+        let guard = stack.guard_page().start_address();
+        crate::sys::without_interrupts(|| {
+            let mut guard_pages = GUARD_PAGES.lock();
+            guard_pages.try_reserve(1).ok()?;
+            guard_pages.push((guard, kind));
+            Some(())
+        })?;
+        Some(stack)
+    }
+
+    /// The address just above the stack, which is the initial stack pointer
+    pub fn top(&self) -> VirtAddr {
+        self.bottom + self.size
+    }
+
+    /// The unmapped page below the stack
+    pub fn guard_page(&self) -> Page {
+        Page::containing_address(self.bottom - 1u64)
+    }
+
+    pub fn kind(&self) -> StackKind {
+        self.kind
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let guard = self.guard_page().start_address();
+        crate::sys::without_interrupts(|| {
+            let mut guard_pages = GUARD_PAGES.lock();
+            // Not there if registering the stack failed in `new`
+            if let Some(index) = guard_pages.iter().position(|&(page, _)| page == guard) {
+                guard_pages.swap_remove(index);
+            }
+        });
+        // SAFETY: The owner of the stack guarantees that it is no longer in use
+        unsafe { vfree(self.bottom) };
+    }
+}
+
+/// Returns the stack whose guard page contains `addr`, if any. Used by the fault handlers to tell
+/// stack overflows apart from other faults
+pub fn overflowed_stack(addr: VirtAddr) -> Option<StackKind> {
+    let guard = Page::<Size4KiB>::containing_address(addr).start_address();
+    // The fault may have interrupted code that holds the lock
+    crate::sys::without_interrupts(|| {
+        let guard_pages = GUARD_PAGES.try_lock()?;
+        let &(_, kind) = guard_pages.iter().find(|&&(page, _)| page == guard)?;
+        Some(kind)
+    })
+}
@@ -0,0 +1,93 @@
+use x86_64::{
+    structures::paging::{page::PageRange, Page, PageTableFlags},
+    VirtAddr,
+};
+
+/// What a [`Vma`] is used for. Only used for bookkeeping and diagnostics, every kind except
+/// [`VmaKind::Shared`] is backed the same way
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaKind {
+    /// Segments loaded from the program's elf file
+    Image,
+    /// The user stack, which is backed a page at a time as it grows down
+    Stack,
+    /// Anonymous memory
+    Anonymous,
+    /// The heap below the program break, which grows and shrinks through the `brk` syscall
+    Heap,
+    /// A mapping of a shared memory object, whose pages are mapped when it is created
+    Shared,
+}
+
+/// A virtual memory area: a page aligned range of user addresses that the process is allowed to
+/// touch.
+///
+/// Pages inside a VMA don't need to be mapped. The first access to an unmapped page causes a page
+/// fault, which is resolved by mapping a zeroed frame with the VMA's flags.
+#[derive(Debug, Clone)]
+pub struct Vma {
+    pub start: VirtAddr,
+    /// One past the last address in this VMA
+    pub end: VirtAddr,
+    pub flags: PageTableFlags,
+    pub kind: VmaKind,
+}
+
+/// Reasons why a [`Vma`] could not be added to an address space
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaError {
+    /// The VMA is empty, isn't page aligned, or leaves the user half of the address space
+    InvalidRange,
+    /// The VMA overlaps one that already exists
+    Overlaps,
+    /// The flags make the memory both writable and executable, which is never allowed
+    WritableAndExecutable,
+    /// Part of the range isn't covered by any VMA
+    NotMapped,
+    /// A page table or a frame couldn't be allocated
+    OutOfMemory,
+}
+
+/// Fails with [`VmaError::WritableAndExecutable`] if `flags` would let user code write to memory
+/// that it can also execute
+pub fn check_vma_flags(flags: PageTableFlags) -> Result<(), VmaError> {
+    if flags.contains(PageTableFlags::WRITABLE) && !flags.contains(PageTableFlags::NO_EXECUTE) {
+        return Err(VmaError::WritableAndExecutable);
+    }
+    Ok(())
+}
+
+impl Vma {
+    pub fn new(start: VirtAddr, end: VirtAddr, flags: PageTableFlags, kind: VmaKind) -> Self {
+        Vma {
+            start,
+            end,
+            flags,
+            kind,
+        }
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    pub fn overlaps(&self, other: &Vma) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+
+    pub fn pages(&self) -> PageRange {
+        Page::range(
+            Page::containing_address(self.start),
+            Page::containing_address(self.end),
+        )
+    }
+
+    /// The size of this VMA in bytes
+    pub fn len(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+}
@@ -0,0 +1,360 @@
+use alloc::{vec, vec::Vec};
+use core::ptr;
+
+use x86_64::{
+    instructions::tlb,
+    structures::paging::{
+        frame::PhysFrameRange, page_table::PageTableEntry, FrameAllocator, FrameDeallocator,
+        Mapper, Page, PageSize, PageTable, PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+    },
+    VirtAddr,
+};
+
+use super::{frame_allocator, frame_allocator_for, mapper, phys_to_virt, FrameOwner};
+
+/// The start of the kernel's vmalloc area. It takes up a whole level 4 slot, whose level 3 table
+/// is created at boot so that every address space shares it
+pub const VMALLOC_START: u64 = 0x0000_5000_0000_0000;
+
+/// One past the end of the vmalloc area
+pub const VMALLOC_END: u64 = VMALLOC_START + (1 << 39);
+
+/// Hands out non-overlapping, page aligned ranges of virtual addresses.
+///
+/// Free ranges are kept sorted by address and merged with their neighbours when a range is given
+/// back, so the allocator never needs more entries than there are holes
+#[derive(Debug)]
+pub struct VirtualRangeAllocator {
+    /// Free ranges as their start and end address
+    free: Vec<(VirtAddr, VirtAddr)>,
+    /// How many ranges are handed out. There is at most one more hole than that, and `free` always
+    /// has room for that many, so that giving a range back never needs to allocate
+    allocated: usize,
+}
+
+impl VirtualRangeAllocator {
+    /// Creates an allocator that hands out addresses in `start..end`
+    pub fn new(start: VirtAddr, end: VirtAddr) -> Self {
+        assert!(start.is_aligned(Size4KiB::SIZE) && end.is_aligned(Size4KiB::SIZE));
+        assert!(start < end, "empty virtual range");
+        VirtualRangeAllocator {
+            free: vec![(start, end)],
+            allocated: 0,
+        }
+    }
+
+    /// Reserves `size` bytes, rounded up to whole pages, from the lowest free range that fits.
+    /// Returns `None` if no range fits, or if there is no memory to track it
+    pub fn allocate(&mut self, size: u64) -> Option<VirtAddr> {
+        let size = page_align(size);
+        if size == 0 {
+            return None;
+        }
+        let index = self
+            .free
+            .iter()
+            .position(|&(start, end)| end - start >= size)?;
+        let holes = self.allocated + 2;
+        self.free
+            .try_reserve(holes.saturating_sub(self.free.len()))
+            .ok()?;
+
+        let (start, end) = self.free[index];
+        if end - start > size {
+            self.free[index].0 = start + size;
+        } else {
+            self.free.remove(index);
+        }
+        self.allocated += 1;
+        Some(start)
+    }
+
+    /// Gives back `size` bytes starting at `start`, which must have come from [`Self::allocate`]
+    pub fn deallocate(&mut self, start: VirtAddr, size: u64) {
+        let end = start + page_align(size);
+        // The first free range after the one that is given back
+        let index = self
+            .free
+            .partition_point(|&(free_start, _)| free_start < start);
+        let prev = index.checked_sub(1).map(|prev| self.free[prev]);
+        let next = self.free.get(index).copied();
+        let double_free = prev.map_or(false, |(_, prev_end)| prev_end > start)
+            || next.map_or(false, |(next_start, _)| end > next_start);
+        assert!(!double_free, "double free of virtual range at {:?}", start);
+
+        match (
+            prev.filter(|&(_, prev_end)| prev_end == start),
+            next.filter(|&(next_start, _)| next_start == end),
+        ) {
+            (Some(_), Some((_, next_end))) => {
+                self.free[index - 1].1 = next_end;
+                self.free.remove(index);
+            }
+            (Some(_), None) => self.free[index - 1].1 = end,
+            (None, Some(_)) => self.free[index].0 = start,
+            (None, None) => {
+                debug_assert!(self.free.len() < self.free.capacity());
+                self.free.insert(index, (start, end));
+            }
+        }
+        self.allocated -= 1;
+    }
+
+    /// The number of bytes that haven't been handed out
+    pub fn free_bytes(&self) -> u64 {
+        self.free.iter().map(|&(start, end)| end - start).sum()
+    }
+
+    /// The number of separate free ranges
+    pub fn free_ranges(&self) -> usize {
+        self.free.len()
+    }
+}
+
+fn page_align(size: u64) -> u64 {
+    (size + Size4KiB::SIZE - 1) & !(Size4KiB::SIZE - 1)
+}
+
+/// A mapping made by [`vmalloc`] or [`vmap`]
+struct Region {
+    /// Bytes that are mapped, starting one page above the reserved range
+    size: u64,
+    /// True if the frames came from the frame allocator and are freed with the region
+    owns_frames: bool,
+}
+
+struct Vmalloc {
+    ranges: VirtualRangeAllocator,
+    /// Every live region with its first mapped address, sorted by that address
+    regions: Vec<(VirtAddr, Region)>,
+}
+
+static VMALLOC: spin::Mutex<Option<Vmalloc>> = spin::Mutex::new(None);
+
+fn with_vmalloc<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut Vmalloc) -> R,
+{
+    crate::sys::without_interrupts(|| {
+        let mut vmalloc = VMALLOC.lock();
+        f(vmalloc.get_or_insert_with(|| Vmalloc {
+            ranges: VirtualRangeAllocator::new(
+                VirtAddr::new(VMALLOC_START),
+                VirtAddr::new(VMALLOC_END),
+            ),
+            regions: Vec::new(),
+        }))
+    })
+}
+
+/// Reserves room for `size` bytes plus an unmapped guard page below them, and maps each page with
+/// the frame returned by `frame_for`.
+///
+/// Returns the first mapped address, or `None` if the range or a frame could not be allocated
+fn map_region(
+    size: u64,
+    flags: PageTableFlags,
+    owns_frames: bool,
+    mut frame_for: impl FnMut(u64) -> Option<PhysFrame>,
+) -> Option<VirtAddr> {
+    let size = page_align(size);
+    if size == 0 {
+        return None;
+    }
+    with_vmalloc(|vmalloc| {
+        let guard = vmalloc.ranges.allocate(size + Size4KiB::SIZE)?;
+        if vmalloc.regions.try_reserve(1).is_err() {
+            vmalloc.ranges.deallocate(guard, size + Size4KiB::SIZE);
+            return None;
+        }
+        let start = guard + Size4KiB::SIZE;
+        let first_page = Page::<Size4KiB>::containing_address(start);
+
+        let pages = size / Size4KiB::SIZE;
+        for i in 0..pages {
+            let mapped = frame_for(i).map_or(false, |frame| {
+                // SAFETY: The page is inside a range that was just reserved
+                let result = mapper().with(|mapper| unsafe {
+                    mapper.map_to(
+                        first_page + i,
+                        frame,
+                        flags,
+                        &mut frame_allocator_for(FrameOwner::PageTable),
+                    )
+                });
+                match result {
+                    Ok(flush) => {
+                        flush.flush();
+                        true
+                    }
+                    Err(_) => {
+                        if owns_frames {
+                            // SAFETY: The frame was never mapped
+                            unsafe { frame_allocator().deallocate_frame(frame) };
+                        }
+                        false
+                    }
+                }
+            });
+            if !mapped {
+                // SAFETY: Only the pages mapped above are unmapped, after which nothing in the
+                // range is mapped
+                unsafe {
+                    unmap_pages(first_page, i, owns_frames);
+                    free_empty_tables(start, start + size);
+                }
+                vmalloc.ranges.deallocate(guard, size + Size4KiB::SIZE);
+                return None;
+            }
+        }
+
+        let index = vmalloc.regions.partition_point(|&(other, _)| other < start);
+        vmalloc
+            .regions
+            .insert(index, (start, Region { size, owns_frames }));
+        Some(start)
+    })
+}
+
+/// Unmaps `count` pages starting at `first`, giving their frames back if `owns_frames` is set
+///
+/// # Safety
+/// The pages must be mapped and no longer used, and the vmalloc lock must be held
+unsafe fn unmap_pages(first: Page, count: u64, owns_frames: bool) {
+    for i in 0..count {
+        let frame = mapper().with(|mapper| {
+            let (frame, flush) = mapper.unmap(first + i).expect("vmalloc page is mapped");
+            flush.flush();
+            frame
+        });
+        if owns_frames {
+            // SAFETY: The frame was allocated by `vmalloc` and is no longer mapped
+            unsafe { frame_allocator().deallocate_frame(frame) };
+        }
+    }
+}
+
+/// Frees the level 2 and level 1 tables below `start..end` that are empty after the range was
+/// unmapped. The level 3 table of the vmalloc area is never freed, as every address space shares
+/// it
+///
+/// # Safety
+/// Nothing in the range may be mapped anymore, and the vmalloc lock must be held
+unsafe fn free_empty_tables(start: VirtAddr, end: VirtAddr) {
+    let level_4 = mapper().with(|mapper| mapper.level_4_table() as *mut PageTable);
+    // SAFETY: The vmalloc area's level 3 table is created at boot, and its lower tables are only
+    // changed with the vmalloc lock held
+    let level_3 = unsafe { next_table(&mut (*level_4)[start.p4_index()]) }
+        .expect("vmalloc level 3 table exists");
+
+    // Each level 1 table covers 2MiB, and each level 2 table 1GiB
+    let mut addr = start.align_down(Size2MiB::SIZE);
+    while addr < end {
+        // SAFETY: Same as above
+        if let Some(level_2) = unsafe { next_table(&mut level_3[addr.p3_index()]) } {
+            // SAFETY: Guaranteed by the caller
+            unsafe { free_if_empty(&mut level_2[addr.p2_index()], addr) };
+        }
+        addr += Size2MiB::SIZE;
+    }
+    let mut addr = start.align_down(Size1GiB::SIZE);
+    while addr < end {
+        // SAFETY: Guaranteed by the caller
+        unsafe { free_if_empty(&mut level_3[addr.p3_index()], addr) };
+        addr += Size1GiB::SIZE;
+    }
+}
+
+/// The table that `entry` points to, if it is present
+///
+/// # Safety
+/// The table must not be in use anywhere else
+unsafe fn next_table<'a>(entry: &mut PageTableEntry) -> Option<&'a mut PageTable> {
+    if entry.is_unused() {
+        return None;
+    }
+    // SAFETY: The entry is present, so it points at a table, which the caller lets us use
+    Some(unsafe { &mut *phys_to_virt(entry.addr()).as_mut_ptr::<PageTable>() })
+}
+
+/// Removes the table that `entry` points to and frees its frame if every entry in it is unused.
+/// `addr` is an address that the entry covers
+///
+/// # Safety
+/// The table must not be in use anywhere else
+unsafe fn free_if_empty(entry: &mut PageTableEntry, addr: VirtAddr) {
+    // SAFETY: Guaranteed by the caller
+    let Some(table) = (unsafe { next_table(entry) }) else {
+        return;
+    };
+    if !table.iter().all(PageTableEntry::is_unused) {
+        return;
+    }
+    let frame = PhysFrame::containing_address(entry.addr());
+    entry.set_unused();
+    // Drops the cached walk through the removed table
+    tlb::flush(addr);
+    // SAFETY: The table is empty and nothing points at it anymore
+    unsafe { frame_allocator_for(FrameOwner::PageTable).deallocate_frame(frame) };
+}
+
+/// Maps `size` bytes, rounded up to whole pages, of zeroed and writable kernel memory.
+///
+/// The pages don't need to be physically contiguous. There is always an unmapped guard page below
+/// the returned range. Returns `None` if there isn't enough memory
+pub fn vmalloc(size: usize) -> Option<VirtAddr> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    map_region(size as u64, flags, true, |_| {
+        let frame = frame_allocator().allocate_frame()?;
+        // SAFETY: The frame was just allocated, so nothing else refers to it
+        unsafe {
+            ptr::write_bytes(
+                phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(),
+                0,
+                Size4KiB::SIZE as usize,
+            )
+        };
+        Some(frame)
+    })
+}
+
+/// Maps the physical `frames` into the vmalloc area with `flags`, for example to access a device's
+/// registers. The frames are not freed by [`vfree`].
+///
+/// # Safety
+/// Accessing `frames` through the new mapping must not break any of the kernel's invariants
+pub unsafe fn vmap(frames: PhysFrameRange, flags: PageTableFlags) -> Option<VirtAddr> {
+    let size = (frames.end - frames.start) * Size4KiB::SIZE;
+    map_region(size, flags, false, |i| Some(frames.start + i))
+}
+
+/// Unmaps a range returned by [`vmalloc`] or [`vmap`], freeing its frames if they came from
+/// [`vmalloc`]
+///
+/// # Safety
+/// Nothing may use the range after it is freed
+pub unsafe fn vfree(addr: VirtAddr) {
+    with_vmalloc(|vmalloc| {
+        let index = vmalloc
+            .regions
+            .binary_search_by_key(&addr, |&(start, _)| start)
+            .unwrap_or_else(|_| panic!("vfree of {:?}, which was not vmalloced", addr));
+        let (_, region) = vmalloc.regions.remove(index);
+        let first_page = Page::containing_address(addr);
+        // SAFETY: The region was mapped by `map_region`, and the caller guarantees that it is no
+        // longer used
+        unsafe {
+            unmap_pages(first_page, region.size / Size4KiB::SIZE, region.owns_frames);
+            free_empty_tables(addr, addr + region.size);
+        }
+        let guard = addr - Size4KiB::SIZE;
+        vmalloc
+            .ranges
+            .deallocate(guard, region.size + Size4KiB::SIZE);
+    })
+}
+
+/// The number of bytes of the vmalloc area that are not reserved
+pub fn vmalloc_free_bytes() -> u64 {
+    with_vmalloc(|vmalloc| vmalloc.ranges.free_bytes())
+}
@@ -0,0 +1,298 @@
+use alloc::collections::VecDeque;
+use core::{
+    arch::asm,
+    cell::Cell,
+    num::NonZeroU64,
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use raw_cpuid::CpuId;
+use x86_64::{
+    instructions::segmentation::GS,
+    registers::{
+        control::{Cr4, Cr4Flags},
+        model_specific::{GsBase, KernelGsBase},
+        segmentation::Segment64,
+    },
+    structures::{idt::InterruptStackFrame, paging::PhysFrame, tss::TaskStateSegment},
+    VirtAddr,
+};
+
+use crate::{memory::KernelStack, process::Process};
+
+/// The data that belongs to one CPU. While the CPU runs kernel code its GS base points here, and
+/// while it runs user code the pointer waits in KernelGsBase for the `swapgs` on the way back in.
+///
+/// Fields are reached through [`percpu!`](crate::percpu!). `syscall_handler` uses the first two
+/// from assembly, so their offsets must not change
+#[repr(C)]
+pub struct PerCpu {
+    /// RSP of the kernel stack (or user stack if handling syscall)
+    pub kernel_rsp: Cell<Option<NonZeroU64>>,
+    /// RSP of the user task that initialized this syscall
+    pub user_tmp_rsp: Cell<Option<NonZeroU64>>,
+    /// Points back at this area, so that it can be found with a single GS relative load
+    this: Cell<*const PerCpu>,
+    /// The number that the CPU is known by. The bootstrap processor is 0
+    pub cpu: usize,
+    /// The TSS that holds this CPU's interrupt stacks
+    pub tss: *const TaskStateSegment,
+    /// How many interrupt handlers are running on this CPU, nested inside each other
+    pub interrupt_depth: Cell<usize>,
+    /// The level 4 table of the address space that is active on this CPU, or `None` while it runs
+    /// on the kernel's own tables. Kept up to date by
+    /// [`AddressSpace::activate`](crate::memory::AddressSpace::activate)
+    pub address_space: Cell<Option<PhysFrame>>,
+    /// The process that is running on this CPU, whose address space is active
+    pub current: spin::Mutex<Option<Process>>,
+    /// Processes waiting to run on this CPU, in the order they will be resumed
+    pub run_queue: spin::Mutex<VecDeque<Process>>,
+    /// The syscall stack of the last process that exited on this CPU
+    pub exited_stack: spin::Mutex<Option<KernelStack>>,
+}
+
+// SAFETY: Each CPU only uses its own area, which it reaches through GS. Fields that other CPUs may
+// touch are behind locks, and the raw pointers only point at data that lives forever
+unsafe impl Send for PerCpu {}
+unsafe impl Sync for PerCpu {}
+
+/// Offsets of the fields that are used from assembly, which `percpu_offsets` checks
+pub const KERNEL_RSP_OFFSET: usize = 0;
+pub const USER_TMP_RSP_OFFSET: usize = 8;
+const THIS_OFFSET: usize = 16;
+
+impl PerCpu {
+    /// Creates the area of the CPU numbered `cpu`, whose interrupt stacks are in `tss`
+    pub fn new(cpu: usize, tss: *const TaskStateSegment) -> PerCpu {
+        PerCpu {
+            kernel_rsp: Cell::new(None),
+            user_tmp_rsp: Cell::new(None),
+            this: Cell::new(ptr::null()),
+            cpu,
+            tss,
+            interrupt_depth: Cell::new(0),
+            address_space: Cell::new(None),
+            current: spin::Mutex::new(None),
+            run_queue: spin::Mutex::new(VecDeque::new()),
+            exited_stack: spin::Mutex::new(None),
+        }
+    }
+
+    /// Makes this the area of the calling CPU, by pointing its GS base here
+    ///
+    /// # Safety
+    /// No other CPU may use this area, and nothing may still be using the calling CPU's previous
+    /// one. `syscall::init` must have run on the calling CPU
+    pub unsafe fn install(&'static self) {
+        self.this.set(self);
+        // SAFETY: Guaranteed by the caller
+        unsafe { set_gs_base(VirtAddr::from_ptr(self)) };
+    }
+}
+
+lazy_static::lazy_static! {
+    /// The area of the bootstrap processor, which is needed before the heap exists
+    static ref BOOTSTRAP: PerCpu = PerCpu::new(0, crate::gdt::tss());
+}
+
+/// Installs the bootstrap processor's area. Called by [`crate::init`], after `syscall::init`
+pub fn init() {
+    // SAFETY: This runs once on the bootstrap processor, before anything used GS
+    unsafe { BOOTSTRAP.install() };
+}
+
+/// Set if the GS base is accessed with the FSGSBASE instructions, and not through MSRs
+static FSGSBASE: AtomicBool = AtomicBool::new(false);
+
+/// Turns on the `rdgsbase` and `wrgsbase` instructions if the CPU has them, as they are faster
+/// than the MSRs. Called on every CPU by `syscall::init`
+pub(crate) fn enable_fsgsbase() {
+    let supported = CpuId::new()
+        .get_extended_feature_info()
+        .map_or(false, |info| info.has_fsgsbase());
+    if supported {
+        // SAFETY: The instructions only give access to bases that the MSRs already expose
+        unsafe { Cr4::update(|cr4| cr4.insert(Cr4Flags::FSGSBASE)) };
+    }
+    FSGSBASE.store(supported, Ordering::Relaxed);
+}
+
+/// Returns true if the GS base is accessed with the FSGSBASE instructions, or false if the CPU
+/// doesn't have them and the `IA32_GS_BASE` MSR is used instead
+pub fn fsgsbase_enabled() -> bool {
+    FSGSBASE.load(Ordering::Relaxed)
+}
+
+/// The GS base of the calling CPU
+pub fn gs_base() -> VirtAddr {
+    if fsgsbase_enabled() {
+        GS::read_base()
+    } else {
+        GsBase::read()
+    }
+}
+
+/// Sets the GS base of the calling CPU
+///
+/// # Safety
+/// While the CPU runs kernel code, GS must point at its [`PerCpu`] area
+pub unsafe fn set_gs_base(base: VirtAddr) {
+    if fsgsbase_enabled() {
+        // SAFETY: The instruction is enabled, and the caller guarantees the rest
+        unsafe { GS::write_base(base) };
+    } else {
+        GsBase::write(base);
+    }
+}
+
+/// The base that `swapgs` exchanges with the GS base. Only the `IA32_KERNEL_GS_BASE` MSR holds it
+pub fn kernel_gs_base() -> VirtAddr {
+    KernelGsBase::read()
+}
+
+/// Sets the base that `swapgs` exchanges with the GS base
+///
+/// # Safety
+/// While the CPU runs user code, this must point at its [`PerCpu`] area
+pub unsafe fn set_kernel_gs_base(base: VirtAddr) {
+    KernelGsBase::write(base);
+}
+
+/// The area of the calling CPU. Kernel code never moves to another CPU, so the reference stays
+/// valid for whoever holds it
+pub fn this() -> &'static PerCpu {
+    let this: *const PerCpu;
+    // SAFETY: The kernel's GS base always points at an installed area, which stays alive for as
+    // long as the kernel runs
+    unsafe {
+        asm!(
+            "mov {}, gs:[{offset}]",
+            out(reg) this,
+            offset = const(THIS_OFFSET),
+            options(nostack, preserves_flags, readonly)
+        );
+        &*this
+    }
+}
+
+/// Reaches a field of the calling CPU's [`PerCpu`](crate::percpu::PerCpu) area, for example
+/// `percpu!(interrupt_depth).get()`. Evaluates to a `&'static` reference to the field
+#[macro_export]
+macro_rules! percpu {
+    ($field:ident) => {
+        &$crate::percpu::this().$field
+    };
+}
+
+/// Counts an interrupt handler in [`PerCpu::interrupt_depth`] while it lives. Interrupts from user
+/// mode leave the user's GS base in place, so it is swapped for the kernel's for that time too
+pub struct InterruptGuard {
+    from_user: bool,
+}
+
+impl InterruptGuard {
+    /// Must come first in an interrupt handler, before anything uses the per-CPU area
+    pub fn enter(frame: &InterruptStackFrame) -> InterruptGuard {
+        Self::enter_from(frame.code_segment & 3 == 3)
+    }
+
+    fn enter_from(from_user: bool) -> InterruptGuard {
+        if from_user {
+            // SAFETY: We came from user mode, so the kernel's GS base is in KernelGsBase
+            unsafe { GS::swap() };
+        }
+        let depth = percpu!(interrupt_depth);
+        depth.set(depth.get() + 1);
+        InterruptGuard { from_user }
+    }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        let depth = percpu!(interrupt_depth);
+        depth.set(depth.get() - 1);
+        if self.from_user {
+            // SAFETY: The handler returns to user mode, which expects its own GS base back
+            unsafe { GS::swap() };
+        }
+    }
+}
+
+/// Returns true if the calling CPU is handling an interrupt
+pub fn in_interrupt() -> bool {
+    percpu!(interrupt_depth).get() > 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::{size_of, size_of_val, transmute, MaybeUninit};
+    use memoffset::offset_of;
+
+    #[test_case]
+    fn percpu_offsets() {
+        let data = PerCpu::new(0, ptr::null());
+        // We depend on `Option<NonZeroU64>` having the niche optimization, (None == 0)
+        // so that we can write to this in assembly and still have correctness
+        assert_eq!(size_of_val(&data.kernel_rsp), size_of::<u64>());
+        assert_eq!(
+            unsafe { MaybeUninit::<Option<NonZeroU64>>::zeroed().assume_init() },
+            None
+        );
+        // we depend on this in syscall handler
+        assert_eq!(offset_of!(PerCpu, kernel_rsp), KERNEL_RSP_OFFSET);
+        assert_eq!(offset_of!(PerCpu, user_tmp_rsp), USER_TMP_RSP_OFFSET);
+        // and on this in `this`
+        assert_eq!(offset_of!(PerCpu, this), THIS_OFFSET);
+        assert_eq!(size_of_val(&data.this), size_of::<u64>());
+
+        assert_eq!(
+            unsafe { transmute::<_, Option<NonZeroU64>>(10u64) },
+            Some(NonZeroU64::new(10).unwrap())
+        );
+    }
+
+    #[test_case]
+    fn this_is_installed_area() {
+        assert_eq!(this() as *const PerCpu as u64, gs_base().as_u64());
+        assert_eq!(percpu!(cpu), &0);
+    }
+
+    #[test_case]
+    fn interrupt_from_user_swaps_gs() {
+        let area = VirtAddr::from_ptr(this());
+        let (inside, outside, kernel) = crate::sys::without_interrupts(|| unsafe {
+            // Pretend to be in user mode, with the area waiting in KernelGsBase
+            let user = kernel_gs_base();
+            set_kernel_gs_base(area);
+            set_gs_base(VirtAddr::zero());
+
+            let guard = InterruptGuard::enter_from(true);
+            let inside = (gs_base(), in_interrupt());
+            drop(guard);
+            let outside = gs_base();
+            let kernel = kernel_gs_base();
+
+            set_gs_base(area);
+            set_kernel_gs_base(user);
+            (inside, outside, kernel)
+        });
+        assert_eq!(inside, (area, true));
+        assert_eq!(outside, VirtAddr::zero());
+        assert_eq!(kernel, area);
+        assert!(!in_interrupt());
+    }
+
+    #[test_case]
+    fn interrupt_depth_nests() {
+        assert!(!in_interrupt());
+        let outer = InterruptGuard::enter_from(false);
+        let inner = InterruptGuard::enter_from(false);
+        assert_eq!(percpu!(interrupt_depth).get(), 2);
+        drop(inner);
+        assert!(in_interrupt());
+        drop(outer);
+        assert!(!in_interrupt());
+    }
+}
@@ -0,0 +1,120 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use x86_64::VirtAddr;
+
+use super::USER_STACK_TOP;
+use crate::{memory::USER_START, random::random_below};
+
+/// Where the image is loaded when ASLR is disabled. Syncs up with the constant in gdb.sh so gdb
+/// knows where to look when we're debugging the user program
+pub const DEFAULT_IMAGE_BASE: u64 = 0x1000_0066_0000;
+
+/// Start of the zone that the heap base is picked from
+pub const HEAP_ZONE: u64 = 0x1800_0000_0000;
+
+/// Start of the zone that the mmap base is picked from
+pub const MMAP_ZONE: u64 = 0x2000_0000_0000;
+
+/// How far the image, heap and mmap bases may be moved from the start of their zones (1TiB)
+const ZONE_RANDOMIZATION: u64 = 1 << 40;
+
+/// How far the stack top may be moved down from [`USER_STACK_TOP`] (16GiB)
+const STACK_RANDOMIZATION: u64 = 1 << 34;
+
+const PAGE_SIZE: u64 = 4096;
+
+static ASLR: AtomicBool = AtomicBool::new(!cfg!(feature = "no_aslr"));
+
+/// Returns true if new processes get a randomized [`MemoryLayout`].
+///
+/// ASLR is on unless the kernel is built with the `no_aslr` feature. This can't be a boot option,
+/// as the bootloader doesn't pass a command line to the kernel, so it is a cargo feature instead
+pub fn aslr_enabled() -> bool {
+    ASLR.load(Ordering::Relaxed)
+}
+
+/// Turns address space layout randomization on or off for processes created from now on
+pub fn set_aslr_enabled(enabled: bool) {
+    ASLR.store(enabled, Ordering::Relaxed);
+}
+
+/// Where the regions of a process's address space start
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryLayout {
+    /// Where the first executable segment of the image is loaded
+    pub image_base: VirtAddr,
+    /// Where the heap starts
+    pub heap_base: VirtAddr,
+    /// Where anonymous and shared mappings are placed from
+    pub mmap_base: VirtAddr,
+    /// The user stack grows down from here
+    pub stack_top: VirtAddr,
+}
+
+impl MemoryLayout {
+    /// The layout every process gets when ASLR is disabled
+    pub const DEFAULT: MemoryLayout = MemoryLayout {
+        image_base: VirtAddr::new_truncate(DEFAULT_IMAGE_BASE),
+        heap_base: VirtAddr::new_truncate(HEAP_ZONE),
+        mmap_base: VirtAddr::new_truncate(MMAP_ZONE),
+        stack_top: VirtAddr::new_truncate(USER_STACK_TOP),
+    };
+
+    /// Picks the layout for a new process, moving each region by a random number of pages if
+    /// ASLR is enabled
+    pub fn new() -> Self {
+        if !aslr_enabled() {
+            return Self::DEFAULT;
+        }
+        let offset = |range: u64| random_below(range / PAGE_SIZE) * PAGE_SIZE;
+        MemoryLayout {
+            image_base: VirtAddr::new(USER_START + offset(ZONE_RANDOMIZATION)),
+            heap_base: VirtAddr::new(HEAP_ZONE + offset(ZONE_RANDOMIZATION)),
+            mmap_base: VirtAddr::new(MMAP_ZONE + offset(ZONE_RANDOMIZATION)),
+            stack_top: VirtAddr::new(USER_STACK_TOP - offset(STACK_RANDOMIZATION)),
+        }
+    }
+}
+
+impl Default for MemoryLayout {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn random_layouts_stay_in_their_zones() {
+        let enabled = aslr_enabled();
+        set_aslr_enabled(true);
+        for _ in 0..64 {
+            let layout = MemoryLayout::new();
+            let in_zone = |addr: VirtAddr, start: u64, len: u64| {
+                addr.is_aligned(PAGE_SIZE) && (start..start + len).contains(&addr.as_u64())
+            };
+            assert!(in_zone(layout.image_base, USER_START, ZONE_RANDOMIZATION));
+            assert!(in_zone(layout.heap_base, HEAP_ZONE, ZONE_RANDOMIZATION));
+            assert!(in_zone(layout.mmap_base, MMAP_ZONE, ZONE_RANDOMIZATION));
+            let stack_zone = USER_STACK_TOP - STACK_RANDOMIZATION + PAGE_SIZE;
+            assert!(in_zone(layout.stack_top, stack_zone, STACK_RANDOMIZATION));
+        }
+        // 2^28 possible image bases make a collision very unlikely
+        assert_ne!(MemoryLayout::new(), MemoryLayout::new());
+        set_aslr_enabled(enabled);
+    }
+
+    #[test_case]
+    fn disabling_aslr_gives_fixed_layout() {
+        let enabled = aslr_enabled();
+        set_aslr_enabled(false);
+        assert_eq!(MemoryLayout::new(), MemoryLayout::DEFAULT);
+        assert_eq!(
+            MemoryLayout::DEFAULT.image_base.as_u64(),
+            DEFAULT_IMAGE_BASE
+        );
+        set_aslr_enabled(enabled);
+    }
+}
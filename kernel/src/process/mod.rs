@@ -1,5 +1,366 @@
+mod layout;
+
+pub use layout::{
+    aslr_enabled, set_aslr_enabled, MemoryLayout, DEFAULT_IMAGE_BASE, HEAP_ZONE, MMAP_ZONE,
+};
+
+use alloc::collections::TryReserveError;
+use core::{
+    cmp, fmt,
+    num::NonZeroU64,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use x86_64::{
+    structures::paging::{mapper::MapToError, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+use crate::{
+    elf::MapError,
+    memory::{
+        frames_owned_by, AddressSpace, FrameOwner, KernelStack, SharedMemoryHandles, StackKind,
+        Vma, VmaError, VmaKind, USER_END,
+    },
+    percpu, println,
+    syscall::{handler::enter_user_context, UserContext},
+};
+
+/// The highest the user stack may start, leaving one unmapped page before the end of the user half.
+/// With ASLR the stack top is moved down from here by a random amount
+pub const USER_STACK_TOP: u64 = USER_END - 4096;
+
+/// The largest the user stack may grow to. The page below it is left unmapped so that overflows
+/// fault instead of running into other mappings
+pub const USER_STACK_LIMIT: u64 = 1024 * 1024;
+
+/// Size of the kernel stack that each process's syscalls run on
+pub const SYSCALL_STACK_SIZE: usize = 1024 * 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pid(u64);
+
+impl Pid {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
 
 pub struct Process {
-    // registers: ???,
+    pid: Pid,
+    pub address_space: AddressSpace,
+    /// Handles to shared memory objects, which are closed when the process exits
+    pub shared_memory: SharedMemoryHandles,
+    /// Where the regions of the address space start
+    layout: MemoryLayout,
+    /// The end of the heap, which starts out at the heap base of the layout
+    brk: VirtAddr,
+    /// The stack that this process's syscalls run on
+    kernel_stack: KernelStack,
+    entry_point: VirtAddr,
+    /// Where the process continues when it is next run
+    context: UserContext,
+    /// The return value of the syscall that the process is resumed from
+    syscall_return: u64,
+}
+
+impl Process {
+    /// Creates a new process running the elf file in `bin`, with a lazily backed stack. The image
+    /// and stack are placed according to a new [`MemoryLayout`]. Fails if `bin` can't be loaded or
+    /// there isn't enough memory
+    pub fn spawn(bin: &[u8]) -> Result<Self, MapError> {
+        let pid = Pid::new();
+        let layout = MemoryLayout::new();
+        let mut address_space = AddressSpace::new()?;
+        address_space.set_owner(pid.as_u64());
+
+        let stack_flags = PageTableFlags::PRESENT
+            | PageTableFlags::USER_ACCESSIBLE
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::NO_EXECUTE;
+        let stack = Vma::new(
+            layout.stack_top - USER_STACK_LIMIT,
+            layout.stack_top,
+            stack_flags,
+            VmaKind::Stack,
+        );
+        address_space.add_vma(stack)?;
+
+        let elf = crate::elf::load(bin, &mut address_space, layout.image_base)?;
+        Ok(Process {
+            pid,
+            address_space,
+            shared_memory: SharedMemoryHandles::new(pid.as_u64()),
+            layout,
+            brk: layout.heap_base,
+            kernel_stack: syscall_stack(pid)?,
+            entry_point: elf.entry_point,
+            context: UserContext::new(elf.entry_point, layout.stack_top),
+            syscall_return: 0,
+        })
+    }
+
+    /// Creates a child process that shares all of this process's memory copy-on-write, and
+    /// resumes from `context` with a syscall return value of 0. The child gets its own copy of
+    /// every shared memory handle that this process has open
+    pub fn fork(&mut self, context: &UserContext) -> Result<Self, MapToError<Size4KiB>> {
+        let pid = Pid::new();
+        let mut address_space = self.address_space.fork()?;
+        address_space.set_owner(pid.as_u64());
+        let shared_memory = self
+            .shared_memory
+            .fork(pid.as_u64())
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        Ok(Process {
+            pid,
+            address_space,
+            shared_memory,
+            layout: self.layout,
+            brk: self.brk,
+            kernel_stack: syscall_stack(pid)?,
+            entry_point: self.entry_point,
+            context: *context,
+            syscall_return: 0,
+        })
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn entry_point(&self) -> VirtAddr {
+        self.entry_point
+    }
+
+    pub fn stack_top(&self) -> VirtAddr {
+        self.layout.stack_top
+    }
+
+    pub fn layout(&self) -> &MemoryLayout {
+        &self.layout
+    }
+
+    /// The end of the heap
+    pub fn brk(&self) -> VirtAddr {
+        self.brk
+    }
+
+    /// Moves the end of the heap to `brk`, adding or unmapping whole pages at the end of it. The
+    /// heap may grow from the heap base up to the mmap base, as long as nothing else is mapped
+    /// in the way. On failure the end of the heap stays where it was
+    ///
+    /// The heap is a single VMA whose end is moved in place, which is only added when the heap
+    /// grows from nothing, or when the process unmapped or changed the end of it
+    pub fn set_brk(&mut self, brk: VirtAddr) -> Result<(), VmaError> {
+        if brk < self.layout.heap_base || brk > self.layout.mmap_base {
+            return Err(VmaError::InvalidRange);
+        }
+        let base = self.layout.heap_base.align_up(4096u64);
+        let old_end = self.brk.align_up(4096u64);
+        let new_end = brk.align_up(4096u64);
+        let last = (old_end > base)
+            .then(|| self.address_space.vma(old_end - 1u64))
+            .flatten();
+        let resizable = last.map_or(false, |vma| {
+            vma.kind == VmaKind::Heap && vma.end == old_end && vma.start < new_end
+        });
+        match new_end.cmp(&old_end) {
+            cmp::Ordering::Greater if resizable => {
+                self.address_space.resize_vma(old_end, new_end)?;
+            }
+            cmp::Ordering::Greater => {
+                let flags = PageTableFlags::PRESENT
+                    | PageTableFlags::USER_ACCESSIBLE
+                    | PageTableFlags::WRITABLE
+                    | PageTableFlags::NO_EXECUTE;
+                let heap = Vma::new(old_end, new_end, flags, VmaKind::Heap);
+                self.address_space.add_vma(heap)?;
+            }
+            cmp::Ordering::Less if resizable => {
+                self.address_space.resize_vma(old_end, new_end)?;
+            }
+            cmp::Ordering::Less => self.address_space.unmap_range(new_end, old_end)?,
+            cmp::Ordering::Equal => {}
+        }
+        self.brk = brk;
+        Ok(())
+    }
+}
+
+fn syscall_stack(pid: Pid) -> Result<KernelStack, MapToError<Size4KiB>> {
+    let kind = StackKind::Syscall { pid: pid.as_u64() };
+    KernelStack::new(SYSCALL_STACK_SIZE, kind).ok_or(MapToError::FrameAllocationFailed)
+}
+
+/// Makes `process` the running process on this CPU and switches to its address space.
+///
+/// The previous process (if any) is dropped, freeing all of its memory
+pub fn make_current(process: Process) {
+    crate::sys::without_interrupts(|| {
+        let mut current = percpu!(current).lock();
+        // SAFETY: The process is stored in the per-CPU area right after, which keeps the address
+        // space alive for as long as it is active
+        unsafe { process.address_space.activate() };
+        *current = Some(process);
+    })
+}
+
+/// Calls `f` with the process running on this CPU, or returns `None` if no process is running
+///
+/// Interrupts are disabled while `f` runs, and `f` must not call back into `with_current`
+pub fn with_current<F, R>(f: F) -> Option<R>
+where
+    F: FnOnce(&mut Process) -> R,
+{
+    crate::sys::without_interrupts(|| percpu!(current).lock().as_mut().map(f))
+}
+
+/// Like [`with_current`], but returns `None` instead of spinning forever if the current process is
+/// already borrowed. Used by fault handlers that might interrupt a `with_current` call
+pub fn try_with_current<F, R>(f: F) -> Option<R>
+where
+    F: FnOnce(&mut Process) -> R,
+{
+    crate::sys::without_interrupts(|| percpu!(current).try_lock()?.as_mut().map(f))
+}
+
+/// Switches to `process` and resumes it in user mode where it left off
+pub fn run(process: Process) -> ! {
+    let context = process.context;
+    let syscall_return = process.syscall_return;
+    percpu!(kernel_rsp).set(NonZeroU64::new(process.kernel_stack.top().as_u64()));
+    // Whichever interrupt handler we were called from never returns
+    percpu!(interrupt_depth).set(0);
+    make_current(process);
+    // SAFETY: `make_current` activated the process's address space, and whatever called us is
+    // done with the kernel stack because we never return
+    unsafe { enter_user_context(&context, syscall_return) }
+}
+
+/// Makes room for one more process in this CPU's run queue, so that queueing it in
+/// [`suspend_current`] doesn't need to allocate
+pub fn reserve_run_queue() -> Result<(), TryReserveError> {
+    crate::sys::without_interrupts(|| percpu!(run_queue).lock().try_reserve(1))
+}
+
+/// Takes the current process off the CPU and queues it to resume from `context`, with
+/// `syscall_return` as the result of the syscall it is stopped in
+///
+/// The process's address space stays active until another process is run. Room for it in the
+/// run queue should be made with [`reserve_run_queue`] first
+pub fn suspend_current(context: &UserContext, syscall_return: u64) {
+    crate::sys::without_interrupts(|| {
+        let mut process = percpu!(current)
+            .lock()
+            .take()
+            .expect("no process to suspend");
+        process.context = *context;
+        process.syscall_return = syscall_return;
+        percpu!(run_queue).lock().push_back(process);
+    })
+}
+
+/// Ends the current process with `status`, giving all of its memory back, and runs the next ready
+/// process
+pub fn exit_current(status: ExitStatus) -> ! {
+    let pid = crate::sys::without_interrupts(|| {
+        let process = percpu!(current).lock().take()?;
+        // We are most likely still running on the process's syscall stack, so it can only be
+        // freed once the next process has exited. The previous one is no longer in use
+        *percpu!(exited_stack).lock() = Some(process.kernel_stack);
+        Some(process.pid)
+    });
+
+    if let Some(pid) = pid {
+        ended(pid, status);
+    }
+
+    if let Some(next) = crate::sys::without_interrupts(|| percpu!(run_queue).lock().pop_front()) {
+        run(next);
+    }
+
+    crate::sys::enable_interrupts();
+    // Nothing is left to run on this core so this will never return
+    crate::sys::hlt_loop();
+}
+
+/// Frees memory for a page fault that couldn't be backed by killing the waiting process with the
+/// most resident pages, if it has more than the current process.
+///
+/// Returns false if the current process is the largest one, which leaves it to the caller to kill
+/// the current process instead
+pub fn kill_larger_process() -> bool {
+    let victim = crate::sys::without_interrupts(|| {
+        let current = percpu!(current)
+            .lock()
+            .as_mut()
+            .map_or(0, |process| process.address_space.resident_pages());
+        let mut ready = percpu!(run_queue).lock();
+        let (index, pages) = ready
+            .iter_mut()
+            .map(|process| process.address_space.resident_pages())
+            .enumerate()
+            .max_by_key(|&(_, pages)| pages)?;
+        if pages <= current {
+            return None;
+        }
+        ready.remove(index).map(|process| (process, pages))
+    });
+
+    let Some((process, pages)) = victim else {
+        return false;
+    };
+    let pid = process.pid;
+    println!(
+        "out of memory: killed pid {} with {} resident pages",
+        pid, pages
+    );
+    // Its kernel stack isn't in use, as waiting processes start over at the top of it
+    drop(process);
+    ended(pid, ExitStatus::Killed);
+    true
+}
+
+/// How a process ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// The process called exit with this code
+    Exited(u8),
+    /// The kernel killed the process, because of a fault it couldn't resolve or to free memory
+    Killed,
+}
+
+/// Called with every process that ends, see [`set_exit_hook`]
+pub type ExitHook = fn(Pid, ExitStatus);
+
+static EXIT_HOOK: spin::Mutex<Option<ExitHook>> = spin::Mutex::new(None);
+
+/// Calls `hook` every time a process ends, once all of its memory is given back. Lets tests see
+/// how the processes that they run end
+pub fn set_exit_hook(hook: ExitHook) {
+    crate::sys::without_interrupts(|| *EXIT_HOOK.lock() = Some(hook));
+}
 
+/// Reports the process `pid`, whose memory is gone, to the exit hook. Logs any frames still
+/// accounted to it, which were never freed
+fn ended(pid: Pid, status: ExitStatus) {
+    let leaked = frames_owned_by(FrameOwner::User { pid: pid.as_u64() });
+    if leaked > 0 {
+        println!("pid {} leaked {} frames", pid, leaked);
+    }
+    let hook = crate::sys::without_interrupts(|| *EXIT_HOOK.lock());
+    if let Some(hook) = hook {
+        hook(pid, status);
+    }
 }
@@ -0,0 +1,41 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::instructions::random::RdRand;
+
+/// Returns a random number from RDRAND, or one derived from the time stamp counter on CPUs that
+/// don't support it.
+///
+/// Good enough to randomize addresses, but not for cryptography
+pub fn random_u64() -> u64 {
+    if let Some(rdrand) = RdRand::new() {
+        // RDRAND can run out of entropy for a moment, so give it a few tries
+        for _ in 0..10 {
+            if let Some(value) = rdrand.get_u64() {
+                return value;
+            }
+        }
+    }
+    tsc_random()
+}
+
+/// Returns a random number in `0..bound`
+pub fn random_below(bound: u64) -> u64 {
+    assert!(bound > 0, "empty random range");
+    random_u64() % bound
+}
+
+/// Mixes the time stamp counter with a counter, so that calls in quick succession still differ
+fn tsc_random() -> u64 {
+    static STATE: AtomicU64 = AtomicU64::new(0);
+    // SAFETY: RDTSC has no side effects
+    let tsc = unsafe { core::arch::x86_64::_rdtsc() };
+    let state = STATE.fetch_add(0x9E37_79B9_7F4A_7C15, Ordering::Relaxed);
+    splitmix64(tsc ^ state)
+}
+
+/// The finalizer of the SplitMix64 generator, which spreads every input bit over the whole output
+fn splitmix64(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
@@ -0,0 +1,167 @@
+use alloc::vec::Vec;
+use core::{mem::size_of, ptr, slice};
+
+use x86_64::PhysAddr;
+
+use crate::memory;
+
+/// The root pointer that leads to the other ACPI tables
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // Only present from revision 2 on
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// The size of the part of [`Rsdp`] that exists in revision 0
+const RSDP_V1_SIZE: usize = 20;
+
+/// The header that every ACPI table starts with
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct SdtHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+/// Entries of the MADT that are needed to start the other CPUs
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_LOCAL_APIC_OVERRIDE: u8 = 5;
+
+/// The processor can be started
+const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+
+/// What the MADT (multiple APIC description table) says about the CPUs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Madt {
+    /// Where the registers of each CPU's local APIC are
+    pub local_apic_address: PhysAddr,
+    /// The local APIC IDs of every CPU that can be started, including the bootstrap processor
+    pub apic_ids: Vec<u8>,
+}
+
+/// Reads a `T` from physical memory
+///
+/// # Safety
+/// `addr` must be mapped by the bootloader's physical memory mapping
+unsafe fn read_phys<T: Copy>(addr: u64) -> T {
+    // SAFETY: Guaranteed by the caller. ACPI structures aren't aligned
+    unsafe { ptr::read_unaligned(memory::phys_to_virt(PhysAddr::new(addr)).as_ptr()) }
+}
+
+/// Returns true if the `len` bytes at `addr` add up to 0, as every ACPI structure does
+///
+/// # Safety
+/// The bytes must be mapped by the bootloader's physical memory mapping
+unsafe fn checksum_ok(addr: u64, len: usize) -> bool {
+    let ptr = memory::phys_to_virt(PhysAddr::new(addr)).as_ptr();
+    // SAFETY: Guaranteed by the caller
+    let bytes = unsafe { slice::from_raw_parts(ptr, len) };
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+/// Searches the places the BIOS may put the RSDP in: the first KiB of the extended BIOS data area
+/// and the BIOS ROM area. Both are in the first MiB, which the bootloader maps
+fn find_rsdp() -> Option<Rsdp> {
+    // SAFETY: The BIOS data area is in the first MiB
+    let ebda = unsafe { read_phys::<u16>(0x40e) } as u64 * 16;
+    let areas = [(ebda, ebda + 1024), (0xe_0000, 0x10_0000)];
+    for (start, end) in areas {
+        if start == 0 {
+            continue;
+        }
+        for addr in (start..end).step_by(16) {
+            // SAFETY: Both areas are in the first MiB
+            unsafe {
+                let rsdp: Rsdp = read_phys(addr);
+                if &rsdp.signature == b"RSD PTR " && checksum_ok(addr, RSDP_V1_SIZE) {
+                    return Some(rsdp);
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Returns the address of the table with `signature` from the RSDT or XSDT, if its checksum is
+/// valid
+fn find_table(signature: &[u8; 4]) -> Option<u64> {
+    let rsdp = find_rsdp()?;
+    // The XSDT has 64 bit pointers, the RSDT 32 bit ones
+    let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (rsdp.xsdt_address, 8)
+    } else {
+        (rsdp.rsdt_address as u64, 4)
+    };
+    // SAFETY: The bootloader maps all of physical memory, which includes the ACPI tables
+    unsafe {
+        let header: SdtHeader = read_phys(root);
+        let entries = (header.length as usize).checked_sub(size_of::<SdtHeader>())? / entry_size;
+        for i in 0..entries {
+            let entry = root + (size_of::<SdtHeader>() + i * entry_size) as u64;
+            let table = match entry_size {
+                8 => read_phys::<u64>(entry),
+                _ => read_phys::<u32>(entry) as u64,
+            };
+            let header: SdtHeader = read_phys(table);
+            if &header.signature == signature && checksum_ok(table, header.length as usize) {
+                return Some(table);
+            }
+        }
+    }
+    None
+}
+
+/// Finds and parses the MADT. Returns `None` if the firmware doesn't provide ACPI tables
+pub fn madt() -> Option<Madt> {
+    let table = find_table(b"APIC")?;
+    // SAFETY: `find_table` checked that the whole table is there
+    unsafe {
+        let header: SdtHeader = read_phys(table);
+        let end = table + header.length as u64;
+        // The local APIC address and flags come right after the header
+        let mut local_apic_address = read_phys::<u32>(table + size_of::<SdtHeader>() as u64) as u64;
+        let mut apic_ids = Vec::new();
+
+        let mut entry = table + size_of::<SdtHeader>() as u64 + 8;
+        while entry + 2 <= end {
+            let kind: u8 = read_phys(entry);
+            let len: u8 = read_phys(entry + 1);
+            if len < 2 {
+                break;
+            }
+            match kind {
+                MADT_LOCAL_APIC => {
+                    let apic_id: u8 = read_phys(entry + 3);
+                    let flags: u32 = read_phys(entry + 4);
+                    if flags & LOCAL_APIC_ENABLED != 0 {
+                        apic_ids.push(apic_id);
+                    }
+                }
+                MADT_LOCAL_APIC_OVERRIDE => local_apic_address = read_phys(entry + 4),
+                _ => {}
+            }
+            entry += len as u64;
+        }
+
+        Some(Madt {
+            local_apic_address: PhysAddr::new(local_apic_address),
+            apic_ids,
+        })
+    }
+}
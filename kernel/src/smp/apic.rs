@@ -0,0 +1,169 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::{
+    instructions::port::Port,
+    structures::paging::{PageTableFlags, PhysFrame},
+    PhysAddr, VirtAddr,
+};
+
+use crate::memory;
+
+/// Registers of the local APIC, as offsets from its base
+const ID: usize = 0x20;
+const END_OF_INTERRUPT: usize = 0xb0;
+const SPURIOUS_VECTOR: usize = 0xf0;
+const ERROR_STATUS: usize = 0x280;
+const INTERRUPT_COMMAND_LOW: usize = 0x300;
+const INTERRUPT_COMMAND_HIGH: usize = 0x310;
+
+/// Enables the local APIC in the spurious vector register
+const APIC_ENABLE: u32 = 1 << 8;
+/// Set in the interrupt command register while an IPI hasn't been delivered yet
+const DELIVERY_PENDING: u32 = 1 << 12;
+const INIT_IPI: u32 = 0b101 << 8;
+const STARTUP_IPI: u32 = 0b110 << 8;
+/// INIT IPIs are sent with the level asserted
+const LEVEL_ASSERT: u32 = 1 << 14;
+
+/// The vector that spurious interrupts from the local APIC arrive on
+pub const SPURIOUS_INTERRUPT_VECTOR: u8 = 0xff;
+
+/// Where the local APIC registers are mapped, or 0 before [`map`] is called. Every CPU sees its
+/// own local APIC at the same address
+static BASE: AtomicU64 = AtomicU64::new(0);
+
+/// Maps the local APIC registers at `addr` uncached, so that every CPU can use them
+pub fn map(addr: PhysAddr) -> Option<LocalApic> {
+    if BASE.load(Ordering::Relaxed) == 0 {
+        let frame = PhysFrame::containing_address(addr);
+        let flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::NO_CACHE
+            | PageTableFlags::WRITE_THROUGH
+            | PageTableFlags::NO_EXECUTE;
+        // SAFETY: The registers only affect the CPU that accesses them
+        let base = unsafe { memory::vmap(PhysFrame::range(frame, frame + 1), flags) }?;
+        BASE.store(base.as_u64(), Ordering::Relaxed);
+    }
+    local_apic()
+}
+
+/// The local APIC of the calling CPU, if [`map`] was called
+pub fn local_apic() -> Option<LocalApic> {
+    match BASE.load(Ordering::Relaxed) {
+        0 => None,
+        base => Some(LocalApic {
+            base: VirtAddr::new(base),
+        }),
+    }
+}
+
+/// The local APIC of the calling CPU, which receives its interrupts and sends interrupts to the
+/// other CPUs
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApic {
+    base: VirtAddr,
+}
+
+impl LocalApic {
+    fn read(&self, register: usize) -> u32 {
+        // SAFETY: The registers are mapped at `base`, and must be accessed 32 bits at a time
+        unsafe { (self.base + register).as_ptr::<u32>().read_volatile() }
+    }
+
+    fn write(&self, register: usize, value: u32) {
+        // SAFETY: Same as in `read`
+        unsafe {
+            (self.base + register)
+                .as_mut_ptr::<u32>()
+                .write_volatile(value)
+        }
+    }
+
+    /// The ID of the calling CPU's local APIC
+    pub fn id(&self) -> u8 {
+        (self.read(ID) >> 24) as u8
+    }
+
+    /// Turns the local APIC on, so that it accepts interrupts from other CPUs
+    pub fn enable(&self) {
+        let vector = self.read(SPURIOUS_VECTOR) & !0xff;
+        self.write(
+            SPURIOUS_VECTOR,
+            vector | APIC_ENABLE | SPURIOUS_INTERRUPT_VECTOR as u32,
+        );
+    }
+
+    /// Tells the local APIC that the interrupt it delivered last has been handled
+    pub fn end_of_interrupt(&self) {
+        self.write(END_OF_INTERRUPT, 0);
+    }
+
+    /// Interrupts the CPU with `apic_id` on `vector`
+    pub fn send_interrupt(&self, apic_id: u8, vector: u8) {
+        self.send_ipi(apic_id, vector as u32);
+    }
+
+    /// Sends an interrupt to the CPU with `apic_id` and waits until it is delivered
+    fn send_ipi(&self, apic_id: u8, command: u32) {
+        self.write(ERROR_STATUS, 0);
+        self.write(INTERRUPT_COMMAND_HIGH, (apic_id as u32) << 24);
+        self.write(INTERRUPT_COMMAND_LOW, command);
+        while self.read(INTERRUPT_COMMAND_LOW) & DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Starts the CPU with `apic_id` with the INIT-SIPI-SIPI sequence. It begins running in real
+    /// mode at the start of `frame`, which must be below 1MiB.
+    ///
+    /// Returns once `started` returns true, or false if it still doesn't after a while
+    pub fn start_cpu(&self, apic_id: u8, frame: PhysFrame, started: impl Fn() -> bool) -> bool {
+        let vector = frame.start_address().as_u64() >> 12;
+        assert!(vector < 0x100, "startup code must be below 1MiB");
+
+        self.send_ipi(apic_id, INIT_IPI | LEVEL_ASSERT);
+        wait_us(10_000);
+        // The second startup IPI is only needed if the CPU missed the first
+        for _ in 0..2 {
+            self.send_ipi(apic_id, STARTUP_IPI | vector as u32);
+            wait_us(200);
+            if started() {
+                return true;
+            }
+        }
+        // Starting can take a while in emulators, so give it up to a second
+        for _ in 0..100 {
+            if started() {
+                return true;
+            }
+            wait_us(10_000);
+        }
+        started()
+    }
+}
+
+/// Busy waits for at least `us` microseconds, up to 50ms at once, using channel 2 of the PIT
+fn wait_us(us: u64) {
+    const PIT_FREQUENCY: u64 = 1_193_182;
+    let ticks = (PIT_FREQUENCY * us / 1_000_000).clamp(1, u16::MAX as u64) as u16;
+
+    let mut speaker: Port<u8> = Port::new(0x61);
+    let mut command: Port<u8> = Port::new(0x43);
+    let mut channel_2: Port<u8> = Port::new(0x42);
+    // SAFETY: Channel 2 only drives the PC speaker, which stays off because bit 1 is cleared
+    unsafe {
+        // Gate channel 2 off while it is programmed
+        let control = speaker.read() & !0b11;
+        speaker.write(control);
+        // Channel 2, low then high byte, interrupt on terminal count
+        command.write(0b1011_0000);
+        channel_2.write(ticks as u8);
+        channel_2.write((ticks >> 8) as u8);
+        // Start counting, and wait until the output goes high at 0
+        speaker.write(control | 1);
+        while speaker.read() & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
@@ -0,0 +1,215 @@
+mod acpi;
+mod apic;
+mod trampoline;
+
+pub use acpi::{madt, Madt};
+pub use apic::{local_apic, LocalApic, SPURIOUS_INTERRUPT_VECTOR};
+
+/// The vector that [`run_on`] interrupts other CPUs with
+pub const CALL_FUNCTION_VECTOR: u8 = 0xfd;
+
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    mem,
+    num::NonZeroU64,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    gdt::CpuTables,
+    memory::{self, KernelStack, StackKind},
+    percpu::PerCpu,
+    println, serial_println, syscall,
+};
+use trampoline::Trampoline;
+
+/// Size of the stack that each application processor starts and idles on
+const IDLE_STACK_SIZE: usize = 16 * 1024;
+
+/// The local APIC IDs of the CPUs that are running, in the order they came online. The bootstrap
+/// processor is first
+static ONLINE: spin::Mutex<Vec<u8>> = spin::Mutex::new(Vec::new());
+
+/// What an application processor needs to set itself up, prepared for it by the bootstrap
+/// processor. It is leaked, as the CPU uses its tables and stack for as long as the kernel runs
+struct Startup {
+    /// The number that the CPU is known by in log messages. The bootstrap processor is 0
+    cpu: usize,
+    tables: &'static CpuTables,
+    stack: KernelStack,
+    /// Set by the CPU once it has set itself up, which is how the bootstrap processor knows that
+    /// this CPU started, and not one that was started earlier
+    online: AtomicBool,
+}
+
+/// Finds the other CPUs in the ACPI tables and starts them. Each one loads its own GDT and TSS
+/// with its own interrupt stacks, points GS at its own [`PerCpu`] area, reports its APIC ID over
+/// serial, and then idles.
+///
+/// Returns how many CPUs are online, including the calling one. Must be called once, on the
+/// bootstrap processor, after the kernel heap and [`crate::gdt::init_stacks`] are set up
+pub fn init() -> usize {
+    let Some(madt) = acpi::madt() else {
+        println!("smp: no MADT, so only the bootstrap processor runs");
+        return 1;
+    };
+    let Some(apic) = apic::map(madt.local_apic_address) else {
+        println!("smp: the local APIC couldn't be mapped");
+        return 1;
+    };
+    apic.enable();
+    let bsp = apic.id();
+    with_online(|online| online.push(bsp));
+    serial_println!("cpu 0: APIC ID {} online", bsp);
+
+    let others: Vec<u8> = madt.apic_ids.into_iter().filter(|&id| id != bsp).collect();
+    if others.is_empty() {
+        return online_cpus();
+    }
+    let Some(trampoline) = memory::low_memory_frame().and_then(Trampoline::new) else {
+        println!("smp: no memory below 1MiB to start the other CPUs from");
+        return online_cpus();
+    };
+
+    for (i, apic_id) in others.into_iter().enumerate() {
+        let cpu = i + 1;
+        let stack = KernelStack::new(IDLE_STACK_SIZE, StackKind::Idle { cpu })
+            .expect("no memory for idle stacks");
+        let startup: &'static Startup = Box::leak(Box::new(Startup {
+            cpu,
+            tables: CpuTables::new(),
+            stack,
+            online: AtomicBool::new(false),
+        }));
+        trampoline.prepare(
+            ap_main,
+            startup.stack.top(),
+            startup as *const Startup as u64,
+        );
+
+        let started = || startup.online.load(Ordering::Acquire);
+        if !apic.start_cpu(apic_id, trampoline.frame(), started) {
+            // It may still start later, and run from the trampoline with this CPU's startup data.
+            // So the trampoline is neither patched for another CPU nor unmapped
+            println!(
+                "smp: cpu {} with APIC ID {} didn't start, so no more CPUs are started",
+                cpu, apic_id
+            );
+            mem::forget(trampoline);
+            break;
+        }
+    }
+    online_cpus()
+}
+
+fn with_online<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut Vec<u8>) -> R,
+{
+    crate::sys::without_interrupts(|| f(&mut ONLINE.lock()))
+}
+
+/// Held by the [`run_on`] call that is in progress, as only one can be at a time
+static RUNNING: spin::Mutex<()> = spin::Mutex::new(());
+
+/// A function that [`run_on`] asked another CPU to run
+#[derive(Clone, Copy)]
+struct Call {
+    apic_id: u8,
+    f: fn(),
+}
+
+/// The call in progress, until the CPU that it is meant for takes it
+static CALL: spin::Mutex<Option<Call>> = spin::Mutex::new(None);
+
+/// Set once the CPU that [`CALL`] was meant for has run it
+static CALL_DONE: AtomicBool = AtomicBool::new(false);
+
+/// Runs `f` on the CPU with `apic_id`, from an interrupt handler if it isn't the calling CPU, and
+/// waits until it returns. Returns false if no such CPU is online.
+///
+/// Must not be called from an interrupt handler, as the CPU may be in the middle of another call
+pub fn run_on(apic_id: u8, f: fn()) -> bool {
+    let Some(apic) = apic::local_apic() else {
+        return false;
+    };
+    if apic_id == apic.id() {
+        f();
+        return true;
+    }
+    if !apic_ids().contains(&apic_id) {
+        return false;
+    }
+    // Interrupts stay enabled while waiting, so that calls to this CPU still get through
+    let _running = RUNNING.lock();
+    crate::sys::without_interrupts(|| {
+        *CALL.lock() = Some(Call { apic_id, f });
+        CALL_DONE.store(false, Ordering::Relaxed);
+    });
+    apic.send_interrupt(apic_id, CALL_FUNCTION_VECTOR);
+    while !CALL_DONE.load(Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
+    true
+}
+
+/// Runs the function that [`run_on`] asked the calling CPU to run. Called by the interrupt handler
+/// of [`CALL_FUNCTION_VECTOR`], with interrupts disabled
+pub(crate) fn handle_call() {
+    let apic = apic::local_apic().expect("local APIC is mapped before CPUs are interrupted");
+    let call = {
+        let mut call = CALL.lock();
+        match *call {
+            Some(Call { apic_id, f }) if apic_id == apic.id() => {
+                *call = None;
+                Some(f)
+            }
+            _ => None,
+        }
+    };
+    apic.end_of_interrupt();
+    if let Some(f) = call {
+        f();
+        CALL_DONE.store(true, Ordering::Release);
+    }
+}
+
+/// How many CPUs are running. This is 1 until [`init`] is called
+pub fn online_cpus() -> usize {
+    with_online(|online| online.len()).max(1)
+}
+
+/// The local APIC IDs of the running CPUs, starting with the bootstrap processor. Empty until
+/// [`init`] is called
+pub fn apic_ids() -> Vec<u8> {
+    with_online(|online| online.clone())
+}
+
+/// Where application processors continue in Rust, with a pointer to their [`Startup`]. The
+/// trampoline has loaded the kernel's page tables and the idle stack, but interrupts are off and
+/// nothing else is set up yet
+extern "sysv64" fn ap_main(startup: u64) -> ! {
+    // SAFETY: The bootstrap processor leaked the startup data for this CPU
+    let startup = unsafe { &*(startup as *const Startup) };
+    // SAFETY: The tables were created for this CPU, and interrupts are still disabled
+    unsafe { startup.tables.load() };
+    crate::interrupts::init_idt();
+    syscall::init();
+    let area = PerCpu::new(startup.cpu, startup.tables.tss());
+    let stack_top = startup.stack.top().as_u64();
+    area.kernel_rsp.set(NonZeroU64::new(stack_top));
+    let area: &'static PerCpu = Box::leak(Box::new(area));
+    // SAFETY: The area was just created for this CPU, which hasn't used GS yet
+    unsafe { area.install() };
+
+    let apic = apic::local_apic().expect("local APIC is mapped before CPUs are started");
+    apic.enable();
+    let id = apic.id();
+    with_online(|online| online.push(id));
+    serial_println!("cpu {}: APIC ID {} online", startup.cpu, id);
+    startup.online.store(true, Ordering::Release);
+
+    // Nothing is routed to this CPU yet, so it sleeps until the kernel has work for it
+    crate::sys::enable_interrupts();
+    crate::sys::hlt_loop()
+}
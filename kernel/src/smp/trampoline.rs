@@ -0,0 +1,243 @@
+use core::{
+    arch::global_asm,
+    ptr,
+    sync::atomic::{fence, Ordering},
+};
+
+use x86_64::{
+    structures::paging::{
+        mapper::{MappedFrame, TranslateResult},
+        Mapper, Page, PageTableFlags, PhysFrame, Size4KiB, Translate,
+    },
+    VirtAddr,
+};
+
+use crate::memory;
+
+// Application processors start in real mode, at the start of the page that the startup IPI names.
+// This switches straight from there to long mode with the kernel's page tables, and calls the
+// entry point with the stack and argument that were patched in.
+//
+// The code is copied to a low frame before it runs, so it only uses offsets from
+// `ap_trampoline`, and rip relative addresses once in long mode
+global_asm!(
+    ".pushsection .rodata.ap_trampoline, \"a\"",
+    ".global ap_trampoline",
+    ".global ap_trampoline_far_jump",
+    ".global ap_trampoline_long_mode",
+    ".global ap_trampoline_gdt",
+    ".global ap_trampoline_gdt_base",
+    ".global ap_trampoline_cr3",
+    ".global ap_trampoline_stack",
+    ".global ap_trampoline_entry",
+    ".global ap_trampoline_argument",
+    ".global ap_trampoline_end",
+    ".code16",
+    "ap_trampoline:",
+    "cli",
+    "cld",
+    "mov ax, cs",
+    "mov ds, ax",
+    // Long mode needs physical address extensions
+    "mov eax, 0x20",
+    "mov cr4, eax",
+    "mov eax, dword ptr [ap_trampoline_cr3_offset]",
+    "mov cr3, eax",
+    // Long mode, syscall and no-execute in EFER
+    "mov ecx, 0xc0000080",
+    "rdmsr",
+    "or eax, 0x901",
+    "wrmsr",
+    "lgdt [ap_trampoline_gdt_pointer_offset]",
+    // Turning on protection, write protection and paging at once activates long mode
+    "mov eax, cr0",
+    "or eax, 0x80010001",
+    "mov cr0, eax",
+    // A far jump into the 64 bit code segment, to a 32 bit address that is patched in
+    ".byte 0x66, 0xea",
+    "ap_trampoline_far_jump:",
+    ".long 0",
+    ".word 0x08",
+    ".code64",
+    "ap_trampoline_long_mode:",
+    "mov ax, 0x10",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "mov rsp, qword ptr [rip + ap_trampoline_stack]",
+    "mov rdi, qword ptr [rip + ap_trampoline_argument]",
+    "xor ebp, ebp",
+    // Calling leaves the stack aligned the way the entry point expects. It never returns
+    "call qword ptr [rip + ap_trampoline_entry]",
+    "ud2",
+    ".balign 8",
+    "ap_trampoline_gdt:",
+    ".quad 0",
+    // 64 bit kernel code
+    ".quad 0x00af9a000000ffff",
+    // Kernel data
+    ".quad 0x00cf92000000ffff",
+    "ap_trampoline_gdt_pointer:",
+    ".word ap_trampoline_gdt_pointer - ap_trampoline_gdt - 1",
+    "ap_trampoline_gdt_base:",
+    ".long 0",
+    ".balign 8",
+    "ap_trampoline_cr3:",
+    ".quad 0",
+    "ap_trampoline_stack:",
+    ".quad 0",
+    "ap_trampoline_entry:",
+    ".quad 0",
+    "ap_trampoline_argument:",
+    ".quad 0",
+    "ap_trampoline_end:",
+    // Real mode addresses are offsets from the start of the segment, which is the trampoline
+    ".set ap_trampoline_cr3_offset, ap_trampoline_cr3 - ap_trampoline",
+    ".set ap_trampoline_gdt_pointer_offset, ap_trampoline_gdt_pointer - ap_trampoline",
+    ".popsection",
+);
+
+extern "C" {
+    static ap_trampoline: u8;
+    static ap_trampoline_far_jump: u8;
+    static ap_trampoline_long_mode: u8;
+    static ap_trampoline_gdt: u8;
+    static ap_trampoline_gdt_base: u8;
+    static ap_trampoline_cr3: u8;
+    static ap_trampoline_stack: u8;
+    static ap_trampoline_entry: u8;
+    static ap_trampoline_argument: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// What an application processor calls once it is in long mode, with the argument passed to
+/// [`Trampoline::prepare`]
+pub type Entry = extern "sysv64" fn(u64) -> !;
+
+/// The offset of a symbol from the start of the trampoline
+fn offset(symbol: *const u8) -> u64 {
+    // SAFETY: Only the address of the symbol is used
+    symbol as u64 - unsafe { ptr::addr_of!(ap_trampoline) } as u64
+}
+
+/// A copy of the startup code in a frame below 1MiB, which is identity mapped for as long as this
+/// lives. Application processors turn on paging while running from it, so it has to stay at the
+/// same address afterwards
+pub struct Trampoline {
+    frame: PhysFrame,
+    /// Whether the identity mapping was created here, and has to be removed again
+    mapped: bool,
+}
+
+impl Trampoline {
+    /// Copies the startup code into `frame` and identity maps it. Returns `None` if the page is
+    /// already mapped to something else
+    pub fn new(frame: PhysFrame) -> Option<Trampoline> {
+        let addr = frame.start_address().as_u64();
+        assert!(addr < 0x10_0000, "startup code must be below 1MiB");
+        // SAFETY: Only the address of the symbol is used
+        let len = offset(unsafe { ptr::addr_of!(ap_trampoline_end) });
+        assert!(len <= frame.size(), "startup code doesn't fit in a frame");
+
+        let mapped = identity_map(frame)?;
+        // SAFETY: The frame is reserved for starting CPUs, and the code is `len` bytes long
+        unsafe {
+            ptr::copy_nonoverlapping(
+                ptr::addr_of!(ap_trampoline),
+                memory::phys_to_virt(frame.start_address()).as_mut_ptr(),
+                len as usize,
+            )
+        };
+        let trampoline = Trampoline { frame, mapped };
+
+        // The code runs on the kernel's page tables, which it can only load from a 32 bit register
+        let cr3 = memory::kernel_level_4_frame().start_address().as_u64();
+        assert!(cr3 < 1 << 32, "kernel page tables must be below 4GiB");
+        // SAFETY: Only the addresses of the symbols are used
+        unsafe {
+            trampoline.write(ptr::addr_of!(ap_trampoline_cr3), cr3);
+            trampoline.write(
+                ptr::addr_of!(ap_trampoline_gdt_base),
+                (addr + offset(ptr::addr_of!(ap_trampoline_gdt))) as u32,
+            );
+            trampoline.write(
+                ptr::addr_of!(ap_trampoline_far_jump),
+                (addr + offset(ptr::addr_of!(ap_trampoline_long_mode))) as u32,
+            );
+        }
+        Some(trampoline)
+    }
+
+    /// The frame that the code was copied to, which the startup IPI has to name
+    pub fn frame(&self) -> PhysFrame {
+        self.frame
+    }
+
+    /// Patches `value` into the copy of the code, at the field that starts at `symbol`
+    fn write<T>(&self, symbol: *const u8, value: T) {
+        let field = memory::phys_to_virt(self.frame.start_address()) + offset(symbol);
+        // SAFETY: Every field is within the copy of the code, which is only read by CPUs that
+        // haven't been started yet. The far jump target isn't aligned
+        unsafe { field.as_mut_ptr::<T>().write_unaligned(value) };
+    }
+
+    /// Sets what the next CPU to start runs: `entry(argument)`, on the stack that ends at
+    /// `stack_top`
+    pub fn prepare(&self, entry: Entry, stack_top: VirtAddr, argument: u64) {
+        // SAFETY: Only the addresses of the symbols are used
+        unsafe {
+            self.write(ptr::addr_of!(ap_trampoline_stack), stack_top.as_u64());
+            self.write(ptr::addr_of!(ap_trampoline_entry), entry as usize as u64);
+            self.write(ptr::addr_of!(ap_trampoline_argument), argument);
+        }
+        // The CPU is started by writing to local APIC registers, which must come after these
+        fence(Ordering::SeqCst);
+    }
+}
+
+impl Drop for Trampoline {
+    fn drop(&mut self) {
+        if !self.mapped {
+            return;
+        }
+        let page = identity_page(self.frame);
+        memory::mapper().with(|mapper| {
+            let (_, flush) = mapper
+                .unmap(page)
+                .expect("trampoline identity mapping disappeared");
+            flush.flush();
+        });
+    }
+}
+
+fn identity_page(frame: PhysFrame) -> Page<Size4KiB> {
+    Page::containing_address(VirtAddr::new(frame.start_address().as_u64()))
+}
+
+/// Maps `frame` at its own address in the kernel's page tables. Returns whether a new mapping was
+/// made, or `None` if the page is already in use for something else
+fn identity_map(frame: PhysFrame) -> Option<bool> {
+    let page = identity_page(frame);
+    memory::mapper().with(|mapper| match mapper.translate(page.start_address()) {
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(mapped),
+            flags,
+            ..
+        } => (mapped == frame && !flags.contains(PageTableFlags::NO_EXECUTE)).then_some(false),
+        TranslateResult::NotMapped => {
+            // SAFETY: The page is in the lower half, which the kernel doesn't otherwise use
+            let flush = unsafe {
+                mapper.map_to(
+                    page,
+                    frame,
+                    PageTableFlags::PRESENT,
+                    &mut memory::frame_allocator_for(memory::FrameOwner::PageTable),
+                )
+            }
+            .ok()?;
+            flush.flush();
+            Some(true)
+        }
+        _ => None,
+    })
+}
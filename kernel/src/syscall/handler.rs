@@ -1,5 +1,8 @@
-use super::{io, with_user_slice, with_user_slice_mut, ThreadData};
-use crate::println;
+use super::{io, memory, UserContext, UserPtr, UserSlice};
+use crate::{
+    percpu::{KERNEL_RSP_OFFSET, USER_TMP_RSP_OFFSET},
+    println,
+};
 use core::arch::asm;
 use memoffset::offset_of;
 use syscall::{Error, Result, Syscall};
@@ -14,6 +17,7 @@ extern "sysv64" fn syscall_handler_inner(
     arg2: usize,
     arg3: usize,
     arg4: usize,
+    context: &UserContext,
 ) -> usize {
     let inner = || -> Result<usize> {
         if STRACE {
@@ -25,14 +29,21 @@ extern "sysv64" fn syscall_handler_inner(
         let syscall: Syscall = syscall_num.try_into().map_err(|_| Error::NoSys)?;
 
         match syscall {
-            // SAFETY: If the slice can be constructed, then it means it is a user page which we do
-            // not alias in the kernel. This memory may be aliased in the user program, but they
-            // are paused so they cannot observe that we alias the same memory here
-            Syscall::Read => unsafe {
-                with_user_slice_mut(arg1, arg2, |bytes| io::read(arg0, bytes))?
-            },
-            Syscall::Write => with_user_slice(arg1, arg2, |bytes| io::write(arg0, bytes))?,
+            // User memory is only accessed through the checked `UserPtr`, `UserSlice` and `UserStr`
+            Syscall::Read => io::read(arg0, UserSlice::new(arg1, arg2)?),
+            Syscall::Write => io::write(arg0, UserSlice::new(arg1, arg2)?),
             Syscall::Exit => super::process::exit(arg0 as u8),
+            Syscall::Fork => super::process::fork(context),
+            Syscall::MemInfo => memory::mem_info(UserPtr::new(arg0)?),
+            Syscall::Mmap => memory::mmap(arg0, arg1, arg2, arg3),
+            Syscall::Munmap => memory::munmap(arg0, arg1),
+            Syscall::Mprotect => memory::mprotect(arg0, arg1, arg2),
+            Syscall::ShmCreate => memory::shm_create(arg0, arg1),
+            Syscall::ShmOpen => memory::shm_open(arg0),
+            Syscall::ShmMap => memory::shm_map(arg0, arg1, arg2),
+            Syscall::ShmClose => memory::shm_close(arg0),
+            Syscall::Brk => memory::brk(arg0),
+            Syscall::ShmId => memory::shm_id(arg0),
         }
     };
 
@@ -79,6 +90,18 @@ pub(super) extern "x86-interrupt" fn syscall_handler() {
             "push rcx",
             // Push saved rflags
             "push r11",
+            // Push the rest of the user's registers so that a `UserContext` is at the top of the
+            // stack. Processes are switched by resuming a different context
+            "push qword ptr gs:[{user_rsp_offset}]",
+            "push rbx",
+            "push rbp",
+            "push r12",
+            "push r13",
+            "push r14",
+            "push r15",
+            // A pointer to the context is the 7th argument, which SystemV passes on the stack
+            "mov rax, rsp",
+            "push rax",
             // SystemV expects registers in the folowing order for calling syscall_handler_inner
             //
             // rdi  syscall number
@@ -90,17 +113,65 @@ pub(super) extern "x86-interrupt" fn syscall_handler() {
             "mov rcx, r10",
             "call syscall_handler_inner",
             // rax now holds return value from call
+            "add rsp, 8",
+            "pop r15",
+            "pop r14",
+            "pop r13",
+            "pop r12",
+            "pop rbp",
+            "pop rbx",
+            // user rsp, r10 doesn't need to be preserved across syscalls
+            "pop r10",
             // pop saved flags
             "pop r11",
             // pop saved return address
             "pop rcx",
             // Restore user stack
             "mov gs:[{kernel_rsp_offset}], rsp",
-            "mov rsp, gs:[{user_rsp_offset}]",
+            "mov rsp, r10",
             "swapgs",
             "sysretq",
-            kernel_rsp_offset = const(offset_of!(ThreadData, kernel_rsp)),
-            user_rsp_offset = const(offset_of!(ThreadData, user_tmp_rsp)),
+            kernel_rsp_offset = const(KERNEL_RSP_OFFSET),
+            user_rsp_offset = const(USER_TMP_RSP_OFFSET),
+            options(noreturn)
+        )
+    };
+}
+
+/// Returns to user mode with the registers in `context`, with `rax` as the return value of the
+/// syscall that the process is resumed from. Also enables interrupts
+///
+/// # Safety
+/// 1. The address space that `context` belongs to must be active
+/// 2. Nothing on the current kernel stack may be used again, as the next syscall starts over at the
+///    top of the kernel stack
+#[naked]
+#[no_mangle]
+pub unsafe extern "sysv64" fn enter_user_context(context: *const UserContext, rax: u64) -> ! {
+    unsafe {
+        asm!(
+            "mov rax, rsi",
+            "mov r15, [rdi + {r15}]",
+            "mov r14, [rdi + {r14}]",
+            "mov r13, [rdi + {r13}]",
+            "mov r12, [rdi + {r12}]",
+            "mov rbp, [rdi + {rbp}]",
+            "mov rbx, [rdi + {rbx}]",
+            // rip and rflags get set to rcx and r11 when sysret is invoked
+            "mov rcx, [rdi + {rip}]",
+            "mov r11, [rdi + {rflags}]",
+            "mov rsp, [rdi + {rsp}]",
+            "swapgs",
+            "sysretq",
+            r15 = const(offset_of!(UserContext, r15)),
+            r14 = const(offset_of!(UserContext, r14)),
+            r13 = const(offset_of!(UserContext, r13)),
+            r12 = const(offset_of!(UserContext, r12)),
+            rbp = const(offset_of!(UserContext, rbp)),
+            rbx = const(offset_of!(UserContext, rbx)),
+            rsp = const(offset_of!(UserContext, rsp)),
+            rflags = const(offset_of!(UserContext, rflags)),
+            rip = const(offset_of!(UserContext, rip)),
             options(noreturn)
         )
     };
@@ -1,14 +1,27 @@
 use syscall::Result;
 
+use super::UserSlice;
 use crate::println;
 
-pub fn write(_fd: usize, bytes: &[u8]) -> Result<usize> {
+/// User buffers are copied through the kernel this many bytes at a time
+const CHUNK_SIZE: usize = 256;
+
+pub fn write(_fd: usize, buf: UserSlice<u8>) -> Result<usize> {
     println!("write");
-    crate::vga_buffer::print_bytes(bytes);
-    Ok(bytes.len())
+    let mut chunk = [0; CHUNK_SIZE];
+    let mut rest = buf;
+    while !rest.is_empty() {
+        let (src, tail) = rest.split_at(rest.len().min(CHUNK_SIZE));
+        let bytes = &mut chunk[..src.len()];
+        src.copy_to_slice(bytes)?;
+        crate::vga_buffer::print_bytes(bytes);
+        rest = tail;
+    }
+    Ok(buf.len())
 }
 
-pub fn read(_fd: usize, bytes: &mut [u8]) -> Result<usize> {
+pub fn read(_fd: usize, _buf: UserSlice<u8>) -> Result<usize> {
     println!("read");
-    Ok(bytes.len())
+    // There is no input source yet, so nothing is ever read
+    Ok(0)
 }
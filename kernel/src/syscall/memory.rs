@@ -0,0 +1,241 @@
+use syscall::{Error, MapFlags, MemInfo, Prot, Result};
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+use super::UserPtr;
+use crate::{
+    allocator,
+    memory::{self, Access, AddressSpace, SharedMemoryError, Vma, VmaError, VmaKind},
+};
+
+/// Writes the current memory statistics to `info`
+pub fn mem_info(info: UserPtr<MemInfo>) -> Result<usize> {
+    let resident_pages =
+        crate::process::with_current(|process| process.address_space.resident_pages())
+            .ok_or(Error::InvalidArgument)?;
+
+    let frames = memory::frame_stats();
+    let heap = allocator::heap_stats();
+    let stats = MemInfo {
+        total_frames: memory::total_frames(),
+        free_frames: (frames.free + frames.contiguous_free) as u64,
+        kernel_reserved_frames: memory::kernel_reserved_frames(),
+        heap_size: heap.size as u64,
+        heap_used: heap.used as u64,
+        resident_pages: resident_pages as u64,
+    };
+
+    info.write(&stats)?;
+    Ok(0)
+}
+
+fn vma_error(err: VmaError) -> Error {
+    match err {
+        VmaError::InvalidRange | VmaError::Overlaps => Error::InvalidArgument,
+        VmaError::WritableAndExecutable => Error::PermissionDenied,
+        VmaError::NotMapped => Error::BadAddress,
+        VmaError::OutOfMemory => Error::OutOfMemory,
+    }
+}
+
+/// The page table flags of user memory with the permissions in `prot`. An empty `prot` is
+/// rejected, as present user pages can always be read
+fn prot_flags(prot: usize) -> Result<PageTableFlags> {
+    let prot = u32::try_from(prot)
+        .ok()
+        .and_then(Prot::from_bits)
+        .filter(|prot| !prot.is_empty())
+        .ok_or(Error::InvalidArgument)?;
+    if prot.contains(Prot::WRITE | Prot::EXEC) {
+        return Err(Error::PermissionDenied);
+    }
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    flags.set(PageTableFlags::WRITABLE, prot.contains(Prot::WRITE));
+    flags.set(PageTableFlags::NO_EXECUTE, !prot.contains(Prot::EXEC));
+    Ok(flags)
+}
+
+/// Rounds `len` up to whole pages. Fails if it is zero or too large
+fn page_len(len: usize) -> Result<u64> {
+    if len == 0 {
+        return Err(Error::InvalidArgument);
+    }
+    let len = len.checked_add(4095).ok_or(Error::InvalidArgument)? & !4095;
+    Ok(len as u64)
+}
+
+/// Checks that `addr` is page aligned and returns the range of `len` bytes starting at it, with
+/// `len` rounded up to whole pages. Whether the range is in user memory is left to the VMA code
+fn page_range(addr: usize, len: usize) -> Result<(VirtAddr, VirtAddr)> {
+    if addr % 4096 != 0 {
+        return Err(Error::InvalidArgument);
+    }
+    let end = (addr as u64)
+        .checked_add(page_len(len)?)
+        .ok_or(Error::InvalidArgument)?;
+    let start = VirtAddr::try_new(addr as u64).map_err(|_| Error::InvalidArgument)?;
+    let end = VirtAddr::try_new(end).map_err(|_| Error::InvalidArgument)?;
+    Ok((start, end))
+}
+
+/// Finds a free range of `len` bytes for a new mapping, at `hint` if possible and above
+/// `mmap_base` otherwise
+fn place_mapping(
+    space: &AddressSpace,
+    mmap_base: VirtAddr,
+    hint: usize,
+    len: u64,
+) -> Result<VirtAddr> {
+    // Hints are rounded down to a page, and only used if the whole range is free
+    let hint = VirtAddr::try_new(hint as u64 & !4095)
+        .ok()
+        .filter(|&start| hint != 0 && space.find_free_range(start, len) == Some(start));
+    match hint {
+        Some(hint) => Ok(hint),
+        None => space
+            .find_free_range(mmap_base, len)
+            .ok_or(Error::OutOfMemory),
+    }
+}
+
+/// Creates an anonymous private mapping of `len` bytes and returns its address.
+///
+/// Without [`MapFlags::FIXED`], `addr` is only a hint that is used if the range is free, and the
+/// mapping is placed above the process's mmap base otherwise
+pub fn mmap(addr: usize, len: usize, prot: usize, flags: usize) -> Result<usize> {
+    let flags = u32::try_from(flags)
+        .ok()
+        .and_then(MapFlags::from_bits)
+        .ok_or(Error::InvalidArgument)?;
+    if !flags.contains(MapFlags::PRIVATE | MapFlags::ANONYMOUS) {
+        return Err(Error::InvalidArgument);
+    }
+    let vma_flags = prot_flags(prot)?;
+    let len = page_len(len)?;
+
+    crate::process::with_current(|process| {
+        let mmap_base = process.layout().mmap_base;
+        let space = &mut process.address_space;
+        if flags.contains(MapFlags::FIXED) {
+            let (start, end) = page_range(addr, len as usize)?;
+            // Replaces whatever is mapped there, but only once the new mapping is known to fit
+            let vma = Vma::new(start, end, vma_flags, VmaKind::Anonymous);
+            space.replace_vma(vma).map_err(vma_error)?;
+            return Ok(start.as_u64() as usize);
+        }
+        let start = place_mapping(space, mmap_base, addr, len)?;
+        let vma = Vma::new(start, start + len, vma_flags, VmaKind::Anonymous);
+        space.add_vma(vma).map_err(vma_error)?;
+        Ok(start.as_u64() as usize)
+    })
+    // Syscalls can only be made by a running process
+    .unwrap_or(Err(Error::InvalidArgument))
+}
+
+/// Unmaps the pages in the `len` bytes at `addr`
+pub fn munmap(addr: usize, len: usize) -> Result<usize> {
+    let (start, end) = page_range(addr, len)?;
+    crate::process::with_current(|process| process.address_space.unmap_range(start, end))
+        .ok_or(Error::InvalidArgument)?
+        .map_err(vma_error)?;
+    Ok(0)
+}
+
+/// Changes the permissions of the `len` bytes at `addr`, which must all be mapped
+pub fn mprotect(addr: usize, len: usize, prot: usize) -> Result<usize> {
+    let (start, end) = page_range(addr, len)?;
+    let flags = prot_flags(prot)?;
+    crate::process::with_current(|process| process.address_space.protect(start, end, flags))
+        .ok_or(Error::InvalidArgument)?
+        .map_err(vma_error)?;
+    Ok(0)
+}
+
+/// Moves the end of the calling process's heap to `addr` and returns the new end. An `addr` of
+/// 0 leaves it in place, which gives the current end
+pub fn brk(addr: usize) -> Result<usize> {
+    crate::process::with_current(|process| {
+        if addr != 0 {
+            let brk = VirtAddr::try_new(addr as u64).map_err(|_| Error::InvalidArgument)?;
+            process.set_brk(brk).map_err(|err| match err {
+                // Something else is mapped where the heap would grow
+                VmaError::Overlaps => Error::OutOfMemory,
+                err => vma_error(err),
+            })?;
+        }
+        Ok(process.brk().as_u64() as usize)
+    })
+    .unwrap_or(Err(Error::InvalidArgument))
+}
+
+fn shared_memory_error(err: SharedMemoryError) -> Error {
+    match err {
+        SharedMemoryError::NotFound => Error::InvalidArgument,
+        SharedMemoryError::PermissionDenied => Error::PermissionDenied,
+        SharedMemoryError::OutOfMemory => Error::OutOfMemory,
+    }
+}
+
+/// Creates a shared memory object of `size` bytes, rounded up to whole pages, and returns a
+/// read-write handle to it. Other processes may open it with the permissions in `share`, which
+/// are read-write if they include [`Prot::WRITE`], read-only if not, and none if empty
+pub fn shm_create(size: usize, share: usize) -> Result<usize> {
+    let pages = (page_len(size)? / 4096) as usize;
+    let share = u32::try_from(share)
+        .ok()
+        .and_then(Prot::from_bits)
+        .ok_or(Error::InvalidArgument)?;
+    let shared = if share.contains(Prot::WRITE) {
+        Some(Access::ReadWrite)
+    } else if !share.is_empty() {
+        Some(Access::ReadOnly)
+    } else {
+        None
+    };
+    crate::process::with_current(|process| process.shared_memory.create(pages, shared))
+        .ok_or(Error::InvalidArgument)?
+        .map_err(shared_memory_error)
+}
+
+/// Opens a handle to the shared memory object `id`, if the calling process may, and returns it
+pub fn shm_open(id: usize) -> Result<usize> {
+    crate::process::with_current(|process| process.shared_memory.open(id as u64))
+        .ok_or(Error::InvalidArgument)?
+        .map_err(shared_memory_error)
+}
+
+/// Returns the id of the shared memory object that `handle` refers to, which other processes
+/// open it by
+pub fn shm_id(handle: usize) -> Result<usize> {
+    let id = crate::process::with_current(|process| process.shared_memory.id(handle))
+        .flatten()
+        .ok_or(Error::InvalidArgument)?;
+    Ok(id as usize)
+}
+
+/// Maps the whole shared memory object that `handle` refers to with the permissions in `prot`,
+/// placing it like [`mmap`] places mappings with a hint. Writable mappings need a read-write
+/// handle
+pub fn shm_map(handle: usize, hint: usize, prot: usize) -> Result<usize> {
+    let flags = prot_flags(prot)?;
+    crate::process::with_current(|process| {
+        let write = flags.contains(PageTableFlags::WRITABLE);
+        let frames = process
+            .shared_memory
+            .frames(handle, write)
+            .map_err(shared_memory_error)?;
+        let mmap_base = process.layout().mmap_base;
+        let space = &mut process.address_space;
+        let start = place_mapping(space, mmap_base, hint, frames.len() as u64 * 4096)?;
+        space.map_shared(start, &frames, flags).map_err(vma_error)?;
+        Ok(start.as_u64() as usize)
+    })
+    .unwrap_or(Err(Error::InvalidArgument))
+}
+
+/// Closes the calling process's `handle` to a shared memory object
+pub fn shm_close(handle: usize) -> Result<usize> {
+    crate::process::with_current(|process| process.shared_memory.close(handle))
+        .flatten()
+        .ok_or(Error::InvalidArgument)?;
+    Ok(0)
+}
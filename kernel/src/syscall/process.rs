@@ -1,8 +1,23 @@
-use syscall::Result;
+use syscall::{Error, Result};
 
-pub fn exit(_code: u8) -> Result<usize> {
-    crate::sys::enable_interrupts();
-    // End of process on this core so this will never return
-    // TODO: Call into the scheduler here
-    crate::sys::hlt_loop();
+use super::UserContext;
+use crate::{println, process::ExitStatus};
+
+pub fn exit(code: u8) -> Result<usize> {
+    if let Some(pid) = crate::process::with_current(|process| process.pid()) {
+        println!("pid {} exited with code {}", pid, code);
+    }
+    crate::process::exit_current(ExitStatus::Exited(code));
+}
+
+/// Forks the current process. The child runs first and sees a return value of 0, while the
+/// parent gets the child's pid once it is resumed
+pub fn fork(context: &UserContext) -> Result<usize> {
+    crate::process::reserve_run_queue().map_err(|_| Error::OutOfMemory)?;
+    let child = crate::process::with_current(|parent| parent.fork(context))
+        .ok_or(Error::InvalidArgument)?
+        .map_err(|_| Error::OutOfMemory)?;
+
+    crate::process::suspend_current(context, child.pid().as_u64());
+    crate::process::run(child);
 }
@@ -0,0 +1,181 @@
+use core::{
+    arch::{asm, global_asm},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use raw_cpuid::CpuId;
+use syscall::{Error, Result};
+use x86_64::{
+    registers::{
+        control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+        rflags::{self, RFlags},
+    },
+    VirtAddr,
+};
+
+use crate::memory::{USER_END, USER_START};
+
+static SMEP: AtomicBool = AtomicBool::new(false);
+static SMAP: AtomicBool = AtomicBool::new(false);
+
+/// Stops the kernel from executing (SMEP) and touching (SMAP) user pages on CPUs that support it.
+/// Once SMAP is enabled, user memory can only be accessed inside [`with_user_access`]
+pub(super) fn init() {
+    let features = CpuId::new().get_extended_feature_info();
+    let smep = features.as_ref().map_or(false, |f| f.has_smep());
+    let smap = features.as_ref().map_or(false, |f| f.has_smap());
+
+    // SAFETY: The kernel never runs code from user pages, and only touches user memory inside
+    // `with_user_access`. Write protection makes `copy_to_user` fault on read only and copy on
+    // write pages instead of writing to them
+    unsafe {
+        Cr0::update(|cr0| cr0.insert(Cr0Flags::WRITE_PROTECT));
+        Cr4::update(|cr4| {
+            cr4.set(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION, smep);
+            cr4.set(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION, smap);
+        })
+    };
+    SMEP.store(smep, Ordering::Relaxed);
+    SMAP.store(smap, Ordering::Relaxed);
+}
+
+/// Returns true if the kernel faults when it executes code from a user page
+pub fn smep_enabled() -> bool {
+    SMEP.load(Ordering::Relaxed)
+}
+
+/// Returns true if the kernel faults when it touches a user page outside of [`with_user_access`]
+pub fn smap_enabled() -> bool {
+    SMAP.load(Ordering::Relaxed)
+}
+
+/// Runs `f` with access to user pages allowed, by setting RFLAGS.AC with `stac` until `f`
+/// returns.
+///
+/// The window should be as small as possible, ideally just the accesses to user memory. Nested
+/// windows keep access allowed until the outermost one ends
+pub fn with_user_access<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    if !smap_enabled() {
+        return f();
+    }
+    let nested = rflags::read().contains(RFlags::ALIGNMENT_CHECK);
+    // SAFETY: SMAP is supported, so `stac` exists. It is a compiler barrier, which keeps the user
+    // accesses in `f` inside of the window
+    unsafe { asm!("stac", options(nostack)) };
+    let result = f();
+    if !nested {
+        // SAFETY: Same as above
+        unsafe { asm!("clac", options(nostack)) };
+    }
+    result
+}
+
+// Copies `rdx` bytes from `rsi` to `rdi` and returns how many bytes were left uncopied, or
+// `usize::MAX` if a page couldn't be backed because memory ran out.
+//
+// `rep movsb` updates its registers as it goes, so when it faults on user memory the page fault
+// handler resumes at the fixup, which returns the remaining count in `rcx`
+global_asm!(
+    ".global copy_user_bytes",
+    ".global copy_user_bytes_copy",
+    ".global copy_user_bytes_fixup",
+    ".global copy_user_bytes_no_memory",
+    "copy_user_bytes:",
+    "cld",
+    "mov rcx, rdx",
+    "copy_user_bytes_copy:",
+    "rep movsb",
+    "copy_user_bytes_fixup:",
+    "mov rax, rcx",
+    "ret",
+    "copy_user_bytes_no_memory:",
+    "mov rax, -1",
+    "ret",
+);
+
+extern "sysv64" {
+    fn copy_user_bytes(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    fn copy_user_bytes_copy();
+    fn copy_user_bytes_fixup();
+    fn copy_user_bytes_no_memory();
+}
+
+/// What [`copy_user_bytes`] returns when memory ran out
+const NO_MEMORY: usize = usize::MAX;
+
+/// An instruction that may fault on user memory, and where to continue if it does
+struct ExceptionEntry {
+    instruction: unsafe extern "sysv64" fn(),
+    fixup: unsafe extern "sysv64" fn(),
+    /// Where to continue instead if the page couldn't be backed because memory ran out
+    no_memory_fixup: unsafe extern "sysv64" fn(),
+}
+
+/// Every instruction that is allowed to fault on user memory
+static EXCEPTION_TABLE: [ExceptionEntry; 1] = [ExceptionEntry {
+    instruction: copy_user_bytes_copy,
+    fixup: copy_user_bytes_fixup,
+    no_memory_fixup: copy_user_bytes_no_memory,
+}];
+
+/// Returns where to continue after a page fault on user memory at `rip`, or `None` if the
+/// instruction at `rip` is not allowed to fault. `out_of_memory` is set if the fault happened on
+/// valid user memory that couldn't be backed
+pub fn exception_fixup(rip: VirtAddr, out_of_memory: bool) -> Option<VirtAddr> {
+    let entry = EXCEPTION_TABLE
+        .iter()
+        .find(|entry| entry.instruction as usize as u64 == rip.as_u64())?;
+    let fixup = if out_of_memory {
+        entry.no_memory_fixup
+    } else {
+        entry.fixup
+    };
+    Some(VirtAddr::new(fixup as usize as u64))
+}
+
+/// Checks that `len` bytes starting at `addr` are all in the user half of the address space
+pub(super) fn check_user_range(addr: usize, len: usize) -> Result<()> {
+    let end = addr.checked_add(len).ok_or(Error::BadAddress)?;
+    if len != 0 && (addr < USER_START as usize || end > USER_END as usize) {
+        return Err(Error::BadAddress);
+    }
+    Ok(())
+}
+
+/// Copies `dst.len()` bytes from the user address `src` into `dst`.
+///
+/// Pages that the process hasn't touched yet are backed on the way. Fails with
+/// [`Error::BadAddress`] if any of the range is outside of user memory or can't be read, and with
+/// [`Error::OutOfMemory`] if a page couldn't be backed, in which case `dst` may be partially
+/// written
+pub fn copy_from_user(dst: &mut [u8], src: usize) -> Result<()> {
+    check_user_range(src, dst.len())?;
+    // SAFETY: `src` was checked to be a user range, so it can't alias kernel memory, and faults on
+    // it are recovered through the exception table
+    let left =
+        with_user_access(|| unsafe { copy_user_bytes(dst.as_mut_ptr(), src as _, dst.len()) });
+    match left {
+        0 => Ok(()),
+        NO_MEMORY => Err(Error::OutOfMemory),
+        _ => Err(Error::BadAddress),
+    }
+}
+
+/// Copies `src` to the user address `dst`.
+///
+/// Copy on write pages get a private copy on the way. Fails with [`Error::BadAddress`] if any of
+/// the range is outside of user memory or can't be written, and with [`Error::OutOfMemory`] if a
+/// page couldn't be backed, in which case part of `src` may have been copied
+pub fn copy_to_user(dst: usize, src: &[u8]) -> Result<()> {
+    check_user_range(dst, src.len())?;
+    // SAFETY: Same as `copy_from_user`
+    let left = with_user_access(|| unsafe { copy_user_bytes(dst as _, src.as_ptr(), src.len()) });
+    match left {
+        0 => Ok(()),
+        NO_MEMORY => Err(Error::OutOfMemory),
+        _ => Err(Error::BadAddress),
+    }
+}
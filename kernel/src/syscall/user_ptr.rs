@@ -0,0 +1,333 @@
+use alloc::{string::String, vec::Vec};
+use core::{
+    marker::PhantomData,
+    mem::{align_of, size_of, size_of_val, MaybeUninit},
+    slice,
+};
+
+use syscall::{Error, MemInfo, Result};
+use x86_64::VirtAddr;
+
+use super::{copy_from_user, copy_to_user, user_access::check_user_range};
+
+/// Types that can be copied to and from user memory
+///
+/// # Safety
+/// Every bit pattern must be a valid value of the type, and the type must have no padding, so that
+/// user memory can be copied into it byte by byte and copying it out doesn't leak kernel memory
+pub unsafe trait UserData: Copy {}
+
+// SAFETY: Integers are valid for every bit pattern and have no padding
+unsafe impl UserData for u8 {}
+unsafe impl UserData for u16 {}
+unsafe impl UserData for u32 {}
+unsafe impl UserData for u64 {}
+unsafe impl UserData for usize {}
+unsafe impl UserData for i8 {}
+unsafe impl UserData for i16 {}
+unsafe impl UserData for i32 {}
+unsafe impl UserData for i64 {}
+unsafe impl UserData for isize {}
+// SAFETY: `MemInfo` is `repr(C)` and made of `u64`s only
+unsafe impl UserData for MemInfo {}
+
+/// Checks that `count` values of `T` starting at `addr` fit in user memory and that `addr` is
+/// aligned for `T`. Returns the size of the range in bytes
+fn check_user_array<T>(addr: usize, count: usize) -> Result<usize> {
+    let bytes = count.checked_mul(size_of::<T>()).ok_or(Error::BadAddress)?;
+    if bytes == 0 {
+        // Nothing is ever accessed, like a dangling pointer of an empty rust slice
+        return Ok(0);
+    }
+    VirtAddr::try_new(addr as u64).map_err(|_| Error::BadAddress)?;
+    check_user_range(addr, bytes)?;
+    if addr % align_of::<T>() != 0 {
+        return Err(Error::InvalidArgument);
+    }
+    Ok(bytes)
+}
+
+fn as_bytes<T: UserData>(values: &[T]) -> &[u8] {
+    // SAFETY: `UserData` types have no padding, so every byte is initialized
+    unsafe { slice::from_raw_parts(values.as_ptr() as *const u8, size_of_val(values)) }
+}
+
+fn as_bytes_mut<T: UserData>(values: &mut [T]) -> &mut [u8] {
+    // SAFETY: Any bytes written are a valid `T` by the contract of `UserData`
+    unsafe { slice::from_raw_parts_mut(values.as_mut_ptr() as *mut u8, size_of_val(values)) }
+}
+
+/// A pointer to a `T` in user memory, taken from a syscall argument.
+///
+/// Creating one checks that the whole value is inside of user memory and properly aligned. Whether
+/// the memory is mapped with the right permissions is checked by every read and write, which fail
+/// with [`Error::BadAddress`] if it isn't
+#[derive(Debug)]
+pub struct UserPtr<T> {
+    addr: usize,
+    _marker: PhantomData<*mut T>,
+}
+
+impl<T> Clone for UserPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for UserPtr<T> {}
+
+impl<T: UserData> UserPtr<T> {
+    pub fn new(addr: usize) -> Result<Self> {
+        check_user_array::<T>(addr, 1)?;
+        Ok(UserPtr {
+            addr,
+            _marker: PhantomData,
+        })
+    }
+
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    /// Copies the value out of user memory
+    pub fn read(&self) -> Result<T> {
+        // SAFETY: Zeroed memory is a valid `T` by the contract of `UserData`
+        let mut value: T = unsafe { MaybeUninit::zeroed().assume_init() };
+        copy_from_user(as_bytes_mut(slice::from_mut(&mut value)), self.addr)?;
+        Ok(value)
+    }
+
+    /// Copies `value` into user memory
+    pub fn write(&self, value: &T) -> Result<()> {
+        copy_to_user(self.addr, as_bytes(slice::from_ref(value)))
+    }
+}
+
+/// `len` values of `T` in user memory, taken from a pointer and a length passed to a syscall.
+///
+/// Like [`UserPtr`], creating one checks the range and alignment, while permissions are checked
+/// when copying
+#[derive(Debug)]
+pub struct UserSlice<T> {
+    addr: usize,
+    len: usize,
+    _marker: PhantomData<*mut T>,
+}
+
+impl<T> Clone for UserSlice<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for UserSlice<T> {}
+
+impl<T: UserData> UserSlice<T> {
+    /// Empty slices are accepted at any address, as nothing is ever accessed through them
+    pub fn new(addr: usize, len: usize) -> Result<Self> {
+        check_user_array::<T>(addr, len)?;
+        Ok(UserSlice {
+            addr,
+            len,
+            _marker: PhantomData,
+        })
+    }
+
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Divides the slice in two at `mid`, like [`slice::split_at`]. Panics if `mid > len`
+    pub fn split_at(&self, mid: usize) -> (Self, Self) {
+        assert!(mid <= self.len, "split index out of bounds");
+        let second = UserSlice {
+            addr: self.addr + mid * size_of::<T>(),
+            len: self.len - mid,
+            _marker: PhantomData,
+        };
+        let first = UserSlice { len: mid, ..*self };
+        (first, second)
+    }
+
+    /// Copies the user values into `dst`, whose length must match. `dst` may be partially written
+    /// if this fails
+    pub fn copy_to_slice(&self, dst: &mut [T]) -> Result<()> {
+        assert_eq!(dst.len(), self.len, "destination length mismatch");
+        copy_from_user(as_bytes_mut(dst), self.addr)
+    }
+
+    /// Copies `src`, whose length must match, into user memory
+    pub fn copy_from_slice(&self, src: &[T]) -> Result<()> {
+        assert_eq!(src.len(), self.len, "source length mismatch");
+        copy_to_user(self.addr, as_bytes(src))
+    }
+
+    /// Copies the user values into a new vector
+    pub fn to_vec(&self) -> Result<Vec<T>> {
+        let mut values: Vec<T> = Vec::new();
+        values
+            .try_reserve_exact(self.len)
+            .map_err(|_| Error::OutOfMemory)?;
+        // SAFETY: The zeroed bytes are valid `T`s by the contract of `UserData`, and the capacity
+        // was reserved above
+        unsafe {
+            values.as_mut_ptr().write_bytes(0, self.len);
+            values.set_len(self.len);
+        }
+        self.copy_to_slice(&mut values)?;
+        Ok(values)
+    }
+}
+
+/// A UTF-8 string in user memory of at most [`UserStr::MAX_LEN`] bytes, passed to a syscall as a
+/// pointer and a length
+#[derive(Debug, Clone, Copy)]
+pub struct UserStr {
+    bytes: UserSlice<u8>,
+}
+
+impl UserStr {
+    /// The longest string that syscalls accept, so that users can't make the kernel allocate
+    /// arbitrary amounts of memory
+    pub const MAX_LEN: usize = 4096;
+
+    /// Fails with [`Error::InvalidArgument`] if `len` is longer than [`Self::MAX_LEN`]
+    pub fn new(addr: usize, len: usize) -> Result<Self> {
+        if len > Self::MAX_LEN {
+            return Err(Error::InvalidArgument);
+        }
+        Ok(UserStr {
+            bytes: UserSlice::new(addr, len)?,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Copies the string out of user memory. Fails with [`Error::InvalidArgument`] if it isn't
+    /// valid UTF-8
+    pub fn read(&self) -> Result<String> {
+        String::from_utf8(self.bytes.to_vec()?).map_err(|_| Error::InvalidArgument)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{USER_END, USER_START};
+
+    const START: usize = USER_START as usize;
+    const END: usize = USER_END as usize;
+
+    #[test_case]
+    fn zero_length_slices() {
+        for addr in [0, START, END, 0x8000_0000_0000, usize::MAX] {
+            let slice = UserSlice::<u64>::new(addr, 0).unwrap();
+            assert!(slice.is_empty());
+            slice.copy_to_slice(&mut []).unwrap();
+            slice.copy_from_slice(&[]).unwrap();
+        }
+        assert!(UserStr::new(0, 0).unwrap().is_empty());
+    }
+
+    #[test_case]
+    fn ranges_must_stay_in_user_memory() {
+        assert!(UserSlice::<u8>::new(START, 16).is_ok());
+        assert!(UserSlice::<u8>::new(END - 16, 16).is_ok());
+        assert!(matches!(
+            UserSlice::<u8>::new(END - 16, 17),
+            Err(Error::BadAddress)
+        ));
+        assert!(matches!(
+            UserSlice::<u8>::new(START - 1, 16),
+            Err(Error::BadAddress)
+        ));
+        assert!(matches!(
+            UserPtr::<u64>::new(END - 4),
+            Err(Error::BadAddress)
+        ));
+        // Across the top of the lower half, and past the end of the address space
+        assert!(matches!(
+            UserSlice::<u8>::new(0x7FFF_FFFF_F000, 0x2000),
+            Err(Error::BadAddress)
+        ));
+        assert!(matches!(
+            UserSlice::<u8>::new(usize::MAX - 8, 16),
+            Err(Error::BadAddress)
+        ));
+    }
+
+    #[test_case]
+    fn lengths_must_not_overflow() {
+        assert!(matches!(
+            UserSlice::<u64>::new(START, usize::MAX / 4),
+            Err(Error::BadAddress)
+        ));
+        assert!(matches!(
+            UserSlice::<u8>::new(START, usize::MAX),
+            Err(Error::BadAddress)
+        ));
+    }
+
+    #[test_case]
+    fn non_canonical_addresses() {
+        for addr in [0x8000_0000_0000, 0x1234_5678_9ABC_DEF0] {
+            assert!(matches!(UserPtr::<u8>::new(addr), Err(Error::BadAddress)));
+            assert!(matches!(
+                UserSlice::<u8>::new(addr, 1),
+                Err(Error::BadAddress)
+            ));
+        }
+        // Canonical, but in the kernel's half
+        assert!(matches!(
+            UserPtr::<u8>::new(0xFFFF_8000_0000_0000),
+            Err(Error::BadAddress)
+        ));
+    }
+
+    #[test_case]
+    fn alignment() {
+        assert!(UserPtr::<u64>::new(START + 8).is_ok());
+        assert!(matches!(
+            UserPtr::<u64>::new(START + 4),
+            Err(Error::InvalidArgument)
+        ));
+        assert!(matches!(
+            UserSlice::<u32>::new(START + 2, 4),
+            Err(Error::InvalidArgument)
+        ));
+        assert!(UserSlice::<u8>::new(START + 3, 4).is_ok());
+    }
+
+    #[test_case]
+    fn split_at() {
+        let slice = UserSlice::<u32>::new(START, 10).unwrap();
+        let (first, second) = slice.split_at(4);
+        assert_eq!((first.addr(), first.len()), (START, 4));
+        assert_eq!((second.addr(), second.len()), (START + 16, 6));
+        assert!(slice.split_at(10).1.is_empty());
+    }
+
+    #[test_case]
+    fn strings_are_bounded() {
+        assert_eq!(UserStr::new(START, UserStr::MAX_LEN).unwrap().len(), 4096);
+        assert!(matches!(
+            UserStr::new(START, UserStr::MAX_LEN + 1),
+            Err(Error::InvalidArgument)
+        ));
+        assert!(matches!(UserStr::new(END - 2, 4), Err(Error::BadAddress)));
+    }
+}
@@ -0,0 +1,7 @@
+#!/bin/bash
+# Runs the tests on a CPU with FSGSBASE and SMAP, which `test-args` asks for, and then again on
+# Qemu's default CPU model, which has neither. Arguments after `--` are passed on to Qemu, where
+# the last `-cpu` wins
+set -e
+cargo test "$@"
+cargo test "$@" -- -cpu qemu64
@@ -0,0 +1,535 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(zulu_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::{
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            mapper::{MappedFrame, TranslateResult},
+            Page, PageTableFlags, PhysFrame, Translate,
+        },
+    },
+    VirtAddr,
+};
+use zulu_os::memory::{
+    self, AddressSpace, FaultError, Vma, VmaError, VmaKind, COPY_ON_WRITE, USER_END, USER_START,
+};
+use zulu_os::syscall::with_user_access;
+use zulu_os::{percpu, test_user_page, TEST_USER_FLAGS};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    zulu_os::init(boot_info);
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut frame_allocator = memory::frame_allocator();
+    // VMAs live on the heap
+    unsafe {
+        memory::init(phys_mem_offset, &boot_info.memory_map).with(|mapper| {
+            zulu_os::allocator::init_kernel_heap(mapper, &mut frame_allocator)
+                .expect("heap initialization failed")
+        })
+    };
+
+    zulu_os::warm_vma_cache();
+
+    test_main();
+    zulu_os::sys::hlt_loop()
+}
+
+#[test_case]
+fn drop_frees_everything() {
+    let before = memory::frame_stats();
+    {
+        let mut space = AddressSpace::new().unwrap();
+        for n in 0..16 {
+            space.map(test_user_page(n), TEST_USER_FLAGS).unwrap();
+        }
+        // Far away pages need their own page tables
+        space.map(test_user_page(1 << 27), TEST_USER_FLAGS).unwrap();
+        assert!(memory::frame_stats().free < before.free);
+    }
+    assert_eq!(memory::frame_stats(), before);
+}
+
+#[test_case]
+fn unmap_frees_frame() {
+    let mut space = AddressSpace::new().unwrap();
+    space.map(test_user_page(0), TEST_USER_FLAGS).unwrap();
+    let before = memory::frame_stats();
+    space.unmap(test_user_page(0)).unwrap();
+    assert_eq!(memory::frame_stats().free, before.free + 1);
+    assert!(matches!(
+        space.translate(test_user_page(0).start_address()),
+        TranslateResult::NotMapped
+    ));
+}
+
+#[test_case]
+fn user_mappings_are_private() {
+    let mut a = AddressSpace::new().unwrap();
+    let mut b = AddressSpace::new().unwrap();
+    a.map(test_user_page(0), TEST_USER_FLAGS).unwrap();
+    b.map(test_user_page(0), TEST_USER_FLAGS).unwrap();
+    a.write(test_user_page(0).start_address(), b"from a");
+    b.write(test_user_page(0).start_address(), b"from b");
+
+    let ptr = test_user_page(0).start_address().as_ptr::<[u8; 6]>();
+    unsafe {
+        a.activate();
+        with_user_access(|| assert_eq!(&*ptr, b"from a"));
+        b.activate();
+        with_user_access(|| assert_eq!(&*ptr, b"from b"));
+    }
+    assert_eq!(percpu!(address_space).get(), Some(b.level_4_frame()));
+
+    // The kernel's own table never sees user mappings
+    let addr = test_user_page(0).start_address();
+    memory::mapper()
+        .with(|mapper| assert!(matches!(mapper.translate(addr), TranslateResult::NotMapped)));
+
+    // Dropping the active address space switches back to the kernel's table
+    drop(b);
+    assert_eq!(
+        x86_64::registers::control::Cr3::read().0,
+        memory::kernel_level_4_frame()
+    );
+    assert_eq!(percpu!(address_space).get(), None);
+}
+
+#[test_case]
+fn kernel_half_is_shared() {
+    let mut space = AddressSpace::new().unwrap();
+    let addr = VirtAddr::new(main as usize as u64);
+    let kernel = memory::mapper().with(|mapper| mapper.translate_addr(addr));
+    assert!(kernel.is_some());
+    assert_eq!(space.mapper().translate_addr(addr), kernel);
+}
+
+#[test_case]
+fn vma_pages_are_backed_on_fault() {
+    let mut space = AddressSpace::new().unwrap();
+    let vma = Vma::new(
+        test_user_page(4).start_address(),
+        test_user_page(8).start_address(),
+        TEST_USER_FLAGS,
+        VmaKind::Anonymous,
+    );
+    space.add_vma(vma.clone()).unwrap();
+    assert_eq!(space.add_vma(vma), Err(VmaError::Overlaps));
+
+    // Nothing is mapped until the first access
+    let addr = test_user_page(5).start_address() + 12u64;
+    assert!(matches!(space.translate(addr), TranslateResult::NotMapped));
+    let before = memory::frame_stats();
+    space
+        .handle_fault(
+            addr,
+            PageFaultErrorCode::USER_MODE | PageFaultErrorCode::CAUSED_BY_WRITE,
+        )
+        .unwrap();
+    assert!(matches!(
+        space.translate(addr),
+        TranslateResult::Mapped { .. }
+    ));
+    assert!(memory::frame_stats().free < before.free);
+
+    let fetch = PageFaultErrorCode::USER_MODE | PageFaultErrorCode::INSTRUCTION_FETCH;
+    assert_eq!(
+        space.handle_fault(test_user_page(6).start_address(), fetch),
+        Err(FaultError::AccessViolation)
+    );
+    assert_eq!(
+        space.handle_fault(
+            test_user_page(8).start_address(),
+            PageFaultErrorCode::USER_MODE
+        ),
+        Err(FaultError::NoVma)
+    );
+}
+
+/// Returns the frame and flags that `addr` is mapped with
+fn mapping(space: &mut AddressSpace, addr: VirtAddr) -> (PhysFrame, PageTableFlags) {
+    match space.translate(addr) {
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(frame),
+            flags,
+            ..
+        } => (frame, flags),
+        _ => panic!("{:?} is not mapped", addr),
+    }
+}
+
+#[test_case]
+fn fork_shares_frames_until_written() {
+    let before = memory::frame_stats();
+    {
+        let mut parent = AddressSpace::new().unwrap();
+        let vma = Vma::new(
+            test_user_page(0).start_address(),
+            test_user_page(4).start_address(),
+            TEST_USER_FLAGS,
+            VmaKind::Anonymous,
+        );
+        parent.add_vma(vma).unwrap();
+        let addr = test_user_page(1).start_address();
+        let write = PageFaultErrorCode::USER_MODE | PageFaultErrorCode::CAUSED_BY_WRITE;
+        parent.handle_fault(addr, write).unwrap();
+        parent.write(addr, b"parent");
+
+        let mut child = parent.fork().unwrap();
+        assert_eq!(child.vmas().count(), 1);
+        let (parent_frame, parent_flags) = mapping(&mut parent, addr);
+        let (child_frame, child_flags) = mapping(&mut child, addr);
+        assert_eq!(parent_frame, child_frame);
+        for flags in [parent_flags, child_flags] {
+            assert!(flags.contains(COPY_ON_WRITE));
+            assert!(!flags.contains(PageTableFlags::WRITABLE));
+        }
+        assert_eq!(memory::frame_refcount(parent_frame), 2);
+
+        // The first write gets its own copy, leaving the parent alone
+        let protection = write | PageFaultErrorCode::PROTECTION_VIOLATION;
+        child.handle_fault(addr, protection).unwrap();
+        child.write(addr, b"child!");
+        let (copy, flags) = mapping(&mut child, addr);
+        assert_ne!(copy, parent_frame);
+        assert!(flags.contains(PageTableFlags::WRITABLE) && !flags.contains(COPY_ON_WRITE));
+        assert_eq!(memory::frame_refcount(parent_frame), 1);
+
+        let ptr = addr.as_ptr::<[u8; 6]>();
+        unsafe {
+            parent.activate();
+            with_user_access(|| assert_eq!(&*ptr, b"parent"));
+            child.activate();
+            with_user_access(|| assert_eq!(&*ptr, b"child!"));
+        }
+        drop(child);
+
+        // The parent is the last owner, so it writes to the frame in place
+        parent.handle_fault(addr, protection).unwrap();
+        let (frame, flags) = mapping(&mut parent, addr);
+        assert_eq!(frame, parent_frame);
+        assert!(flags.contains(PageTableFlags::WRITABLE));
+    }
+    assert_eq!(memory::frame_stats(), before);
+}
+
+#[test_case]
+fn anonymous_regions_use_huge_pages() {
+    const HUGE: u64 = 2 * 1024 * 1024;
+    let before = memory::frame_stats();
+    {
+        let mut space = AddressSpace::new().unwrap();
+        // One full huge page, plus a 4KiB tail that can't use one
+        let start = VirtAddr::new(USER_START + HUGE);
+        let vma = Vma::new(
+            start,
+            start + HUGE + 4096u64,
+            TEST_USER_FLAGS,
+            VmaKind::Anonymous,
+        );
+        space.add_vma(vma).unwrap();
+        let write = PageFaultErrorCode::USER_MODE | PageFaultErrorCode::CAUSED_BY_WRITE;
+
+        space.handle_fault(start + 3 * 4096u64, write).unwrap();
+        let TranslateResult::Mapped {
+            frame: MappedFrame::Size2MiB(huge),
+            ..
+        } = space.translate(start) else {
+            panic!("expected a huge page");
+        };
+        space.handle_fault(start + HUGE, write).unwrap();
+        mapping(&mut space, start + HUGE);
+        assert_eq!(space.resident_pages(), 512 + 1);
+
+        // Unmapping one page splits the huge page, and the rest keeps its frames and data
+        let kept = start + 5 * 4096u64;
+        space.write(kept, b"kept");
+        space
+            .unmap(Page::containing_address(start + 4096u64))
+            .unwrap();
+        assert!(matches!(
+            space.translate(start + 4096u64),
+            TranslateResult::NotMapped
+        ));
+        assert_eq!(space.resident_pages(), 512);
+        let (frame, flags) = mapping(&mut space, kept);
+        assert_eq!(frame.start_address(), huge.start_address() + 5 * 4096u64);
+        assert!(!flags.contains(PageTableFlags::HUGE_PAGE));
+        unsafe {
+            space.activate();
+            with_user_access(|| assert_eq!(&*kept.as_ptr::<[u8; 4]>(), b"kept"));
+        }
+
+        // Changing flags only affects a single page
+        let read_only = TEST_USER_FLAGS - PageTableFlags::WRITABLE;
+        space
+            .update_flags(Page::containing_address(kept), read_only)
+            .unwrap();
+        let writable = |space: &mut AddressSpace, addr| {
+            mapping(space, addr).1.contains(PageTableFlags::WRITABLE)
+        };
+        assert!(!writable(&mut space, kept));
+        assert!(writable(&mut space, kept + 4096u64));
+    }
+    assert_eq!(memory::frame_stats(), before);
+}
+
+fn anonymous(space: &mut AddressSpace, pages: core::ops::Range<u64>, flags: PageTableFlags) {
+    let start = test_user_page(pages.start).start_address();
+    let end = test_user_page(pages.end).start_address();
+    space
+        .add_vma(Vma::new(start, end, flags, VmaKind::Anonymous))
+        .unwrap();
+}
+
+fn vma_ranges(space: &AddressSpace) -> Vec<(u64, u64, PageTableFlags)> {
+    let page = |addr: VirtAddr| (addr.as_u64() - USER_START) / 4096;
+    space
+        .vmas()
+        .map(|vma| (page(vma.start), page(vma.end), vma.flags))
+        .collect()
+}
+
+#[test_case]
+fn writable_and_executable_vmas_are_rejected() {
+    let mut space = AddressSpace::new().unwrap();
+    let start = test_user_page(0).start_address();
+    let end = test_user_page(1).start_address();
+    let wx = TEST_USER_FLAGS - PageTableFlags::NO_EXECUTE;
+    assert_eq!(
+        space.add_vma(Vma::new(start, end, wx, VmaKind::Anonymous)),
+        Err(VmaError::WritableAndExecutable)
+    );
+    anonymous(&mut space, 0..1, TEST_USER_FLAGS);
+    assert_eq!(
+        space.protect(start, end, wx),
+        Err(VmaError::WritableAndExecutable)
+    );
+}
+
+#[test_case]
+fn unmap_range_splits_vmas() {
+    let before = memory::frame_stats();
+    {
+        let mut space = AddressSpace::new().unwrap();
+        anonymous(&mut space, 0..8, TEST_USER_FLAGS);
+        let write = PageFaultErrorCode::USER_MODE | PageFaultErrorCode::CAUSED_BY_WRITE;
+        for n in 0..8 {
+            space
+                .handle_fault(test_user_page(n).start_address(), write)
+                .unwrap();
+        }
+
+        let addr = |n| test_user_page(n).start_address();
+        space.unmap_range(addr(2), addr(4)).unwrap();
+        assert_eq!(
+            vma_ranges(&space),
+            [(0, 2, TEST_USER_FLAGS), (4, 8, TEST_USER_FLAGS)]
+        );
+        assert_eq!(space.resident_pages(), 6);
+        assert!(matches!(
+            space.translate(addr(3)),
+            TranslateResult::NotMapped
+        ));
+        mapping(&mut space, addr(4));
+
+        // Holes are skipped, but the range must be page aligned and non-empty
+        space.unmap_range(addr(2), addr(4)).unwrap();
+        assert_eq!(
+            space.unmap_range(addr(0) + 1u64, addr(4)),
+            Err(VmaError::InvalidRange)
+        );
+        assert_eq!(
+            space.unmap_range(addr(4), addr(4)),
+            Err(VmaError::InvalidRange)
+        );
+
+        space.unmap_range(addr(0), addr(8)).unwrap();
+        assert_eq!(space.vmas().count(), 0);
+        assert_eq!(space.resident_pages(), 0);
+    }
+    assert_eq!(memory::frame_stats(), before);
+}
+
+#[test_case]
+fn resize_vma_moves_end_in_place() {
+    let before = memory::frame_stats();
+    {
+        let mut space = AddressSpace::new().unwrap();
+        anonymous(&mut space, 0..2, TEST_USER_FLAGS);
+        anonymous(&mut space, 6..8, TEST_USER_FLAGS);
+        let addr = |n| test_user_page(n).start_address();
+
+        space.resize_vma(addr(2), addr(4)).unwrap();
+        assert_eq!(
+            vma_ranges(&space),
+            [(0, 4, TEST_USER_FLAGS), (6, 8, TEST_USER_FLAGS)]
+        );
+        assert_eq!(space.resize_vma(addr(4), addr(7)), Err(VmaError::Overlaps));
+        assert_eq!(space.resize_vma(addr(5), addr(6)), Err(VmaError::NotMapped));
+
+        let write = PageFaultErrorCode::USER_MODE | PageFaultErrorCode::CAUSED_BY_WRITE;
+        for n in 0..4 {
+            space.handle_fault(addr(n), write).unwrap();
+        }
+        space.resize_vma(addr(4), addr(1)).unwrap();
+        assert_eq!(
+            vma_ranges(&space),
+            [(0, 1, TEST_USER_FLAGS), (6, 8, TEST_USER_FLAGS)]
+        );
+        assert_eq!(space.resident_pages(), 1);
+        assert_eq!(
+            space.resize_vma(addr(1), addr(0)),
+            Err(VmaError::InvalidRange)
+        );
+    }
+    assert_eq!(memory::frame_stats(), before);
+}
+
+#[test_case]
+fn vmas_come_from_slab_cache() {
+    let objects = || {
+        let stats = zulu_os::allocator::slab_stats();
+        let (_, vma) = stats.iter().find(|&&(name, _)| name == "vma").unwrap();
+        vma.objects
+    };
+    let before = objects();
+    {
+        let mut space = AddressSpace::new().unwrap();
+        anonymous(&mut space, 0..8, TEST_USER_FLAGS);
+        let addr = |n| test_user_page(n).start_address();
+        space.unmap_range(addr(2), addr(4)).unwrap();
+        assert_eq!(objects(), before + 2);
+        let _child = space.fork().unwrap();
+        assert_eq!(objects(), before + 4);
+    }
+    assert_eq!(objects(), before);
+}
+
+#[test_case]
+fn replace_vma_keeps_old_mapping_on_error() {
+    let mut space = AddressSpace::new().unwrap();
+    anonymous(&mut space, 0..4, TEST_USER_FLAGS);
+    let addr = |n| test_user_page(n).start_address();
+    let write = PageFaultErrorCode::USER_MODE | PageFaultErrorCode::CAUSED_BY_WRITE;
+    space.handle_fault(addr(1), write).unwrap();
+
+    let wx = TEST_USER_FLAGS - PageTableFlags::NO_EXECUTE;
+    assert_eq!(
+        space.replace_vma(Vma::new(addr(0), addr(2), wx, VmaKind::Anonymous)),
+        Err(VmaError::WritableAndExecutable)
+    );
+    assert_eq!(vma_ranges(&space), [(0, 4, TEST_USER_FLAGS)]);
+    assert_eq!(space.resident_pages(), 1);
+    mapping(&mut space, addr(1));
+
+    let read_only = TEST_USER_FLAGS - PageTableFlags::WRITABLE;
+    space
+        .replace_vma(Vma::new(addr(0), addr(2), read_only, VmaKind::Anonymous))
+        .unwrap();
+    assert_eq!(
+        vma_ranges(&space),
+        [(0, 2, read_only), (2, 4, TEST_USER_FLAGS)]
+    );
+    assert_eq!(space.resident_pages(), 0);
+}
+
+#[test_case]
+fn protect_changes_vmas_and_pages() {
+    let mut space = AddressSpace::new().unwrap();
+    anonymous(&mut space, 0..4, TEST_USER_FLAGS);
+    let write = PageFaultErrorCode::USER_MODE | PageFaultErrorCode::CAUSED_BY_WRITE;
+    let addr = |n| test_user_page(n).start_address();
+    space.handle_fault(addr(1), write).unwrap();
+    space.handle_fault(addr(2), write).unwrap();
+
+    let read_only = TEST_USER_FLAGS - PageTableFlags::WRITABLE;
+    space.protect(addr(1), addr(2), read_only).unwrap();
+    assert_eq!(
+        vma_ranges(&space),
+        [
+            (0, 1, TEST_USER_FLAGS),
+            (1, 2, read_only),
+            (2, 4, TEST_USER_FLAGS)
+        ]
+    );
+    assert!(!mapping(&mut space, addr(1))
+        .1
+        .contains(PageTableFlags::WRITABLE));
+    assert!(mapping(&mut space, addr(2))
+        .1
+        .contains(PageTableFlags::WRITABLE));
+    assert_eq!(
+        space.handle_fault(addr(1), write),
+        Err(FaultError::AccessViolation)
+    );
+
+    // Every page of the range must be inside a VMA
+    assert_eq!(
+        space.protect(addr(2), addr(8), read_only),
+        Err(VmaError::NotMapped)
+    );
+    assert_eq!(vma_ranges(&space)[2], (2, 4, TEST_USER_FLAGS));
+}
+
+#[test_case]
+fn protect_keeps_shared_pages_copy_on_write() {
+    let before = memory::frame_stats();
+    {
+        let read_only = TEST_USER_FLAGS - PageTableFlags::WRITABLE;
+        let addr = test_user_page(0).start_address();
+        let mut parent = AddressSpace::new().unwrap();
+        anonymous(&mut parent, 0..1, read_only);
+        parent.map(test_user_page(0), read_only).unwrap();
+        parent.write(addr, b"parent");
+        let mut child = parent.fork().unwrap();
+        let (shared, _) = mapping(&mut parent, addr);
+
+        // The frame is still shared, so making it writable must not let the child write to it
+        child
+            .protect(addr, addr + 4096u64, TEST_USER_FLAGS)
+            .unwrap();
+        let (frame, flags) = mapping(&mut child, addr);
+        assert_eq!(frame, shared);
+        assert!(!flags.contains(PageTableFlags::WRITABLE) && flags.contains(COPY_ON_WRITE));
+
+        let write = PageFaultErrorCode::USER_MODE | PageFaultErrorCode::CAUSED_BY_WRITE;
+        child.handle_fault(addr, write).unwrap();
+        assert_ne!(mapping(&mut child, addr).0, shared);
+        assert_eq!(mapping(&mut parent, addr).0, shared);
+    }
+    assert_eq!(memory::frame_stats(), before);
+}
+
+#[test_case]
+fn find_free_range_skips_vmas() {
+    let mut space = AddressSpace::new().unwrap();
+    anonymous(&mut space, 2..4, TEST_USER_FLAGS);
+    anonymous(&mut space, 5..6, TEST_USER_FLAGS);
+    let addr = |n| test_user_page(n).start_address();
+    assert_eq!(space.find_free_range(addr(0), 2 * 4096), Some(addr(0)));
+    assert_eq!(space.find_free_range(addr(1), 2 * 4096), Some(addr(6)));
+    assert_eq!(space.find_free_range(addr(4), 4096), Some(addr(4)));
+    // Unaligned and kernel addresses are moved up into user memory
+    assert_eq!(space.find_free_range(addr(4) + 1u64, 4096), Some(addr(6)));
+    assert_eq!(space.find_free_range(VirtAddr::new(0), 4096), Some(addr(0)));
+    assert_eq!(
+        space.find_free_range(VirtAddr::new(USER_END - 4096), 2 * 4096),
+        None
+    );
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    zulu_os::test_panic_handler(info)
+}
@@ -0,0 +1,150 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(zulu_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::{structures::paging::PhysFrame, VirtAddr};
+use zulu_os::memory::{self, BuddyAllocator, MAX_ORDER};
+
+entry_point!(main);
+
+static mut PHYS_MEM_OFFSET: u64 = 0;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    zulu_os::init(boot_info);
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset, &boot_info.memory_map) };
+    unsafe { PHYS_MEM_OFFSET = boot_info.physical_memory_offset };
+
+    test_main();
+    zulu_os::sys::hlt_loop()
+}
+
+const BLOCK_FRAMES: usize = 1 << MAX_ORDER;
+
+/// Runs `f` with a private buddy allocator that owns a single max order block taken from the
+/// global contiguous zone
+fn with_test_allocator(f: impl FnOnce(&mut BuddyAllocator)) {
+    static mut BLOCK_ORDERS: [u8; BLOCK_FRAMES] = [0; BLOCK_FRAMES];
+
+    let block = memory::allocate_contiguous(MAX_ORDER).expect("no free max order block");
+    let frames = PhysFrame::range(block, block + BLOCK_FRAMES as u64);
+    let phys_mem_offset = VirtAddr::new(unsafe { PHYS_MEM_OFFSET });
+    // SAFETY: Tests run one at a time, and the block is owned by this test until it is freed below
+    let mut buddy = unsafe { BuddyAllocator::new(frames, &mut BLOCK_ORDERS, phys_mem_offset) };
+    assert_eq!(buddy.free_blocks(MAX_ORDER), 1);
+
+    f(&mut buddy);
+
+    unsafe { memory::deallocate_contiguous(block, MAX_ORDER) };
+}
+
+fn is_aligned(frame: PhysFrame, order: usize) -> bool {
+    frame.start_address().as_u64() % (4096 << order) == 0
+}
+
+#[test_case]
+fn global_allocations_are_aligned() {
+    for order in 0..=MAX_ORDER {
+        let frame = memory::allocate_contiguous(order).unwrap();
+        assert!(is_aligned(frame, order));
+        unsafe { memory::deallocate_contiguous(frame, order) };
+    }
+    assert!(memory::allocate_contiguous(MAX_ORDER + 1).is_none());
+}
+
+#[test_case]
+fn split_blocks_do_not_overlap() {
+    with_test_allocator(|buddy| {
+        let big = buddy.allocate(3).unwrap();
+        let small = buddy.allocate(0).unwrap();
+        let medium = buddy.allocate(2).unwrap();
+        assert!(is_aligned(big, 3) && is_aligned(small, 0) && is_aligned(medium, 2));
+
+        let overlaps = |a: PhysFrame, a_order: usize, b: PhysFrame, b_order: usize| {
+            let a_end = a + (1u64 << a_order);
+            let b_end = b + (1u64 << b_order);
+            a < b_end && b < a_end
+        };
+        assert!(!overlaps(big, 3, small, 0));
+        assert!(!overlaps(big, 3, medium, 2));
+        assert!(!overlaps(small, 0, medium, 2));
+        assert_eq!(buddy.free_frames(), BLOCK_FRAMES - 8 - 1 - 4);
+
+        unsafe {
+            buddy.deallocate(medium, 2);
+            buddy.deallocate(big, 3);
+            buddy.deallocate(small, 0);
+        }
+        assert_eq!(buddy.free_blocks(MAX_ORDER), 1);
+    });
+}
+
+#[test_case]
+fn frees_merge_back_into_one_block() {
+    with_test_allocator(|buddy| {
+        let mut frames = [None; BLOCK_FRAMES];
+        for slot in frames.iter_mut() {
+            *slot = buddy.allocate(0);
+        }
+        assert_eq!(buddy.free_frames(), 0);
+        assert!(buddy.allocate(0).is_none());
+
+        // Free in an order that leaves no buddies adjacent until the very end
+        for start in [3, 1, 2, 0] {
+            for frame in frames.iter().skip(start).step_by(4) {
+                unsafe { buddy.deallocate(frame.unwrap(), 0) };
+            }
+        }
+        assert_eq!(buddy.free_frames(), BLOCK_FRAMES);
+        assert_eq!(buddy.free_blocks(MAX_ORDER), 1);
+        for order in 0..MAX_ORDER {
+            assert_eq!(buddy.free_blocks(order), 0);
+        }
+        assert!(buddy.allocate(MAX_ORDER).is_some());
+    });
+}
+
+#[test_case]
+fn fragmentation_blocks_larger_orders() {
+    with_test_allocator(|buddy| {
+        let mut frames = [None; BLOCK_FRAMES];
+        for slot in frames.iter_mut() {
+            *slot = buddy.allocate(0);
+        }
+
+        // Free every other frame: half the memory is free but no two free frames are buddies
+        for frame in frames.iter().step_by(2) {
+            unsafe { buddy.deallocate(frame.unwrap(), 0) };
+        }
+        assert_eq!(buddy.free_frames(), BLOCK_FRAMES / 2);
+        assert_eq!(buddy.free_blocks(0), BLOCK_FRAMES / 2);
+        assert!(buddy.allocate(1).is_none());
+
+        // Filling the holes lets everything merge again
+        for frame in frames.iter().skip(1).step_by(2) {
+            unsafe { buddy.deallocate(frame.unwrap(), 0) };
+        }
+        assert_eq!(buddy.free_blocks(MAX_ORDER), 1);
+    });
+}
+
+#[test_case]
+fn large_allocation_can_be_freed_frame_by_frame() {
+    with_test_allocator(|buddy| {
+        let block = buddy.allocate(4).unwrap();
+        for i in 0..16u64 {
+            unsafe { buddy.deallocate(block + i, 0) };
+        }
+        assert_eq!(buddy.free_frames(), BLOCK_FRAMES);
+        assert_eq!(buddy.free_blocks(MAX_ORDER), 1);
+    });
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    zulu_os::test_panic_handler(info)
+}
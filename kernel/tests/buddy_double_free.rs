@@ -0,0 +1,49 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::{structures::paging::PhysFrame, VirtAddr};
+use zulu_os::{
+    exit_qemu,
+    memory::{self, BuddyAllocator, MAX_ORDER},
+    serial_print, serial_println, QemuExitCode,
+};
+
+entry_point!(main);
+
+const EXPECTED: &str = "double free of";
+
+const BLOCK_FRAMES: usize = 1 << MAX_ORDER;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("buddy_double_free::merged_frame_double_free_panics...\t");
+
+    zulu_os::init(boot_info);
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset, &boot_info.memory_map) };
+
+    static mut BLOCK_ORDERS: [u8; BLOCK_FRAMES] = [0; BLOCK_FRAMES];
+    let block = memory::allocate_contiguous(MAX_ORDER).expect("no free max order block");
+    let frames = PhysFrame::range(block, block + BLOCK_FRAMES as u64);
+    let mut buddy = unsafe { BuddyAllocator::new(frames, &mut BLOCK_ORDERS, phys_mem_offset) };
+
+    let first = buddy.allocate(0).unwrap();
+    let second = buddy.allocate(0).unwrap();
+    assert_eq!(second, first + 1);
+    unsafe {
+        buddy.deallocate(first, 0);
+        // Merges back into the max order block that starts at `first`
+        buddy.deallocate(second, 0);
+        assert_eq!(buddy.free_blocks(MAX_ORDER), 1);
+        buddy.deallocate(second, 0);
+    }
+
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed)
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    zulu_os::expected_panic_handler(info, EXPECTED)
+}
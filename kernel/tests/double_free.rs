@@ -0,0 +1,36 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator},
+    VirtAddr,
+};
+use zulu_os::{exit_qemu, memory, serial_print, serial_println, QemuExitCode};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("double_free::double_free_panics...\t");
+
+    zulu_os::init(boot_info);
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset, &boot_info.memory_map) };
+
+    let mut allocator = memory::frame_allocator();
+    let frame = allocator.allocate_frame().unwrap();
+    unsafe {
+        allocator.deallocate_frame(frame);
+        allocator.deallocate_frame(frame);
+    }
+
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed)
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success)
+}
@@ -0,0 +1,124 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(zulu_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec;
+use bootloader::{entry_point, BootInfo};
+use core::{panic::PanicInfo, slice};
+use object::elf::{PF_W, PF_X};
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+use zulu_os::{
+    elf::{self, MapError},
+    memory::{self, AddressSpace, VmaError, USER_END, USER_START},
+    process::DEFAULT_IMAGE_BASE,
+};
+
+entry_point!(main);
+
+/// The elf parser reads the headers in place, so the image has to be aligned
+#[derive(Clone, Copy)]
+#[repr(C, align(4096))]
+struct Aligned<T: ?Sized>(T);
+
+static PROGRAM: &Aligned<[u8]> = &Aligned(*include_bytes!("../processes/userspace_test"));
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    zulu_os::init(boot_info);
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut frame_allocator = memory::frame_allocator();
+    unsafe {
+        memory::init(phys_mem_offset, &boot_info.memory_map).with(|mapper| {
+            zulu_os::allocator::init_kernel_heap(mapper, &mut frame_allocator)
+                .expect("heap initialization failed")
+        })
+    };
+
+    test_main();
+    zulu_os::sys::hlt_loop()
+}
+
+/// Where the executable segment of a loaded image starts
+fn text_start(image: &elf::ElfFile) -> VirtAddr {
+    image
+        .segments
+        .iter()
+        .filter(|segment| !segment.flags.contains(PageTableFlags::NO_EXECUTE))
+        .last()
+        .expect("image has an executable segment")
+        .addr
+        .start
+}
+
+#[test_case]
+fn image_fits_at_lowest_base() {
+    let mut space = AddressSpace::new().unwrap();
+    let image = elf::load(&PROGRAM.0, &mut space, VirtAddr::new(USER_START)).unwrap();
+    for vma in space.vmas() {
+        assert!(vma.start.as_u64() >= USER_START && vma.end.as_u64() <= USER_END);
+    }
+    // Moved up only as far as the segments below the text need
+    assert_eq!(space.vmas().next().unwrap().start.as_u64(), USER_START);
+    let entry = space.vma(image.entry_point).expect("entry point is mapped");
+    assert!(!entry.flags.contains(PageTableFlags::NO_EXECUTE));
+}
+
+#[test_case]
+fn image_is_loaded_at_base() {
+    let mut space = AddressSpace::new().unwrap();
+    let base = VirtAddr::new(DEFAULT_IMAGE_BASE);
+    let image = elf::load(&PROGRAM.0, &mut space, base).unwrap();
+    assert_eq!(text_start(&image), base);
+}
+
+#[test_case]
+fn malformed_image_is_rejected() {
+    let mut space = AddressSpace::new().unwrap();
+    let bytes = Aligned(*b"not an elf file");
+    let result = elf::load(&bytes.0, &mut space, VirtAddr::new(DEFAULT_IMAGE_BASE));
+    assert!(matches!(result, Err(MapError::InvalidElf)));
+    assert_eq!(space.vmas().count(), 0);
+}
+
+#[test_case]
+fn writable_executable_segment_is_rejected() {
+    // A copy of the program whose executable segments are made writable as well
+    let mut pages = vec![Aligned([0u8; 4096]); (PROGRAM.0.len() + 4095) / 4096];
+    // SAFETY: The pages are contiguous, and at least as long as the program
+    let bytes =
+        unsafe { slice::from_raw_parts_mut(pages.as_mut_ptr().cast::<u8>(), PROGRAM.0.len()) };
+    bytes.copy_from_slice(&PROGRAM.0);
+    let field = |bytes: &[u8], offset: usize, len: usize| {
+        let mut value = [0; 8];
+        value[..len].copy_from_slice(&bytes[offset..offset + len]);
+        u64::from_le_bytes(value) as usize
+    };
+    // `e_phoff`, `e_phentsize` and `e_phnum` of the file header
+    let (headers, header_size, count) = (
+        field(bytes, 0x20, 8),
+        field(bytes, 0x36, 2),
+        field(bytes, 0x38, 2),
+    );
+    // `p_flags` is the second field of each program header
+    for header in (0..count).map(|i| headers + i * header_size) {
+        if field(bytes, header + 4, 4) as u32 & PF_X != 0 {
+            bytes[header + 4] |= PF_W as u8;
+        }
+    }
+
+    let mut space = AddressSpace::new().unwrap();
+    let result = elf::load(bytes, &mut space, VirtAddr::new(DEFAULT_IMAGE_BASE));
+    assert!(matches!(
+        result,
+        Err(MapError::Vma(VmaError::WritableAndExecutable))
+    ));
+    assert_eq!(space.resident_pages(), 0);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    zulu_os::test_panic_handler(info)
+}
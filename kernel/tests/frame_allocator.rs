@@ -0,0 +1,71 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(zulu_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame};
+use zulu_os::memory;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+
+    zulu_os::init(boot_info);
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset, &boot_info.memory_map) };
+
+    test_main();
+    zulu_os::sys::hlt_loop()
+}
+
+#[test_case]
+fn counts_track_allocations() {
+    let mut allocator = memory::frame_allocator();
+    let before = memory::frame_stats();
+    assert_eq!(before.free + before.used, before.usable);
+
+    let frame = allocator.allocate_frame().unwrap();
+    let during = memory::frame_stats();
+    assert_eq!(during.free, before.free - 1);
+    assert_eq!(during.used, before.used + 1);
+
+    unsafe { allocator.deallocate_frame(frame) };
+    assert_eq!(memory::frame_stats(), before);
+}
+
+#[test_case]
+fn freed_frames_are_reused() {
+    let mut allocator = memory::frame_allocator();
+    let first = allocator.allocate_frame().unwrap();
+    unsafe { allocator.deallocate_frame(first) };
+    let second = allocator.allocate_frame().unwrap();
+    assert_eq!(first, second);
+    unsafe { allocator.deallocate_frame(second) };
+}
+
+#[test_case]
+fn frames_are_unique() {
+    const COUNT: usize = 256;
+    let mut allocator = memory::frame_allocator();
+    let mut frames = [None::<PhysFrame>; COUNT];
+    for slot in frames.iter_mut() {
+        *slot = allocator.allocate_frame();
+    }
+    for (i, a) in frames.iter().enumerate() {
+        for b in &frames[i + 1..] {
+            assert_ne!(a.unwrap(), b.unwrap());
+        }
+    }
+    for frame in frames {
+        unsafe { allocator.deallocate_frame(frame.unwrap()) };
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    zulu_os::test_panic_handler(info)
+}
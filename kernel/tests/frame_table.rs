@@ -0,0 +1,126 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(zulu_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::{
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB},
+    },
+    PhysAddr, VirtAddr,
+};
+use zulu_os::memory::{self, AddressSpace, FrameFlags, FrameOwner, Vma, VmaKind};
+use zulu_os::{test_user_page, TEST_USER_FLAGS};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    zulu_os::init(boot_info);
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut frame_allocator = memory::frame_allocator();
+    unsafe {
+        memory::init(phys_mem_offset, &boot_info.memory_map).with(|mapper| {
+            zulu_os::allocator::init_kernel_heap(mapper, &mut frame_allocator)
+                .expect("heap initialization failed")
+        })
+    };
+
+    zulu_os::warm_vma_cache();
+
+    test_main();
+    zulu_os::sys::hlt_loop()
+}
+
+#[test_case]
+fn allocations_are_tagged_with_their_owner() {
+    let mut allocator = memory::frame_allocator_for(FrameOwner::PageTable);
+    let frame = allocator.allocate_frame().unwrap();
+    let info = memory::frame_info(frame);
+    assert_eq!(info.refcount, 1);
+    assert_eq!(info.owner, FrameOwner::PageTable);
+
+    memory::set_frame_owner(frame, FrameOwner::Kernel);
+    assert_eq!(memory::frame_info(frame).owner, FrameOwner::Kernel);
+
+    unsafe { allocator.deallocate_frame(frame) };
+    let info = memory::frame_info(frame);
+    assert_eq!(info.refcount, 0);
+    assert_eq!(info.owner, FrameOwner::Free);
+}
+
+#[test_case]
+fn frame_zero_is_reserved() {
+    let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(0));
+    assert_eq!(memory::frame_info(frame).owner, FrameOwner::Reserved);
+}
+
+#[test_case]
+fn huge_frames_are_flagged() {
+    let frame = memory::allocate_huge_frame(FrameOwner::Dma).unwrap();
+    for part in memory::huge_frame_parts(frame) {
+        let info = memory::frame_info(part);
+        assert_eq!(info.owner, FrameOwner::Dma);
+        assert!(info
+            .flags
+            .contains(FrameFlags::HUGE | FrameFlags::CONTIGUOUS_ZONE));
+    }
+    for part in memory::huge_frame_parts(frame) {
+        unsafe { memory::frame_allocator().deallocate_frame(part) };
+        let info = memory::frame_info(part);
+        assert_eq!(info.owner, FrameOwner::Free);
+        assert_eq!(info.flags, FrameFlags::CONTIGUOUS_ZONE);
+    }
+}
+
+#[test_case]
+fn exited_owner_leaves_no_frames_behind() {
+    const PARENT: FrameOwner = FrameOwner::User { pid: 1000 };
+    const CHILD: FrameOwner = FrameOwner::User { pid: 1001 };
+    let before = memory::frame_stats();
+    let shared_before = memory::frames_owned_by(FrameOwner::SharedUser);
+
+    let mut parent = AddressSpace::new().unwrap();
+    parent.set_owner(1000);
+    let start = test_user_page(0).start_address();
+    let vma = Vma::new(start, start + 4 * 4096u64, TEST_USER_FLAGS, VmaKind::Image);
+    parent.add_vma(vma).unwrap();
+    for n in 0..4 {
+        parent.map(test_user_page(n), TEST_USER_FLAGS).unwrap();
+    }
+    assert_eq!(memory::frames_owned_by(PARENT), 4);
+
+    let mut child = parent.fork().unwrap();
+    child.set_owner(1001);
+    let frame = parent.map(test_user_page(4), TEST_USER_FLAGS).unwrap();
+    assert_eq!(memory::frame_info(frame).owner, PARENT);
+
+    // Writing to a shared page gives the child its own copy
+    let write = PageFaultErrorCode::USER_MODE | PageFaultErrorCode::CAUSED_BY_WRITE;
+    child.handle_fault(start, write).unwrap();
+    assert_eq!(memory::frames_owned_by(CHILD), 1);
+
+    // The pages the child still shares outlive the parent
+    drop(parent);
+    assert_eq!(memory::frames_owned_by(PARENT), 0);
+    assert_eq!(
+        memory::frames_owned_by(FrameOwner::SharedUser),
+        shared_before + 3
+    );
+
+    drop(child);
+    assert_eq!(memory::frames_owned_by(CHILD), 0);
+    assert_eq!(
+        memory::frames_owned_by(FrameOwner::SharedUser),
+        shared_before
+    );
+    assert_eq!(memory::frame_stats(), before);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    zulu_os::test_panic_handler(info)
+}
@@ -0,0 +1,57 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::{arch::asm, panic::PanicInfo};
+use x86_64::VirtAddr;
+use zulu_os::{
+    memory::{self, KernelStack, StackKind},
+    serial_print,
+};
+
+entry_point!(main);
+
+const EXPECTED: &str = "syscall stack of pid 42 overflowed";
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("guard_page::syscall_stack_overflow...\t");
+
+    zulu_os::init(boot_info);
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut frame_allocator = memory::frame_allocator();
+    unsafe {
+        memory::init(phys_mem_offset, &boot_info.memory_map).with(|mapper| {
+            zulu_os::allocator::init_kernel_heap(mapper, &mut frame_allocator)
+                .expect("heap initialization failed")
+        })
+    };
+    zulu_os::gdt::init_stacks();
+
+    let stack = KernelStack::new(4 * 4096, StackKind::Syscall { pid: 42 }).unwrap();
+    // Switch to the new stack and overflow it
+    unsafe {
+        asm!(
+            "mov rsp, {top}",
+            "call {overflow}",
+            top = in(reg) stack.top().as_u64(),
+            overflow = in(reg) overflow_syscall_stack as extern "C" fn() -> !,
+            options(noreturn)
+        )
+    }
+}
+
+extern "C" fn overflow_syscall_stack() -> ! {
+    stack_overflow();
+    panic!("Execution continued after stack overflow");
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    stack_overflow(); // for each recursion, the return address is pushed
+    let _ = volatile::Volatile::new(0).read(); // prevent tail recursion optimizations
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    zulu_os::expected_panic_handler(info, EXPECTED)
+}
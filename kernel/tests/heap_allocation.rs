@@ -9,19 +9,22 @@ use alloc::boxed::Box;
 use alloc::vec::Vec;
 
 use bootloader::{entry_point, BootInfo};
-use core::panic::PanicInfo;
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 entry_point!(main);
 
 fn main(boot_info: &'static BootInfo) -> ! {
     use x86_64::VirtAddr;
-    use zulu_os::memory::{self, BootInfoFrameAllocator};
+    use zulu_os::memory;
 
     zulu_os::init(boot_info);
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    let mut frame_allocator = memory::frame_allocator();
     unsafe {
-        memory::init(phys_mem_offset).with(|mapper| {
+        memory::init(phys_mem_offset, &boot_info.memory_map).with(|mapper| {
             zulu_os::allocator::init_kernel_heap(mapper, &mut frame_allocator)
                 .expect("heap initialization failed")
         })
@@ -57,6 +60,166 @@ fn many_boxes() {
     }
 }
 
+#[test_case]
+fn heap_grows_on_demand() {
+    use zulu_os::allocator::{heap_stats, shrink_kernel_heap, HEAP_SIZE};
+
+    let before = heap_stats();
+    // Several megabytes in chunks, far more than the heap starts with
+    let chunks: Vec<Vec<u8>> = (0..48).map(|i| alloc::vec![i as u8; 128 * 1024]).collect();
+    let big = alloc::vec![0xAAu64; 1024 * 1024];
+    assert!(heap_stats().size >= before.size + 14 * 1024 * 1024);
+    for (i, chunk) in chunks.iter().enumerate() {
+        assert!(chunk.iter().all(|&b| b == i as u8));
+    }
+    assert!(big.iter().all(|&x| x == 0xAA));
+    drop(chunks);
+    drop(big);
+
+    let free_frames = || {
+        let stats = zulu_os::memory::frame_stats();
+        stats.free + stats.contiguous_free
+    };
+    let frames = free_frames();
+    assert!(shrink_kernel_heap() > 0);
+    assert!(heap_stats().size < before.size + 14 * 1024 * 1024);
+    assert!(heap_stats().size >= HEAP_SIZE);
+    assert!(free_frames() > frames);
+}
+
+#[test_case]
+fn heap_stops_at_max_size() {
+    use zulu_os::allocator::{heap_stats, set_heap_max_size, HEAP_MAX_SIZE};
+
+    set_heap_max_size(heap_stats().size);
+    let mut vec: Vec<u8> = Vec::new();
+    assert!(vec.try_reserve(heap_stats().size).is_err());
+
+    set_heap_max_size(HEAP_MAX_SIZE);
+    vec.try_reserve(heap_stats().size).unwrap();
+}
+
+#[test_case]
+fn freed_memory_is_reused() {
+    use zulu_os::allocator::heap_stats;
+
+    let before = heap_stats();
+    let first = Box::new([1u8; 512]);
+    let addr = &*first as *const _ as usize;
+    drop(first);
+    let second = Box::new([2u8; 512]);
+    assert_eq!(&*second as *const _ as usize, addr);
+    drop(second);
+
+    // Allocations that outlive later ones are reused too, not just the most recent one
+    for _ in 0..10_000 {
+        let long_lived = Box::new([3u8; 256]);
+        let short_lived = Box::new([4u8; 256]);
+        drop(long_lived);
+        drop(short_lived);
+    }
+    assert_eq!(heap_stats(), before);
+}
+
+#[test_case]
+fn freed_neighbors_are_merged() {
+    let boxes: Vec<Box<[u8; 1024]>> = (0..4).map(|i| Box::new([i; 1024])).collect();
+    let addrs: Vec<usize> = boxes.iter().map(|b| &**b as *const _ as usize).collect();
+    assert!(addrs.windows(2).all(|pair| pair[1] == pair[0] + 1024));
+
+    // Free the middle two out of order, which must merge them into one 2KiB block
+    let mut boxes = boxes.into_iter();
+    let first = boxes.next().unwrap();
+    let second = boxes.next().unwrap();
+    let third = boxes.next().unwrap();
+    let fourth = boxes.next().unwrap();
+    drop(third);
+    drop(second);
+    let merged = Box::new([9u8; 2048]);
+    assert_eq!(&*merged as *const _ as usize, addrs[1]);
+    assert_eq!(first[0], 0);
+    assert_eq!(fourth[0], 3);
+}
+
+#[test_case]
+fn allocations_are_aligned() {
+    use alloc::alloc::{alloc, dealloc, Layout};
+
+    let mut blocks = Vec::new();
+    for (size, align) in [
+        (1, 1),
+        (3, 2),
+        (24, 8),
+        (100, 64),
+        (8, 4096),
+        (5000, 4096),
+        (64, 128),
+    ] {
+        let layout = Layout::from_size_align(size, align).unwrap();
+        let ptr = unsafe { alloc(layout) };
+        assert!(!ptr.is_null());
+        assert_eq!(ptr as usize % align, 0);
+        unsafe { ptr.write_bytes(0xCD, size) };
+        blocks.push((ptr, layout));
+    }
+    // Blocks must not overlap
+    for (i, &(a, a_layout)) in blocks.iter().enumerate() {
+        for &(b, b_layout) in &blocks[i + 1..] {
+            let (a, b) = (a as usize, b as usize);
+            assert!(b >= a + a_layout.size() || a >= b + b_layout.size());
+        }
+    }
+    for (ptr, layout) in blocks {
+        unsafe { dealloc(ptr, layout) };
+    }
+}
+
+/// How many times [`allocate_in_interrupt`] ran
+static INTERRUPT_ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Allocates from the timer interrupt handler, in the middle of whatever the test was doing
+fn allocate_in_interrupt() {
+    assert!(zulu_os::percpu::in_interrupt());
+    let count = INTERRUPT_ALLOCATIONS.load(Ordering::Relaxed);
+    let values: Vec<usize> = (0..64).map(|i| i + count).collect();
+    let boxed = Box::new(values);
+    assert_eq!(boxed.iter().sum::<usize>(), 64 * count + 63 * 64 / 2);
+    INTERRUPT_ALLOCATIONS.store(count + 1, Ordering::Relaxed);
+}
+
+#[test_case]
+fn allocation_is_safe_under_interrupts() {
+    use x86_64::instructions::interrupts;
+    use zulu_os::{allocator::heap_stats, interrupts::set_timer_hook};
+
+    let used = heap_stats().used;
+    INTERRUPT_ALLOCATIONS.store(0, Ordering::Relaxed);
+    set_timer_hook(Some(allocate_in_interrupt));
+    // The timer keeps interrupting the allocator's callers, and allocates itself every time
+    interrupts::enable();
+    let mut kept = Vec::new();
+    let mut i = 0;
+    while INTERRUPT_ALLOCATIONS.load(Ordering::Relaxed) < 20 {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+        kept.push(x);
+        if kept.len() == 1000 {
+            kept.clear();
+        }
+        assert!(interrupts::are_enabled());
+        i += 1;
+    }
+    interrupts::disable();
+    set_timer_hook(None);
+    drop(kept);
+    assert_eq!(heap_stats().used, used);
+
+    // Allocating doesn't enable interrupts when they were disabled
+    let x = Box::new(42);
+    assert!(!interrupts::are_enabled());
+    drop(x);
+}
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     zulu_os::test_panic_handler(info)
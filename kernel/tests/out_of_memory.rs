@@ -0,0 +1,85 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+use x86_64::VirtAddr;
+use zulu_os::{
+    exit_qemu, memory,
+    process::{self, ExitStatus, Pid, Process},
+    serial_print, serial_println, QemuExitCode,
+};
+
+entry_point!(main);
+
+/// The elf parser reads the headers in place, so the image has to be aligned
+#[repr(C, align(4096))]
+struct Aligned<T: ?Sized>(T);
+
+/// Runs every userspace test, including `out_of_memory_test`, which forks a child that touches
+/// memory until there is none left
+static PROGRAM: &Aligned<[u8]> = &Aligned(*include_bytes!("../processes/userspace_test"));
+
+/// Frames that may stay in use after the program ends, such as its syscall stack that is only
+/// freed when the next process exits
+const SLACK_FRAMES: usize = 64;
+
+static ROOT: AtomicU64 = AtomicU64::new(0);
+static KILLED: AtomicUsize = AtomicUsize::new(0);
+static FREE_BEFORE: AtomicUsize = AtomicUsize::new(0);
+static FAILURES_BEFORE: AtomicUsize = AtomicUsize::new(0);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("out_of_memory::user_process_is_killed...\t");
+
+    zulu_os::init(boot_info);
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut frame_allocator = memory::frame_allocator();
+    unsafe {
+        memory::init(phys_mem_offset, &boot_info.memory_map).with(|mapper| {
+            zulu_os::allocator::init_kernel_heap(mapper, &mut frame_allocator)
+                .expect("heap initialization failed")
+        })
+    };
+    zulu_os::gdt::init_stacks();
+
+    assert!(!memory::low_memory());
+    FREE_BEFORE.store(memory::frame_stats().free, Ordering::Relaxed);
+    FAILURES_BEFORE.store(memory::out_of_memory_count(), Ordering::Relaxed);
+
+    let process = Process::spawn(&PROGRAM.0).expect("failed to load the user program");
+    ROOT.store(process.pid().as_u64(), Ordering::Relaxed);
+    process::set_exit_hook(exited);
+    process::run(process)
+}
+
+/// Counts the processes that the kernel killed, and checks the kernel's state once the program
+/// that started them is done
+fn exited(pid: Pid, status: ExitStatus) {
+    if status == ExitStatus::Killed {
+        KILLED.fetch_add(1, Ordering::Relaxed);
+    }
+    if pid.as_u64() != ROOT.load(Ordering::Relaxed) {
+        return;
+    }
+
+    // Every userspace test passed, so the parent kept running after its child was killed
+    assert_eq!(status, ExitStatus::Exited(0));
+    assert!(KILLED.load(Ordering::Relaxed) > 0, "no process was killed");
+    assert!(memory::out_of_memory_count() > FAILURES_BEFORE.load(Ordering::Relaxed));
+    // The victim's memory was given back
+    assert!(!memory::low_memory());
+    let free = memory::frame_stats().free;
+    assert!(free + SLACK_FRAMES >= FREE_BEFORE.load(Ordering::Relaxed));
+
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    zulu_os::test_panic_handler(info)
+}
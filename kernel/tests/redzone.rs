@@ -0,0 +1,54 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+use zulu_os::{allocator, exit_qemu, memory, serial_print, serial_println, QemuExitCode};
+
+entry_point!(main);
+
+/// The allocation is 24 bytes long, and the byte right after it is overwritten
+const EXPECTED: &str = "was overwritten at offset 24, in its redzone";
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("redzone::overflow_is_detected...\t");
+
+    zulu_os::init(boot_info);
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut frame_allocator = memory::frame_allocator();
+    unsafe {
+        memory::init(phys_mem_offset, &boot_info.memory_map).with(|mapper| {
+            allocator::init_kernel_heap(mapper, &mut frame_allocator)
+                .expect("heap initialization failed")
+        })
+    };
+
+    let before = allocator::allocation_stats();
+    let mut vec: Vec<u8> = Vec::with_capacity(24);
+    let class = allocator::allocation_stats()[1];
+    allocator::print_allocations();
+    // Checked without panicking, so that the failure is reported as itself
+    if class.max_size != 32
+        || class.allocations != before[1].allocations + 1
+        || class.bytes != before[1].bytes + 24
+    {
+        serial_println!("[allocation was not counted: {:?}]", class);
+        exit_qemu(QemuExitCode::Failed);
+    }
+
+    // Write one byte past the end of the allocation
+    unsafe { vec.as_mut_ptr().add(vec.capacity()).write(0) };
+    drop(vec);
+
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed)
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    zulu_os::expected_panic_handler(info, EXPECTED)
+}
@@ -0,0 +1,281 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(zulu_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::{
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            mapper::{MappedFrame, TranslateResult},
+            PageTableFlags, PhysFrame,
+        },
+    },
+    VirtAddr,
+};
+use zulu_os::memory::{
+    self, Access, AddressSpace, FaultError, FrameOwner, SharedMemoryError, SharedMemoryHandles,
+    VmaKind, COPY_ON_WRITE, SHARED, USER_START,
+};
+use zulu_os::syscall::with_user_access;
+use zulu_os::TEST_USER_FLAGS;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    zulu_os::init(boot_info);
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut frame_allocator = memory::frame_allocator();
+    // VMAs and objects live on the heap
+    unsafe {
+        memory::init(phys_mem_offset, &boot_info.memory_map).with(|mapper| {
+            zulu_os::allocator::init_kernel_heap(mapper, &mut frame_allocator)
+                .expect("heap initialization failed")
+        })
+    };
+
+    zulu_os::warm_vma_cache();
+
+    test_main();
+    zulu_os::sys::hlt_loop()
+}
+
+fn user_addr(n: u64) -> VirtAddr {
+    VirtAddr::new(USER_START + n * 4096)
+}
+
+/// Returns the frame and flags that `addr` is mapped with
+fn mapping(space: &mut AddressSpace, addr: VirtAddr) -> (PhysFrame, PageTableFlags) {
+    match space.translate(addr) {
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(frame),
+            flags,
+            ..
+        } => (frame, flags),
+        _ => panic!("{:?} is not mapped", addr),
+    }
+}
+
+#[test_case]
+fn objects_are_zeroed_and_owned() {
+    let before = memory::frame_stats();
+    let objects = memory::shared_memory_objects();
+    {
+        let mut handles = SharedMemoryHandles::new(1);
+        let handle = handles.create(3, None).unwrap();
+        assert_eq!(memory::shared_memory_objects(), objects + 1);
+        let frames = handles.frames(handle, true).unwrap();
+        assert_eq!(frames.len(), 3);
+        for frame in frames {
+            let info = memory::frame_info(frame);
+            assert_eq!(info.owner, FrameOwner::SharedMemory);
+            assert_eq!(info.refcount, 1);
+        }
+
+        let mut space = AddressSpace::new().unwrap();
+        let frames = handles.frames(handle, true).unwrap();
+        space
+            .map_shared(user_addr(0), &frames, TEST_USER_FLAGS)
+            .unwrap();
+        let ptr = user_addr(0).as_ptr::<[u8; 3 * 4096]>();
+        unsafe {
+            space.activate();
+            with_user_access(|| assert!((*ptr).iter().all(|&byte| byte == 0)));
+        }
+    }
+    assert_eq!(memory::shared_memory_objects(), objects);
+    assert_eq!(memory::frame_stats(), before);
+}
+
+#[test_case]
+fn mappings_share_frames() {
+    let before = memory::frame_stats();
+    {
+        let mut handles = SharedMemoryHandles::new(1);
+        let handle = handles.create(2, None).unwrap();
+        let frames = handles.frames(handle, true).unwrap();
+
+        let mut writer = AddressSpace::new().unwrap();
+        let mut reader = AddressSpace::new().unwrap();
+        let read_only = TEST_USER_FLAGS - PageTableFlags::WRITABLE;
+        writer
+            .map_shared(user_addr(0), &frames, TEST_USER_FLAGS)
+            .unwrap();
+        reader.map_shared(user_addr(8), &frames, read_only).unwrap();
+        assert_eq!(reader.vma(user_addr(9)).unwrap().kind, VmaKind::Shared);
+
+        for (i, &frame) in frames.iter().enumerate() {
+            let offset = i as u64 * 4096;
+            let (writer_frame, writer_flags) = mapping(&mut writer, user_addr(0) + offset);
+            let (reader_frame, reader_flags) = mapping(&mut reader, user_addr(8) + offset);
+            assert_eq!(writer_frame, frame);
+            assert_eq!(reader_frame, frame);
+            assert!(writer_flags.contains(TEST_USER_FLAGS | SHARED));
+            assert!(reader_flags.contains(SHARED));
+            assert!(!reader_flags.contains(PageTableFlags::WRITABLE));
+            // The object and both mappings
+            assert_eq!(memory::frame_refcount(frame), 3);
+        }
+
+        writer.write(user_addr(1), b"shared");
+        let ptr = user_addr(9).as_ptr::<[u8; 6]>();
+        unsafe {
+            reader.activate();
+            with_user_access(|| assert_eq!(&*ptr, b"shared"));
+        }
+
+        // Writes to a read-only mapping are refused instead of being copied
+        let write = PageFaultErrorCode::USER_MODE
+            | PageFaultErrorCode::CAUSED_BY_WRITE
+            | PageFaultErrorCode::PROTECTION_VIOLATION;
+        assert_eq!(
+            reader.handle_fault(user_addr(8), write),
+            Err(FaultError::AccessViolation)
+        );
+    }
+    assert_eq!(memory::frame_stats(), before);
+}
+
+#[test_case]
+fn mappings_outlive_handles() {
+    let before = memory::frame_stats();
+    let objects = memory::shared_memory_objects();
+    let mut space = AddressSpace::new().unwrap();
+    let frames = {
+        let mut handles = SharedMemoryHandles::new(1);
+        let handle = handles.create(2, None).unwrap();
+        let frames = handles.frames(handle, true).unwrap();
+        space
+            .map_shared(user_addr(0), &frames, TEST_USER_FLAGS)
+            .unwrap();
+        space.write(user_addr(0), b"still here");
+        handles.close(handle).unwrap();
+        assert_eq!(
+            handles.frames(handle, false),
+            Err(SharedMemoryError::NotFound)
+        );
+        assert!(handles.close(handle).is_none());
+        frames
+    };
+    assert_eq!(memory::shared_memory_objects(), objects);
+    assert_eq!(memory::frame_refcount(frames[0]), 1);
+    let ptr = user_addr(0).as_ptr::<[u8; 10]>();
+    unsafe {
+        space.activate();
+        with_user_access(|| assert_eq!(&*ptr, b"still here"));
+    }
+
+    // The last mapping frees the frames
+    space.unmap_range(user_addr(0), user_addr(1)).unwrap();
+    assert_eq!(memory::frame_refcount(frames[1]), 1);
+    drop(space);
+    assert_eq!(memory::frame_stats(), before);
+}
+
+#[test_case]
+fn handles_are_counted() {
+    let objects = memory::shared_memory_objects();
+    let mut first = SharedMemoryHandles::new(1);
+    let mut second = SharedMemoryHandles::new(2);
+    let handle = first.create(1, Some(Access::ReadWrite)).unwrap();
+    let id = first.id(handle).unwrap();
+    let opened = second.open(id).unwrap();
+    assert_eq!(second.open(u64::MAX), Err(SharedMemoryError::NotFound));
+
+    let forked = first.fork(3).unwrap();
+    drop(first);
+    second.close(opened).unwrap();
+    assert_eq!(memory::shared_memory_objects(), objects + 1);
+    assert_eq!(forked.id(handle), Some(id));
+    assert!(forked.frames(handle, true).is_ok());
+    drop(forked);
+    assert_eq!(memory::shared_memory_objects(), objects);
+    assert_eq!(second.open(id), Err(SharedMemoryError::NotFound));
+}
+
+#[test_case]
+fn opening_checks_access() {
+    let mut owner = SharedMemoryHandles::new(1);
+    let mut other = SharedMemoryHandles::new(2);
+    let private = owner.create(1, None).unwrap();
+    let read_only = owner.create(1, Some(Access::ReadOnly)).unwrap();
+    assert_ne!(private, read_only);
+
+    // Only the owner may open an object that isn't shared
+    let id = owner.id(private).unwrap();
+    assert_eq!(other.open(id), Err(SharedMemoryError::PermissionDenied));
+    let again = owner.open(id).unwrap();
+    assert!(owner.frames(again, true).is_ok());
+
+    // Others get no more access than the object was shared with
+    let handle = other.open(owner.id(read_only).unwrap()).unwrap();
+    assert_eq!(
+        other.frames(handle, true),
+        Err(SharedMemoryError::PermissionDenied)
+    );
+    assert!(other.frames(handle, false).is_ok());
+    // Handles are numbered per process, and numbers are only looked up in the own table
+    assert_eq!(handle, 0);
+    assert_eq!(
+        other.frames(read_only, false),
+        Err(SharedMemoryError::NotFound)
+    );
+
+    // Closed numbers are reused
+    owner.close(private).unwrap();
+    assert_eq!(owner.create(1, None), Ok(private));
+}
+
+#[test_case]
+fn fork_keeps_shared_pages_writable() {
+    let before = memory::frame_stats();
+    {
+        let mut handles = SharedMemoryHandles::new(1);
+        let handle = handles.create(1, None).unwrap();
+        let frames = handles.frames(handle, true).unwrap();
+        let mut parent = AddressSpace::new().unwrap();
+        parent
+            .map_shared(user_addr(0), &frames, TEST_USER_FLAGS)
+            .unwrap();
+        let mut child = parent.fork().unwrap();
+
+        for space in [&mut parent, &mut child] {
+            let (frame, flags) = mapping(space, user_addr(0));
+            assert_eq!(frame, frames[0]);
+            assert!(flags.contains(PageTableFlags::WRITABLE));
+            assert!(!flags.contains(COPY_ON_WRITE));
+        }
+
+        // Making the page writable again must not make it copy-on-write either
+        child
+            .protect(
+                user_addr(0),
+                user_addr(1),
+                TEST_USER_FLAGS - PageTableFlags::WRITABLE,
+            )
+            .unwrap();
+        child
+            .protect(user_addr(0), user_addr(1), TEST_USER_FLAGS)
+            .unwrap();
+        let (frame, flags) = mapping(&mut child, user_addr(0));
+        assert_eq!(frame, frames[0]);
+        assert!(flags.contains(PageTableFlags::WRITABLE | SHARED));
+        assert!(!flags.contains(COPY_ON_WRITE));
+
+        child.write(user_addr(0), b"child");
+        let ptr = user_addr(0).as_ptr::<[u8; 5]>();
+        unsafe {
+            parent.activate();
+            with_user_access(|| assert_eq!(&*ptr, b"child"));
+        }
+    }
+    assert_eq!(memory::frame_stats(), before);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    zulu_os::test_panic_handler(info)
+}
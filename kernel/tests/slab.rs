@@ -0,0 +1,148 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(zulu_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use x86_64::VirtAddr;
+use zulu_os::allocator::{self, SlabCache, SlabStats};
+use zulu_os::memory::{self, FrameOwner};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    zulu_os::init(boot_info);
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut frame_allocator = memory::frame_allocator();
+    // The list of caches lives on the heap
+    unsafe {
+        memory::init(phys_mem_offset, &boot_info.memory_map).with(|mapper| {
+            zulu_os::allocator::init_kernel_heap(mapper, &mut frame_allocator)
+                .expect("heap initialization failed")
+        })
+    };
+
+    test_main();
+    zulu_os::sys::hlt_loop()
+}
+
+/// Objects of this size leave room for exactly 31 of them in a slab, after the slab header
+#[derive(Debug, PartialEq, Eq)]
+struct Object([u64; 16]);
+
+#[test_case]
+fn freed_objects_are_reused() {
+    static CACHE: SlabCache<Object> = SlabCache::new("reused");
+    let first = CACHE.alloc(Object([1; 16])).unwrap();
+    let address = &*first as *const Object;
+    assert_eq!(address as usize % core::mem::align_of::<Object>(), 0);
+    assert_eq!(*first, Object([1; 16]));
+    drop(first);
+
+    let second = CACHE.alloc(Object([2; 16])).unwrap();
+    assert_eq!(&*second as *const Object, address);
+    assert_eq!(*second, Object([2; 16]));
+}
+
+#[test_case]
+fn stats_count_objects_and_waste() {
+    static CACHE: SlabCache<Object> = SlabCache::new("stats");
+    let empty = SlabStats {
+        objects: 0,
+        capacity: 0,
+        slabs: 0,
+        waste: 0,
+    };
+    assert_eq!(CACHE.stats(), empty);
+
+    let objects: Vec<_> = (0..3)
+        .map(|_| CACHE.alloc(Object([0; 16])).unwrap())
+        .collect();
+    let stats = CACHE.stats();
+    assert_eq!(stats.objects, 3);
+    assert_eq!(stats.slabs, 1);
+    assert_eq!(stats.capacity, 31);
+    assert_eq!(stats.waste, 4096 - 31 * 128);
+    drop(objects);
+    assert_eq!(CACHE.stats().objects, 0);
+}
+
+#[test_case]
+fn empty_slabs_are_freed() {
+    static CACHE: SlabCache<Object> = SlabCache::new("freed");
+    let before = memory::frames_owned_by(FrameOwner::Slab);
+    let mut objects: Vec<_> = (0..31 * 4)
+        .map(|i| CACHE.alloc(Object([i; 16])).unwrap())
+        .collect();
+    assert_eq!(CACHE.stats().slabs, 4);
+    assert_eq!(memory::frames_owned_by(FrameOwner::Slab), before + 4);
+    for (i, object) in objects.iter().enumerate() {
+        assert_eq!(**object, Object([i as u64; 16]));
+    }
+
+    // A full slab takes objects again once one of its objects is freed
+    let freed = &*objects.swap_remove(0) as *const Object;
+    let object = CACHE.alloc(Object([0; 16])).unwrap();
+    assert_eq!(&*object as *const Object, freed);
+    assert_eq!(CACHE.stats().slabs, 4);
+    objects.push(object);
+
+    // One empty slab is kept around for the next allocation
+    drop(objects);
+    assert_eq!(CACHE.stats().slabs, 1);
+    assert_eq!(memory::frames_owned_by(FrameOwner::Slab), before + 1);
+}
+
+#[test_case]
+fn constructors_create_objects() {
+    fn construct() -> Object {
+        Object([42; 16])
+    }
+    static CACHE: SlabCache<Object> = SlabCache::with_constructor("constructed", construct);
+    let object = CACHE.alloc_new().unwrap();
+    assert_eq!(*object, Object([42; 16]));
+}
+
+#[test_case]
+fn objects_are_dropped() {
+    static DROPPED: AtomicUsize = AtomicUsize::new(0);
+    struct Counted;
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    static CACHE: SlabCache<Counted> = SlabCache::new("dropped");
+    let objects: Vec<_> = (0..3).map(|_| CACHE.alloc(Counted).unwrap()).collect();
+    assert_eq!(DROPPED.load(Ordering::Relaxed), 0);
+    drop(objects);
+    assert_eq!(DROPPED.load(Ordering::Relaxed), 3);
+}
+
+#[test_case]
+fn caches_are_listed_by_name() {
+    static CACHE: SlabCache<u64> = SlabCache::new("listed");
+    assert!(!allocator::slab_stats()
+        .iter()
+        .any(|&(name, _)| name == "listed"));
+    let objects: Vec<_> = (0..10).map(|i| CACHE.alloc(i).unwrap()).collect();
+    let stats = allocator::slab_stats();
+    let (_, listed) = stats.iter().find(|&&(name, _)| name == "listed").unwrap();
+    assert_eq!(listed.objects, 10);
+    assert_eq!(*listed, CACHE.stats());
+    drop(objects);
+    allocator::print_slab_stats();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    zulu_os::test_panic_handler(info)
+}
@@ -0,0 +1,62 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::{panic::PanicInfo, ptr};
+use x86_64::{
+    structures::paging::{Page, PageTableFlags},
+    VirtAddr,
+};
+use zulu_os::{
+    exit_qemu,
+    memory::{self, AddressSpace, USER_START},
+    serial_print, serial_println,
+    syscall::{smap_enabled, with_user_access},
+    QemuExitCode,
+};
+
+entry_point!(main);
+
+const EXPECTED: &str = "outside of a user access window";
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("smap::stray_user_access_panics...\t");
+
+    zulu_os::init(boot_info);
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut frame_allocator = memory::frame_allocator();
+    unsafe {
+        memory::init(phys_mem_offset, &boot_info.memory_map).with(|mapper| {
+            zulu_os::allocator::init_kernel_heap(mapper, &mut frame_allocator)
+                .expect("heap initialization failed")
+        })
+    };
+
+    if !smap_enabled() {
+        serial_println!("[skipped, the CPU doesn't support SMAP]");
+        exit_qemu(QemuExitCode::Success);
+    }
+
+    let page = Page::containing_address(VirtAddr::new(USER_START));
+    let flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let mut space = AddressSpace::new().unwrap();
+    space.map(page, flags).unwrap();
+    space.write(page.start_address(), b"user");
+    unsafe { space.activate() };
+
+    let ptr = page.start_address().as_ptr::<[u8; 4]>();
+    let inside = with_user_access(|| unsafe { ptr::read_volatile(ptr) });
+    assert_eq!(&inside, b"user");
+
+    // Without a window SMAP must stop the kernel
+    let _ = unsafe { ptr::read_volatile(ptr) };
+
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed)
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    zulu_os::expected_panic_handler(info, EXPECTED)
+}
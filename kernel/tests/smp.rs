@@ -0,0 +1,129 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(zulu_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use x86_64::{
+    structures::paging::{mapper::TranslateResult, Translate},
+    VirtAddr,
+};
+use zulu_os::{allocator, memory, smp};
+
+entry_point!(main);
+
+/// The number of CPUs that the tests are run with
+const CPUS: usize = 4;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    zulu_os::init(boot_info);
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut frame_allocator = memory::frame_allocator();
+    unsafe {
+        memory::init(phys_mem_offset, &boot_info.memory_map).with(|mapper| {
+            zulu_os::allocator::init_kernel_heap(mapper, &mut frame_allocator)
+                .expect("heap initialization failed")
+        })
+    };
+    zulu_os::gdt::init_stacks();
+    smp::init();
+
+    test_main();
+    zulu_os::sys::hlt_loop()
+}
+
+#[test_case]
+fn every_cpu_comes_online() {
+    assert_eq!(smp::online_cpus(), CPUS);
+}
+
+#[test_case]
+fn bootstrap_processor_is_first() {
+    let apic = smp::local_apic().expect("local APIC isn't mapped");
+    assert_eq!(smp::apic_ids()[0], apic.id());
+}
+
+#[test_case]
+fn apic_ids_match_madt() {
+    let madt = smp::madt().expect("no MADT");
+    let mut expected = madt.apic_ids;
+    let mut online = smp::apic_ids();
+    expected.sort_unstable();
+    online.sort_unstable();
+    assert_eq!(online, expected);
+}
+
+#[test_case]
+fn apic_ids_are_unique() {
+    let mut ids = smp::apic_ids();
+    ids.sort_unstable();
+    ids.dedup();
+    assert_eq!(ids.len(), CPUS);
+}
+
+#[test_case]
+fn trampoline_is_unmapped() {
+    let frame = memory::low_memory_frame().expect("no low memory frame");
+    let addr = VirtAddr::new(frame.start_address().as_u64());
+    let result = memory::mapper().with(|mapper| mapper.translate(addr));
+    assert!(matches!(result, TranslateResult::NotMapped));
+}
+
+#[test_case]
+fn heap_works_after_startup() {
+    let values: Vec<usize> = (0..1000).collect();
+    assert_eq!(values.iter().sum::<usize>(), 999 * 1000 / 2);
+}
+
+/// The APIC IDs of the CPUs that ran [`allocate`], in the order they ran it
+static ALLOCATED_ON: spin::Mutex<Vec<u8>> = spin::Mutex::new(Vec::new());
+
+/// Allocates on the calling CPU, and records which CPU that was
+fn allocate() {
+    let values: Vec<usize> = (0..1000).collect();
+    assert_eq!(values.iter().sum::<usize>(), 999 * 1000 / 2);
+    let apic = smp::local_apic().expect("local APIC isn't mapped");
+    ALLOCATED_ON.lock().push(apic.id());
+}
+
+#[test_case]
+fn heap_works_on_every_cpu() {
+    let ids = smp::apic_ids();
+    for &id in &ids {
+        assert!(smp::run_on(id, allocate));
+    }
+    let allocated_on = zulu_os::sys::without_interrupts(|| ALLOCATED_ON.lock().clone());
+    assert_eq!(allocated_on, ids);
+}
+
+/// Set by [`grow_heap`] if the heap grew while it ran
+static HEAP_GREW: AtomicBool = AtomicBool::new(false);
+
+/// Allocates more than the heap has free on the calling CPU, so that the heap has to grow there
+fn grow_heap() {
+    let before = allocator::heap_stats().size;
+    let block: Vec<u8> = Vec::with_capacity(before);
+    let grew = allocator::heap_stats().size > before;
+    drop(block);
+    HEAP_GREW.store(grew, Ordering::Relaxed);
+}
+
+#[test_case]
+fn heap_grows_on_application_processor() {
+    let ap = smp::apic_ids()[1];
+    assert!(smp::run_on(ap, grow_heap));
+    assert!(HEAP_GREW.load(Ordering::Relaxed));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    zulu_os::test_panic_handler(info)
+}
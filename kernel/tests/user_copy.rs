@@ -0,0 +1,169 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(zulu_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use syscall::Error;
+use x86_64::{
+    structures::paging::{Page, PageTableFlags},
+    VirtAddr,
+};
+use zulu_os::{
+    memory::{self, AddressSpace, USER_END, USER_START},
+    syscall::{copy_from_user, copy_to_user, UserPtr, UserSlice, UserStr},
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    zulu_os::init(boot_info);
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut frame_allocator = memory::frame_allocator();
+    unsafe {
+        memory::init(phys_mem_offset, &boot_info.memory_map).with(|mapper| {
+            zulu_os::allocator::init_kernel_heap(mapper, &mut frame_allocator)
+                .expect("heap initialization failed")
+        })
+    };
+
+    test_main();
+    zulu_os::sys::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    zulu_os::test_panic_handler(info)
+}
+
+const FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::USER_ACCESSIBLE);
+
+fn user_addr(page: u64, offset: u64) -> usize {
+    (USER_START + page * 4096 + offset) as usize
+}
+
+/// Creates an active address space with `pages` mapped at the start of user memory, with the
+/// flags of each page given by `flags`
+fn active_space(flags: &[PageTableFlags]) -> AddressSpace {
+    let mut space = AddressSpace::new().unwrap();
+    for (i, &flags) in flags.iter().enumerate() {
+        let page = Page::containing_address(VirtAddr::new(user_addr(i as u64, 0) as u64));
+        space.map(page, flags).unwrap();
+    }
+    unsafe { space.activate() };
+    space
+}
+
+#[test_case]
+fn round_trip() {
+    let _space = active_space(&[FLAGS, FLAGS]);
+    // Crosses the boundary between the two pages
+    let addr = user_addr(0, 4090);
+    copy_to_user(addr, b"hello user").unwrap();
+
+    let mut buf = [0; 10];
+    copy_from_user(&mut buf, addr).unwrap();
+    assert_eq!(&buf, b"hello user");
+}
+
+#[test_case]
+fn unmapped_memory_fails() {
+    let _space = active_space(&[]);
+    let mut buf = [0; 8];
+    assert!(matches!(
+        copy_from_user(&mut buf, user_addr(0, 0)),
+        Err(Error::BadAddress)
+    ));
+    assert!(matches!(
+        copy_to_user(user_addr(0, 0), b"data"),
+        Err(Error::BadAddress)
+    ));
+}
+
+#[test_case]
+fn partially_mapped_range_fails() {
+    let _space = active_space(&[FLAGS]);
+    let mut buf = [0xAA; 16];
+    // The first 8 bytes are mapped, the rest are on the unmapped second page
+    let result = copy_from_user(&mut buf, user_addr(0, 4088));
+    assert!(matches!(result, Err(Error::BadAddress)));
+    assert_eq!(buf[..8], [0; 8]);
+}
+
+#[test_case]
+fn read_only_memory_fails() {
+    let read_only = FLAGS - PageTableFlags::WRITABLE;
+    let _space = active_space(&[read_only]);
+    assert!(matches!(
+        copy_to_user(user_addr(0, 0), b"data"),
+        Err(Error::BadAddress)
+    ));
+    let mut buf = [0xAA; 4];
+    copy_from_user(&mut buf, user_addr(0, 0)).unwrap();
+    assert_eq!(buf, [0; 4]);
+}
+
+#[test_case]
+fn kernel_addresses_are_rejected() {
+    let _space = active_space(&[FLAGS]);
+    let secret = [0x55u8; 8];
+    let mut buf = [0; 8];
+    assert!(matches!(
+        copy_from_user(&mut buf, secret.as_ptr() as usize),
+        Err(Error::BadAddress)
+    ));
+    assert_eq!(buf, [0; 8]);
+    assert!(matches!(
+        copy_to_user(buf.as_mut_ptr() as usize, b"data"),
+        Err(Error::BadAddress)
+    ));
+
+    // Ranges that run off the end of user memory or wrap around are rejected too
+    assert!(matches!(
+        copy_from_user(&mut buf, USER_END as usize - 4),
+        Err(Error::BadAddress)
+    ));
+    assert!(matches!(
+        copy_from_user(&mut buf, usize::MAX - 2),
+        Err(Error::BadAddress)
+    ));
+}
+
+#[test_case]
+fn empty_copies_succeed() {
+    let _space = active_space(&[]);
+    copy_from_user(&mut [], 0).unwrap();
+    copy_to_user(usize::MAX, &[]).unwrap();
+}
+
+#[test_case]
+fn typed_pointers() {
+    let _space = active_space(&[FLAGS]);
+    let ptr = UserPtr::<u64>::new(user_addr(0, 8)).unwrap();
+    ptr.write(&0x1234_5678).unwrap();
+    assert_eq!(ptr.read().unwrap(), 0x1234_5678);
+
+    let slice = UserSlice::<u32>::new(user_addr(0, 64), 3).unwrap();
+    slice.copy_from_slice(&[1, 2, 3]).unwrap();
+    assert_eq!(slice.to_vec().unwrap(), [1, 2, 3]);
+    // Only the unmapped second half fails
+    let across = UserSlice::<u8>::new(user_addr(0, 4092), 8).unwrap();
+    let (mapped, unmapped) = across.split_at(4);
+    assert_eq!(mapped.to_vec().unwrap(), [0; 4]);
+    assert!(matches!(unmapped.to_vec(), Err(Error::BadAddress)));
+}
+
+#[test_case]
+fn user_strings() {
+    let _space = active_space(&[FLAGS]);
+    copy_to_user(user_addr(0, 0), "zulu ø".as_bytes()).unwrap();
+    let string = UserStr::new(user_addr(0, 0), "zulu ø".len()).unwrap();
+    assert_eq!(string.read().unwrap(), "zulu ø");
+    // Cuts the two byte character in half
+    let cut = UserStr::new(user_addr(0, 0), "zulu ø".len() - 1).unwrap();
+    assert!(matches!(cut.read(), Err(Error::InvalidArgument)));
+}
@@ -0,0 +1,106 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(zulu_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::{
+    structures::paging::{mapper::TranslateResult, PhysFrame, Translate},
+    VirtAddr,
+};
+use zulu_os::memory::{self, VirtualRangeAllocator, VMALLOC_END, VMALLOC_START};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    zulu_os::init(boot_info);
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut frame_allocator = memory::frame_allocator();
+    unsafe {
+        memory::init(phys_mem_offset, &boot_info.memory_map).with(|mapper| {
+            zulu_os::allocator::init_kernel_heap(mapper, &mut frame_allocator)
+                .expect("heap initialization failed")
+        })
+    };
+
+    test_main();
+    zulu_os::sys::hlt_loop()
+}
+
+fn is_mapped(addr: VirtAddr) -> bool {
+    let result = memory::mapper().with(|mapper| mapper.translate(addr));
+    matches!(result, TranslateResult::Mapped { .. })
+}
+
+#[test_case]
+fn range_allocator_merges_free_ranges() {
+    let start = VirtAddr::new(0x1000_0000);
+    let mut ranges = VirtualRangeAllocator::new(start, start + 16 * 4096u64);
+    let a = ranges.allocate(4096).unwrap();
+    let b = ranges.allocate(3 * 4096).unwrap();
+    let c = ranges.allocate(1).unwrap();
+    assert_eq!((a, b, c), (start, start + 4096u64, start + 4 * 4096u64));
+    assert!(ranges.allocate(12 * 4096).is_none());
+
+    ranges.deallocate(a, 4096);
+    ranges.deallocate(c, 1);
+    assert_eq!(ranges.free_ranges(), 2);
+    // Freeing the middle joins everything back into one range
+    ranges.deallocate(b, 3 * 4096);
+    assert_eq!(ranges.free_ranges(), 1);
+    assert_eq!(ranges.allocate(16 * 4096), Some(start));
+}
+
+#[test_case]
+fn vmalloc_maps_zeroed_memory_with_guard_pages() {
+    // Freeing the last region also frees the page tables that were created for it
+    let before = memory::frame_stats();
+    let free_bytes = memory::vmalloc_free_bytes();
+
+    let a = memory::vmalloc(3 * 4096).unwrap();
+    let b = memory::vmalloc(100).unwrap();
+    for addr in [a, b] {
+        assert!((VMALLOC_START..VMALLOC_END).contains(&addr.as_u64()));
+        assert!(!is_mapped(addr - 1u64), "no guard page below {:?}", addr);
+    }
+    assert!(b >= a + 3 * 4096u64);
+
+    let bytes = unsafe { core::slice::from_raw_parts_mut(a.as_mut_ptr::<u8>(), 3 * 4096) };
+    assert!(bytes.iter().all(|&b| b == 0));
+    bytes.fill(0xAB);
+
+    unsafe {
+        memory::vfree(a);
+        memory::vfree(b);
+    }
+    assert!(!is_mapped(a));
+    assert_eq!(memory::vmalloc_free_bytes(), free_bytes);
+    assert_eq!(memory::frame_stats(), before);
+
+    // Freed ranges are handed out again
+    let c = memory::vmalloc(4096).unwrap();
+    assert_eq!(c, a);
+    unsafe { memory::vfree(c) };
+}
+
+#[test_case]
+fn vmap_does_not_free_frames() {
+    let frame = memory::allocate_contiguous(1).unwrap();
+    let frames = PhysFrame::range(frame, frame + 2);
+    let flags = x86_64::structures::paging::PageTableFlags::PRESENT;
+    let addr = unsafe { memory::vmap(frames, flags) }.unwrap();
+    let translated = memory::mapper().with(|mapper| mapper.translate_addr(addr + 4096u64));
+    assert_eq!(translated, Some((frame + 1).start_address()));
+
+    let before = memory::frame_stats();
+    unsafe { memory::vfree(addr) };
+    assert_eq!(memory::frame_stats(), before);
+    unsafe { memory::deallocate_contiguous(frame, 1) };
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    zulu_os::test_panic_handler(info)
+}
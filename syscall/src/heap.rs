@@ -0,0 +1,142 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    cell::UnsafeCell,
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use free_list::{align_up, block_layout, FreeList, BLOCK_ALIGN};
+
+/// The heap grows by at least this much at once, so that small allocations don't make a syscall
+/// every time
+const HEAP_GROWTH: usize = 64 * 1024;
+
+/// Free memory at the end of the heap is given back once there is at least this much of it
+const TRIM_THRESHOLD: usize = 256 * 1024;
+
+const PAGE_SIZE: usize = 4096;
+
+struct Heap {
+    /// End of the heap, or 0 before the first allocation
+    end: usize,
+    free: FreeList,
+}
+
+impl Heap {
+    /// Moves the program break so that at least `size` bytes aligned to `align` fit after the
+    /// current end of the heap, and frees the new memory. Returns false if the kernel refused
+    fn grow(&mut self, size: usize, align: usize) -> bool {
+        if self.end == 0 {
+            // SAFETY: Passing 0 doesn't move the program break
+            match unsafe { crate::brk(0) } {
+                Ok(start) => self.end = align_up(start, BLOCK_ALIGN),
+                Err(_) => return false,
+            }
+        }
+        let Some(min_end) = self.end.checked_add(size + align) else {
+            return false;
+        };
+        let new_end = align_up(min_end.max(self.end + HEAP_GROWTH), PAGE_SIZE);
+        // SAFETY: Nothing uses the memory after the end of the heap
+        if unsafe { crate::brk(new_end) }.is_err() {
+            return false;
+        }
+        // SAFETY: The memory was just added to the heap
+        unsafe { self.free.free(self.end, new_end - self.end) };
+        self.end = new_end;
+        true
+    }
+
+    /// Gives the free memory at the end of the heap back to the kernel if there is enough of it
+    fn trim(&mut self) {
+        let Some((last, size)) = self.free.free_end(self.end) else {
+            return;
+        };
+        if size < TRIM_THRESHOLD {
+            return;
+        }
+        let new_end = align_up(last, PAGE_SIZE);
+        // SAFETY: Everything after `new_end` is free
+        if unsafe { crate::brk(new_end) }.is_err() {
+            return;
+        }
+        self.free.truncate(new_end);
+        self.end = new_end;
+    }
+}
+
+/// A first fit allocator that keeps free blocks in a list sorted by address, so that freed blocks
+/// are merged with their free neighbors. It grows the heap with [`crate::brk`] when nothing fits,
+/// and shrinks it again once enough memory at its end is free
+pub struct BrkAllocator {
+    locked: AtomicBool,
+    heap: UnsafeCell<Heap>,
+}
+
+// SAFETY: The heap is only accessed with the lock held
+unsafe impl Sync for BrkAllocator {}
+
+impl BrkAllocator {
+    pub const fn new() -> Self {
+        BrkAllocator {
+            locked: AtomicBool::new(false),
+            heap: UnsafeCell::new(Heap {
+                end: 0,
+                free: FreeList::new(),
+            }),
+        }
+    }
+
+    fn with<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut Heap) -> R,
+    {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        // SAFETY: The lock is held, so nothing else accesses the heap
+        let result = f(unsafe { &mut *self.heap.get() });
+        self.locked.store(false, Ordering::Release);
+        result
+    }
+}
+
+impl Default for BrkAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl GlobalAlloc for BrkAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (size, align) = block_layout(layout);
+        self.with(|heap| {
+            let addr = heap.free.allocate(size, align).or_else(|| {
+                heap.grow(size, align)
+                    .then(|| heap.free.allocate(size, align))?
+            });
+            addr.map_or(ptr::null_mut(), |addr| addr as *mut u8)
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = block_layout(layout);
+        self.with(|heap| {
+            // SAFETY: The block was allocated with the same layout, so it is aligned and unused
+            unsafe { heap.free.free(ptr as usize, size) };
+            heap.trim();
+        })
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: BrkAllocator = BrkAllocator::new();
+
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    panic!("allocation of {:?} failed", layout)
+}
@@ -1,9 +1,19 @@
 #![no_std]
 #![feature(naked_functions)]
 #![feature(core_intrinsics)]
+#![cfg_attr(feature = "alloc", feature(alloc_error_handler))]
 
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+/// The global allocator that the `alloc` feature provides
+#[cfg(feature = "alloc")]
+pub mod heap;
+
+use bitflags::bitflags;
 use core::arch::asm;
 use core::hint::unreachable_unchecked;
+use core::ptr::NonNull;
 use num_enum::TryFromPrimitive;
 
 #[derive(Copy, Clone, Debug, TryFromPrimitive)]
@@ -12,6 +22,17 @@ pub enum Syscall {
     Read = 1,
     Write = 2,
     Exit = 3,
+    Fork = 4,
+    MemInfo = 5,
+    Mmap = 6,
+    Munmap = 7,
+    Mprotect = 8,
+    ShmCreate = 9,
+    ShmOpen = 10,
+    ShmMap = 11,
+    ShmClose = 12,
+    Brk = 13,
+    ShmId = 14,
 }
 
 #[derive(Copy, Clone, Debug, TryFromPrimitive)]
@@ -21,10 +42,72 @@ pub enum Error {
     NoSys,
     /// Invalid argument to syscall
     InvalidArgument,
+    /// The kernel ran out of memory
+    OutOfMemory,
+    /// A pointer passed to the syscall points to memory that the process can't access
+    BadAddress,
+    /// The kernel doesn't allow the request, such as memory that is both writable and executable
+    PermissionDenied,
 }
 
 pub type Result<T> = core::result::Result<T, Error>;
 
+/// Decodes a raw syscall return value, where errors are returned as negative error codes
+fn result(ret: usize) -> Result<usize> {
+    let ret = ret as isize;
+    if ret < 0 {
+        let code = (-ret).try_into().map_err(|_| Error::NoSys)?;
+        Err(Error::try_from_primitive(code).unwrap_or(Error::NoSys))
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+/// Physical and kernel memory usage, as reported by [`mem_info`].
+///
+/// All sizes are in bytes unless the field name says otherwise. Frames and pages are 4KiB
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct MemInfo {
+    /// Frames of physical memory that the bootloader reported, including memory the kernel can't
+    /// use
+    pub total_frames: u64,
+    /// Frames that are free to be allocated
+    pub free_frames: u64,
+    /// Frames that the bootloader set aside for the kernel image, its stack, page tables and boot
+    /// information
+    pub kernel_reserved_frames: u64,
+    /// Size of the kernel heap
+    pub heap_size: u64,
+    /// Bytes of the kernel heap that are allocated
+    pub heap_used: u64,
+    /// Pages mapped in the calling process
+    pub resident_pages: u64,
+}
+
+bitflags! {
+    /// How the memory of a mapping may be accessed, see [`mmap`]. Mappings can't be made
+    /// inaccessible, so an empty set fails with [`Error::InvalidArgument`]
+    pub struct Prot: u32 {
+        const READ = 1 << 0;
+        /// Can't be combined with [`Prot::EXEC`]
+        const WRITE = 1 << 1;
+        const EXEC = 1 << 2;
+    }
+}
+
+bitflags! {
+    /// The kind of mapping that [`mmap`] creates. Only private anonymous mappings are supported
+    pub struct MapFlags: u32 {
+        /// Changes to the memory are only visible to the calling process
+        const PRIVATE = 1 << 1;
+        /// Place the mapping exactly at the address, replacing whatever was mapped there
+        const FIXED = 1 << 4;
+        /// The memory is zeroed instead of backed by a file
+        const ANONYMOUS = 1 << 5;
+    }
+}
+
 #[inline]
 pub fn write(fd: u32, bytes: &[u8]) -> usize {
     unsafe {
@@ -55,6 +138,176 @@ pub fn exit(code: u32) -> ! {
     unsafe { unreachable_unchecked() };
 }
 
+/// Creates a copy of the calling process that shares its memory copy-on-write.
+///
+/// Returns 0 in the child and the child's pid in the parent. The child runs first. Fails with
+/// [`Error::OutOfMemory`] if there isn't enough memory for the child, in which case there is none
+#[inline]
+pub fn fork() -> Result<usize> {
+    result(unsafe { syscall_0(Syscall::Fork as usize) })
+}
+
+/// Returns statistics about physical memory, the kernel heap and the calling process
+#[inline]
+pub fn mem_info() -> Result<MemInfo> {
+    let mut info = MemInfo::default();
+    let ret = unsafe {
+        syscall_1(
+            Syscall::MemInfo as usize,
+            &mut info as *mut MemInfo as usize,
+        )
+    };
+    result(ret).map(|_| info)
+}
+
+/// Maps `len` bytes of zeroed memory, rounded up to whole pages, with the permissions in `prot`.
+///
+/// The mapping is placed at `hint` if that range is free, and anywhere else otherwise. Pass 0 to
+/// let the kernel choose. Returns the start of the mapping
+#[inline]
+pub fn mmap(hint: usize, len: usize, prot: Prot) -> Result<NonNull<u8>> {
+    let flags = MapFlags::PRIVATE | MapFlags::ANONYMOUS;
+    // SAFETY: Without `MapFlags::FIXED` the kernel never replaces existing memory
+    unsafe { mmap_raw(hint, len, prot, flags) }
+}
+
+/// Maps `len` bytes of zeroed memory at exactly `addr`, which must be page aligned, replacing
+/// any memory that was there.
+///
+/// # Safety
+/// Nothing may use the memory that is replaced
+#[inline]
+pub unsafe fn mmap_fixed(addr: usize, len: usize, prot: Prot) -> Result<NonNull<u8>> {
+    let flags = MapFlags::PRIVATE | MapFlags::ANONYMOUS | MapFlags::FIXED;
+    // SAFETY: Guaranteed by the caller
+    unsafe { mmap_raw(addr, len, prot, flags) }
+}
+
+/// # Safety
+/// If `flags` contains [`MapFlags::FIXED`], nothing may use the memory that is replaced
+unsafe fn mmap_raw(addr: usize, len: usize, prot: Prot, flags: MapFlags) -> Result<NonNull<u8>> {
+    let ret = unsafe {
+        syscall_4(
+            Syscall::Mmap as usize,
+            addr,
+            len,
+            prot.bits() as usize,
+            flags.bits() as usize,
+        )
+    };
+    result(ret).map(|addr| NonNull::new(addr as *mut u8).expect("mmap returned null"))
+}
+
+/// Unmaps the `len` bytes at `addr`, rounded up to whole pages. Parts of the range that aren't
+/// mapped are ignored
+///
+/// # Safety
+/// Nothing may use the unmapped memory afterwards
+#[inline]
+pub unsafe fn munmap(addr: NonNull<u8>, len: usize) -> Result<()> {
+    let ret = unsafe { syscall_2(Syscall::Munmap as usize, addr.as_ptr() as usize, len) };
+    result(ret).map(|_| ())
+}
+
+/// Changes the permissions of the `len` bytes at `addr`, rounded up to whole pages, to `prot`.
+/// The whole range must be mapped
+///
+/// # Safety
+/// Nothing may access the memory in a way that `prot` no longer allows
+#[inline]
+pub unsafe fn mprotect(addr: NonNull<u8>, len: usize, prot: Prot) -> Result<()> {
+    let ret = unsafe {
+        syscall_3(
+            Syscall::Mprotect as usize,
+            addr.as_ptr() as usize,
+            len,
+            prot.bits() as usize,
+        )
+    };
+    result(ret).map(|_| ())
+}
+
+/// Creates a shared memory object of `size` bytes, rounded up to whole pages, and returns a
+/// read-write handle to it.
+///
+/// The memory starts zeroed. Forked children inherit the handle. Other processes can open their
+/// own handle with [`shm_open`] by the object's [`shm_id`], with the permissions in `share`:
+/// read-write if it contains [`Prot::WRITE`], read-only if not, and not at all if it is empty
+#[inline]
+pub fn shm_create(size: usize, share: Prot) -> Result<usize> {
+    let ret = unsafe { syscall_2(Syscall::ShmCreate as usize, size, share.bits() as usize) };
+    result(ret)
+}
+
+/// Returns the id of the shared memory object that `handle` refers to, which other processes can
+/// pass to [`shm_open`]
+#[inline]
+pub fn shm_id(handle: usize) -> Result<usize> {
+    let ret = unsafe { syscall_1(Syscall::ShmId as usize, handle) };
+    result(ret)
+}
+
+/// Opens a new handle to the shared memory object `id` and returns it. Fails with
+/// [`Error::PermissionDenied`] if the object's creator didn't share it
+#[inline]
+pub fn shm_open(id: usize) -> Result<usize> {
+    let ret = unsafe { syscall_1(Syscall::ShmOpen as usize, id) };
+    result(ret)
+}
+
+/// Maps the whole shared memory object that `handle` refers to, with the permissions in `prot`.
+/// Only read-write handles can be mapped writable.
+///
+/// Like [`mmap`], the mapping is placed at `hint` if that range is free. Writes are seen by every
+/// mapping of the object, including ones in forked processes. The mapping keeps the memory alive
+/// after the handle is closed
+#[inline]
+pub fn shm_map(handle: usize, hint: usize, prot: Prot) -> Result<NonNull<u8>> {
+    let ret = unsafe { syscall_3(Syscall::ShmMap as usize, handle, hint, prot.bits() as usize) };
+    result(ret).map(|addr| NonNull::new(addr as *mut u8).expect("shm_map returned null"))
+}
+
+/// Closes a handle to a shared memory object. The object is destroyed when its last handle is
+/// closed, and its memory is freed once it is also unmapped everywhere
+#[inline]
+pub fn shm_close(handle: usize) -> Result<()> {
+    let ret = unsafe { syscall_1(Syscall::ShmClose as usize, handle) };
+    result(ret).map(|_| ())
+}
+
+/// Moves the end of the heap, also called the program break, to `addr` and returns the new end.
+/// Passing 0 returns the current end without moving it.
+///
+/// The heap starts out empty at a page aligned address, and may grow until it runs into another
+/// mapping. Growing it maps zeroed memory, and shrinking it unmaps the pages past the new end
+///
+/// # Safety
+/// Nothing may use the memory past the new end afterwards
+#[inline]
+pub unsafe fn brk(addr: usize) -> Result<usize> {
+    let ret = unsafe { syscall_1(Syscall::Brk as usize, addr) };
+    result(ret)
+}
+
+/// Grows the heap by `increment` bytes, or shrinks it if `increment` is negative, and returns
+/// the old end of the heap, which is where the new memory starts
+///
+/// # Safety
+/// Nothing may use the memory past the new end afterwards
+#[inline]
+pub unsafe fn sbrk(increment: isize) -> Result<NonNull<u8>> {
+    // SAFETY: Passing 0 doesn't move the end of the heap
+    let old = unsafe { brk(0) }?;
+    let new = old
+        .checked_add_signed(increment)
+        .ok_or(Error::InvalidArgument)?;
+    if new != old {
+        // SAFETY: Guaranteed by the caller
+        unsafe { brk(new) }?;
+    }
+    Ok(NonNull::new(old as *mut u8).expect("brk returned null"))
+}
+
 macro_rules! syscall {
     (
         $name:ident(
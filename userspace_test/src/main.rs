@@ -2,15 +2,226 @@
 #![no_main]
 #![feature(naked_functions)]
 
+extern crate alloc;
+
+static mut FORK_DATA: [u8; 12] = *b"parent value";
+
 #[no_mangle]
 pub extern "C" fn _start() -> ! {
     let s = "Test print";
     syscall::write(0, s.as_bytes());
 
+    fork_test();
+    mem_info_test();
+    bad_pointer_test();
+    mmap_test();
+    shared_memory_test();
+    heap_test();
+    out_of_memory_test();
+
     // exit (code 0)
     syscall::exit(0);
 }
 
+/// Writes to a page shared with the child, and checks that the parent's copy is unchanged
+fn fork_test() {
+    let pid = syscall::fork().expect("fork failed");
+    // SAFETY: Each process is single threaded, and the reference is not held across the fork
+    let data = unsafe { &mut FORK_DATA };
+    if pid == 0 {
+        data.copy_from_slice(b"child value!");
+        assert_eq!(data, b"child value!");
+        syscall::write(0, b"fork: child wrote its copy");
+        syscall::exit(0);
+    }
+
+    assert_eq!(data, b"parent value");
+    syscall::write(0, b"fork: parent data unchanged");
+}
+
+/// Checks that the memory statistics are consistent with each other
+fn mem_info_test() {
+    let info = syscall::mem_info().unwrap();
+    assert!(info.free_frames + info.kernel_reserved_frames <= info.total_frames);
+    assert!(info.heap_used <= info.heap_size);
+    // At least the code and the stack are mapped
+    assert!(info.resident_pages >= 2);
+    syscall::write(0, b"mem_info: statistics are consistent");
+}
+
+/// Passes pointers the process can't access to write, which must fail instead of crashing
+fn bad_pointer_test() {
+    let bad_address = -(syscall::Error::BadAddress as isize) as usize;
+    // Near null, unmapped user memory, and the kernel's own memory
+    for ptr in [0x1000, 0x3000_0000_0000, 0xFFFF_8000_0000_0000] {
+        let ret = unsafe { syscall::syscall_3(syscall::Syscall::Write as usize, 0, ptr, 16) };
+        assert_eq!(ret, bad_address);
+    }
+    syscall::write(0, b"bad_pointer: rejected");
+}
+
+/// Maps, protects and unmaps anonymous memory
+fn mmap_test() {
+    use syscall::{Error, Prot};
+
+    let len = 3 * 4096;
+    let ptr = syscall::mmap(0, len, Prot::READ | Prot::WRITE).unwrap();
+    // SAFETY: The mapping is `len` bytes long and only used through this slice
+    let memory = unsafe { core::slice::from_raw_parts_mut(ptr.as_ptr(), len) };
+    assert!(memory.iter().all(|&b| b == 0));
+    memory[4096..4100].copy_from_slice(b"mmap");
+
+    // SAFETY: The middle page is only read from afterwards
+    let middle = unsafe { core::ptr::NonNull::new_unchecked(ptr.as_ptr().add(4096)) };
+    unsafe { syscall::mprotect(middle, 4096, Prot::READ) }.unwrap();
+    assert_eq!(&memory[4096..4100], b"mmap");
+
+    assert!(matches!(
+        syscall::mmap(0, 4096, Prot::WRITE | Prot::EXEC),
+        Err(Error::PermissionDenied)
+    ));
+    assert!(matches!(
+        syscall::mmap(0, 4096, Prot::empty()),
+        Err(Error::InvalidArgument)
+    ));
+    // SAFETY: The call fails, so nothing changes
+    assert!(matches!(
+        unsafe { syscall::mprotect(middle, 4096, Prot::empty()) },
+        Err(Error::InvalidArgument)
+    ));
+
+    // SAFETY: The slice isn't used anymore
+    unsafe { syscall::munmap(ptr, len) }.unwrap();
+    // The freed range is handed out again when asked for
+    let again = syscall::mmap(ptr.as_ptr() as usize, 4096, Prot::READ).unwrap();
+    assert_eq!(again, ptr);
+    syscall::write(0, b"mmap: mapped, protected and unmapped");
+}
+
+/// Passes a message to a forked child through shared memory, which the child opens a second
+/// time read-only, and gets a reply back. Objects that aren't shared can't be opened by the child
+fn shared_memory_test() {
+    use core::ptr::{self, NonNull};
+    use syscall::{Error, Prot};
+
+    let handle = syscall::shm_create(100, Prot::READ).unwrap();
+    let ptr = syscall::shm_map(handle, 0, Prot::READ | Prot::WRITE).unwrap();
+    let private = syscall::shm_create(4096, Prot::empty()).unwrap();
+    let private_id = syscall::shm_id(private).unwrap();
+    // SAFETY: Objects are at least a page long, and this process is single threaded
+    let shared = |ptr: NonNull<u8>| unsafe { core::slice::from_raw_parts(ptr.as_ptr(), 4096) };
+    // SAFETY: As above, and writes only go through the first mapping, which is writable
+    let write = |offset: usize, bytes: &[u8]| unsafe {
+        ptr::copy_nonoverlapping(bytes.as_ptr(), ptr.as_ptr().add(offset), bytes.len())
+    };
+    write(0, b"hello");
+
+    if syscall::fork().expect("fork failed") == 0 {
+        // The child inherited the handle, and opens another one like an unrelated process would,
+        // which only gets the access that the object was shared with
+        let id = syscall::shm_id(handle).unwrap();
+        let other = syscall::shm_open(id).unwrap();
+        assert_ne!(other, handle);
+        assert!(matches!(
+            syscall::shm_map(other, 0, Prot::READ | Prot::WRITE),
+            Err(Error::PermissionDenied)
+        ));
+        assert!(matches!(
+            syscall::shm_open(private_id),
+            Err(Error::PermissionDenied)
+        ));
+        let view = syscall::shm_map(other, 0, Prot::READ).unwrap();
+        assert_ne!(view, ptr);
+        assert_eq!(&shared(view)[..5], b"hello");
+        write(5, b" world");
+        assert_eq!(&shared(view)[..11], b"hello world");
+        syscall::write(0, b"shared_memory: child replied");
+        syscall::exit(0);
+    }
+
+    assert_eq!(&shared(ptr)[..11], b"hello world");
+    syscall::shm_close(private).unwrap();
+    syscall::shm_close(handle).unwrap();
+    assert!(matches!(
+        syscall::shm_close(handle),
+        Err(Error::InvalidArgument)
+    ));
+    // The mapping keeps the memory alive after the handle is closed
+    assert_eq!(&shared(ptr)[..5], b"hello");
+    // SAFETY: The memory isn't used anymore
+    unsafe { syscall::munmap(ptr, 4096) }.unwrap();
+    syscall::write(0, b"shared_memory: parent got the reply");
+}
+
+/// Builds a large `Vec` and `BTreeMap` on the heap, which grows through `brk` and shrinks again
+/// once they are freed
+fn heap_test() {
+    use alloc::{collections::BTreeMap, string::ToString, vec::Vec};
+
+    // SAFETY: Passing 0 doesn't move the end of the heap
+    let start = unsafe { syscall::brk(0) }.unwrap();
+    let before = syscall::mem_info().unwrap();
+    {
+        let vec: Vec<u64> = (0..1 << 20).collect();
+        let mut map = BTreeMap::new();
+        for i in 0..10_000u64 {
+            map.insert(i, i.to_string());
+        }
+        // SAFETY: Same as above
+        let grown = unsafe { syscall::brk(0) }.unwrap();
+        assert!(grown >= start + 8 * (1 << 20));
+        assert_eq!(vec.iter().sum::<u64>(), (1 << 19) * ((1 << 20) - 1));
+        assert_eq!(map.len(), 10_000);
+        assert_eq!(map[&1234], "1234");
+    }
+
+    // The memory at the end of the heap was given back
+    let after = syscall::mem_info().unwrap();
+    assert!(after.resident_pages < before.resident_pages + 64);
+    // SAFETY: Same as above
+    assert!(unsafe { syscall::brk(0) }.unwrap() < start + 8 * (1 << 20));
+    // The heap can't move below its base, so this fails without touching anything
+    assert!(matches!(
+        unsafe { syscall::brk(4096) },
+        Err(syscall::Error::InvalidArgument)
+    ));
+    syscall::write(0, b"heap: built and freed a Vec and a BTreeMap");
+}
+
+/// Forks a child that touches memory until there is none left. The kernel kills it as the largest
+/// process and keeps running the parent, whose allocations work again afterwards
+fn out_of_memory_test() {
+    use syscall::{Error, Prot};
+
+    let before = syscall::mem_info().unwrap();
+    // There is plenty of memory before the child starts using it up
+    if syscall::fork().expect("fork failed") == 0 {
+        // Far more than the machine has
+        let len = 1 << 32;
+        let ptr = syscall::mmap(0, len, Prot::READ | Prot::WRITE).unwrap();
+        for offset in (0..len).step_by(4096) {
+            // SAFETY: The offset is inside of the mapping
+            unsafe { ptr.as_ptr().add(offset).write_volatile(1) };
+        }
+        panic!("ran out of memory without being killed");
+    }
+
+    // The child's memory was given back when it was killed
+    let after = syscall::mem_info().unwrap();
+    assert!(after.free_frames + 64 >= before.free_frames);
+    // Syscalls fail instead of killing anything
+    assert!(matches!(
+        syscall::shm_create(1 << 40, Prot::empty()),
+        Err(Error::OutOfMemory)
+    ));
+    let ptr = syscall::mmap(0, 4096, Prot::READ | Prot::WRITE).unwrap();
+    // SAFETY: The mapping is a page long
+    unsafe { ptr.as_ptr().write_volatile(1) };
+    // SAFETY: The page isn't used anymore
+    unsafe { syscall::munmap(ptr, 4096) }.unwrap();
+    syscall::write(0, b"out_of_memory: kernel survived");
+}
+
 #[panic_handler]
 fn panic(_info: &core::panic::PanicInfo) -> ! {
     syscall::exit(1);